@@ -0,0 +1,542 @@
+//! Beam search: keep the `n_beams` most likely continuations at every step instead of sampling a
+//! single token.
+//!
+//! Every beam lives in its own sequence of the context. When a beam is extended by more than one
+//! token it is forked with [`LlamaContext::copy_kv_cache_seq`], and the sequences of pruned beams
+//! are cleared and reused. The search is deterministic: ties are broken by beam and token id.
+//!
+//! ```no_run
+//! # use llama_cpp_2::beam_search::BeamSearch;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let prompt = model.str_to_token("Translate to French: cheese =>", AddBos::Always)?;
+//! let hypotheses = BeamSearch::new(4).with_max_tokens(16).with_n_best(2).run(&mut ctx, &prompt)?;
+//! for hypothesis in hypotheses {
+//!     let text = model.tokens_to_str(&hypothesis.tokens, Special::Tokenize)?;
+//!     println!("{:.3} {text}", hypothesis.score);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::cmp::Ordering;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::logprobs::TokenLogprobs;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// An error that can occur during beam search.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum BeamSearchError {
+    /// The prompt was empty, so there are no logits to start from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt has {len} tokens but the context only holds {n_ctx} tokens")]
+    PromptTooLong {
+        /// the length of the prompt
+        len: usize,
+        /// the size of the context
+        n_ctx: u32,
+    },
+    /// There are more beams than the context has sequences.
+    #[error("{n_beams} beams need {n_beams} sequences but the context only has {n_seq_max}")]
+    TooManySequences {
+        /// the number of beams
+        n_beams: usize,
+        /// the number of sequences of the context
+        n_seq_max: u32,
+    },
+    /// One token per beam does not fit into a batch.
+    #[error("{n_beams} beams do not fit into a batch of {n_batch} tokens")]
+    BatchTooSmall {
+        /// the number of beams
+        n_beams: usize,
+        /// the batch size of the context
+        n_batch: u32,
+    },
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// A sequence id or position did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// A completion found by beam search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, without the prompt or the end-of-generation token.
+    pub tokens: Vec<LlamaToken>,
+    /// The sum of the log-probabilities of the tokens (including the end-of-generation token if
+    /// the hypothesis is finished).
+    pub logprob: f32,
+    /// The log-probability normalized by the length penalty, used to rank hypotheses.
+    pub score: f32,
+    /// Whether the hypothesis ended with an end-of-generation token, rather than being cut off
+    /// by the token limit or the context size.
+    pub finished: bool,
+}
+
+/// A beam that is still being extended.
+#[derive(Debug, Clone)]
+struct Beam {
+    tokens: Vec<LlamaToken>,
+    logprob: f32,
+    seq_id: i32,
+    /// the index of the logits of the last token in the last batch
+    logits_index: i32,
+}
+
+/// A possible extension of a beam.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    beam: usize,
+    token: LlamaToken,
+    logprob: f32,
+}
+
+/// Configuration of a beam search.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamSearch {
+    n_beams: usize,
+    n_best: usize,
+    max_tokens: usize,
+    length_penalty: f32,
+}
+
+impl BeamSearch {
+    /// Search with `n_beams` beams. The context used must allow that many sequences (see
+    /// [`crate::context::params::LlamaContextParams::with_n_seq_max`]).
+    ///
+    /// Defaults to returning the single best hypothesis, generating at most 64 tokens and a
+    /// length penalty of `1.0`.
+    ///
+    /// # Panics
+    ///
+    /// - `n_beams` is `0`
+    #[must_use]
+    pub fn new(n_beams: usize) -> Self {
+        assert!(n_beams > 0, "beam search needs at least one beam");
+        Self {
+            n_beams,
+            n_best: 1,
+            max_tokens: 64,
+            length_penalty: 1.0,
+        }
+    }
+
+    /// Return up to `n_best` hypotheses. This is capped at the number of beams.
+    #[must_use]
+    pub fn with_n_best(mut self, n_best: usize) -> Self {
+        self.n_best = n_best;
+        self
+    }
+
+    /// Generate at most `max_tokens` tokens per hypothesis.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Rank hypotheses by `logprob / len^length_penalty`. `0` ranks by total log-probability,
+    /// which favours short hypotheses; larger values favour longer ones.
+    #[must_use]
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    /// Run the search for a continuation of `prompt`, returning the best hypotheses, best first.
+    ///
+    /// Sequences `0..n_beams` of `ctx` are cleared before and after the search. The beams may all
+    /// diverge, so every step can take `n_beams` kv cache cells; the search stops early (returning
+    /// the beams as unfinished hypotheses) once the context cannot hold another step, or if
+    /// llama.cpp finds no kv cache slot for one anyway.
+    ///
+    /// # Errors
+    ///
+    /// - [`BeamSearchError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`BeamSearchError::PromptTooLong`] if `prompt` does not fit into the context.
+    /// - [`BeamSearchError::TooManySequences`] if the context has fewer sequences than beams.
+    /// - [`BeamSearchError::BatchTooSmall`] if the batch size of the context is smaller than the
+    ///   number of beams.
+    /// - [`BeamSearchError::DecodeError`] if decoding the prompt failed, or decoding a step failed
+    ///   for another reason than the kv cache being full.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context or the number of beams does not fit into the api
+    pub fn run(
+        &self,
+        ctx: &mut LlamaContext,
+        prompt: &[LlamaToken],
+    ) -> Result<Vec<Hypothesis>, BeamSearchError> {
+        if prompt.is_empty() {
+            return Err(BeamSearchError::EmptyPrompt);
+        }
+        let n_ctx = ctx.n_ctx();
+        let n_ctx_usize = usize::try_from(n_ctx).unwrap_or(usize::MAX);
+        if prompt.len() > n_ctx_usize {
+            return Err(BeamSearchError::PromptTooLong {
+                len: prompt.len(),
+                n_ctx,
+            });
+        }
+        let n_seq_max = ctx.n_seq_max();
+        if usize::try_from(n_seq_max).is_ok_and(|n_seq_max| self.n_beams > n_seq_max) {
+            return Err(BeamSearchError::TooManySequences {
+                n_beams: self.n_beams,
+                n_seq_max,
+            });
+        }
+        let n_batch = ctx.n_batch();
+        if usize::try_from(n_batch).is_ok_and(|n_batch| self.n_beams > n_batch) {
+            return Err(BeamSearchError::BatchTooSmall {
+                n_beams: self.n_beams,
+                n_batch,
+            });
+        }
+        let n_beams = i32::try_from(self.n_beams).expect("n_beams does not fit into an i32");
+        clear_seqs(ctx, 0..n_beams)?;
+
+        let result = self.search(ctx, prompt, n_ctx_usize);
+        clear_seqs(ctx, 0..n_beams)?;
+        result
+    }
+
+    fn search(
+        &self,
+        ctx: &mut LlamaContext,
+        prompt: &[LlamaToken],
+        n_ctx: usize,
+    ) -> Result<Vec<Hypothesis>, BeamSearchError> {
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let n_prompt = i32::try_from(prompt.len()).expect("prompt fits into the context");
+        let mut pos = 0;
+        for chunk in prompt.chunks(n_batch) {
+            batch.clear();
+            for &token in chunk {
+                batch.add(token, pos, &[0], pos + 1 == n_prompt)?;
+                pos += 1;
+            }
+            ctx.decode(&mut batch)?;
+        }
+
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            logprob: 0.0,
+            seq_id: 0,
+            logits_index: batch.n_tokens() - 1,
+        }];
+        let mut finished = Vec::new();
+        for step in 0..self.max_tokens {
+            if !self.has_room(prompt.len(), step, n_ctx) {
+                break;
+            }
+            let logits = beams
+                .iter()
+                .map(|beam| ctx.get_logits_ith(beam.logits_index))
+                .collect::<Vec<_>>();
+            let candidates = self.candidates(&beams, &logits);
+
+            // pick the best extensions, setting aside finished hypotheses
+            let mut extensions = Vec::with_capacity(self.n_beams);
+            for candidate in candidates {
+                if extensions.len() == self.n_beams {
+                    break;
+                }
+                if ctx.model.is_eog_token(candidate.token) {
+                    let tokens = beams[candidate.beam].tokens.clone();
+                    finished.push(self.hypothesis(tokens, candidate.logprob, true));
+                } else {
+                    extensions.push(candidate);
+                }
+            }
+            if extensions.is_empty() || self.is_done(&finished, &extensions, step + 1) {
+                beams.clear();
+                break;
+            }
+
+            beams = fork(ctx, self.n_beams, &beams, &extensions)?;
+            batch.clear();
+            let pos = i32::try_from(prompt.len() + step).expect("position fits into an i32");
+            for beam in &mut beams {
+                batch.add(
+                    *beam.tokens.last().expect("just extended"),
+                    pos,
+                    &[beam.seq_id],
+                    true,
+                )?;
+                beam.logits_index = batch.n_tokens() - 1;
+            }
+            match ctx.decode(&mut batch) {
+                Ok(()) => {}
+                // the new tokens are scored already, so the beams are still valid hypotheses
+                Err(DecodeError::NoKvCacheSlot) => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        // beams cut off by the limits compete with the finished hypotheses
+        finished.extend(
+            beams
+                .into_iter()
+                .map(|beam| self.hypothesis(beam.tokens, beam.logprob, false)),
+        );
+        finished.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        finished.truncate(self.n_best.min(self.n_beams));
+        Ok(finished)
+    }
+
+    /// Every beam's best `2 * n_beams` extensions (enough that end-of-generation tokens cannot
+    /// crowd out the rest), best first.
+    fn candidates(&self, beams: &[Beam], logits: &[&[f32]]) -> Vec<Candidate> {
+        let mut candidates = beams
+            .iter()
+            .zip(logits)
+            .enumerate()
+            .flat_map(|(i, (beam, logits))| {
+                TokenLogprobs::from_logits(logits, LlamaToken(0), 2 * self.n_beams)
+                    .top
+                    .into_iter()
+                    .map(move |top| Candidate {
+                        beam: i,
+                        token: top.token,
+                        logprob: beam.logprob + top.logprob,
+                    })
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            b.logprob
+                .partial_cmp(&a.logprob)
+                .unwrap_or(Ordering::Equal)
+                .then(a.beam.cmp(&b.beam))
+                .then(a.token.cmp(&b.token))
+        });
+        candidates
+    }
+
+    /// Whether the kv cache is sure to have room for the tokens decoded in step `step` (counting
+    /// from `0`) after a prompt of `n_prompt` tokens. Every beam may have diverged from the
+    /// others, so each step can take a new cell per beam.
+    fn has_room(&self, n_prompt: usize, step: usize, n_ctx: usize) -> bool {
+        self.n_beams
+            .checked_mul(step + 1)
+            .and_then(|n_generated| n_generated.checked_add(n_prompt))
+            .is_some_and(|n_cells| n_cells <= n_ctx)
+    }
+
+    /// Whether no beam can beat the worst of `n_beams` finished hypotheses any more, assuming
+    /// (as the usual heuristic does) that a beam's score only gets worse as it grows.
+    fn is_done(&self, finished: &[Hypothesis], extensions: &[Candidate], len: usize) -> bool {
+        if finished.len() < self.n_beams {
+            return false;
+        }
+        let mut scores = finished.iter().map(|h| h.score).collect::<Vec<_>>();
+        scores.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        let worst_kept = scores[self.n_beams - 1];
+        let best_alive = self.score(extensions[0].logprob, len);
+        best_alive <= worst_kept
+    }
+
+    fn hypothesis(&self, tokens: Vec<LlamaToken>, logprob: f32, finished: bool) -> Hypothesis {
+        // a finished hypothesis also generated the end-of-generation token
+        let len = tokens.len() + usize::from(finished);
+        Hypothesis {
+            score: self.score(logprob, len),
+            tokens,
+            logprob,
+            finished,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn score(&self, logprob: f32, len: usize) -> f32 {
+        logprob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
+/// Create the beams for `extensions`. The first extension of a beam reuses its sequence, further
+/// extensions get a copy of it in a free sequence. The sequences of pruned beams are cleared.
+fn fork(
+    ctx: &mut LlamaContext,
+    n_beams: usize,
+    beams: &[Beam],
+    extensions: &[Candidate],
+) -> Result<Vec<Beam>, BeamSearchError> {
+    let mut has_extension = vec![false; beams.len()];
+    for extension in extensions {
+        has_extension[extension.beam] = true;
+    }
+    let pruned = beams
+        .iter()
+        .zip(&has_extension)
+        .filter(|(_, has_extension)| !**has_extension)
+        .map(|(beam, _)| beam.seq_id);
+    clear_seqs(ctx, pruned)?;
+    let n_beams = i32::try_from(n_beams).expect("n_beams does not fit into an i32");
+    let mut free = (0..n_beams)
+        .rev()
+        .filter(|&seq_id| {
+            !beams
+                .iter()
+                .zip(&has_extension)
+                .any(|(beam, has_extension)| *has_extension && beam.seq_id == seq_id)
+        })
+        .collect::<Vec<_>>();
+
+    let mut reused = vec![false; beams.len()];
+
+    let mut forked = Vec::with_capacity(extensions.len());
+    for extension in extensions {
+        let parent = &beams[extension.beam];
+        let seq_id = if reused[extension.beam] {
+            let seq_id = free.pop().expect("there is a sequence per beam");
+            ctx.copy_kv_cache_seq(parent.seq_id, seq_id, None, None)?;
+            seq_id
+        } else {
+            reused[extension.beam] = true;
+            parent.seq_id
+        };
+        let mut tokens = parent.tokens.clone();
+        tokens.push(extension.token);
+        forked.push(Beam {
+            tokens,
+            logprob: extension.logprob,
+            seq_id,
+            logits_index: -1,
+        });
+    }
+    Ok(forked)
+}
+
+fn clear_seqs(
+    ctx: &mut LlamaContext,
+    seq_ids: impl IntoIterator<Item = i32>,
+) -> Result<(), KvCacheConversionError> {
+    for seq_id in seq_ids {
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::params::LlamaContextParams;
+    use crate::gguf::tiny::test_model;
+    use crate::model::AddBos;
+    use std::num::NonZeroU32;
+
+    fn beam(logprob: f32) -> Beam {
+        Beam {
+            tokens: Vec::new(),
+            logprob,
+            seq_id: 0,
+            logits_index: 0,
+        }
+    }
+
+    #[test]
+    fn beams_must_fit_the_context() {
+        let (backend, model) = test_model();
+        let params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(64))
+            .with_n_batch(2)
+            .with_n_seq_max(4);
+        let mut ctx = model.new_context(backend, params).unwrap();
+        let prompt = model.str_to_token("hello", AddBos::Always).unwrap();
+
+        assert!(matches!(
+            BeamSearch::new(5).run(&mut ctx, &prompt),
+            Err(BeamSearchError::TooManySequences {
+                n_beams: 5,
+                n_seq_max: 4
+            })
+        ));
+        assert!(matches!(
+            BeamSearch::new(3).run(&mut ctx, &prompt),
+            Err(BeamSearchError::BatchTooSmall {
+                n_beams: 3,
+                n_batch: 2
+            })
+        ));
+        assert!(BeamSearch::new(2)
+            .with_max_tokens(2)
+            .run(&mut ctx, &prompt)
+            .is_ok());
+    }
+
+    #[test]
+    fn candidates_are_ranked_by_cumulative_logprob() {
+        let search = BeamSearch::new(1);
+        let beams = [beam(-1.0), beam(0.0)];
+        let logits: [&[f32]; 2] = [&[0.0, 10.0, 0.0], &[1.0, 1.0, 0.0]];
+        let candidates = search.candidates(&beams, &logits);
+
+        let ranked = candidates
+            .iter()
+            .map(|c| (c.beam, c.token.0))
+            .collect::<Vec<_>>();
+        // ties between tokens 0 and 1 of beam 1 are broken by token id
+        assert_eq!(ranked, [(1, 0), (1, 1), (0, 1), (0, 0)]);
+        assert!((candidates[0].logprob - candidates[1].logprob).abs() < 1e-6);
+    }
+
+    #[test]
+    fn length_penalty() {
+        let tokens = vec![LlamaToken(0); 4];
+        let hypothesis = BeamSearch::new(1).hypothesis(tokens.clone(), -4.0, false);
+        assert!((hypothesis.score - -1.0).abs() < 1e-6);
+
+        let search = BeamSearch::new(1).with_length_penalty(0.0);
+        let hypothesis = search.hypothesis(tokens, -4.0, true);
+        assert!((hypothesis.score - -4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn done_once_no_beam_can_win() {
+        let search = BeamSearch::new(1).with_length_penalty(0.0);
+        let finished = [search.hypothesis(Vec::new(), -1.0, true)];
+        let extension = |logprob| Candidate {
+            beam: 0,
+            token: LlamaToken(0),
+            logprob,
+        };
+        assert!(!search.is_done(&[], &[extension(-2.0)], 1));
+        assert!(!search.is_done(&finished, &[extension(-0.5)], 1));
+        assert!(search.is_done(&finished, &[extension(-2.0)], 1));
+    }
+
+    #[test]
+    fn every_beam_needs_room_in_the_kv_cache() {
+        let search = BeamSearch::new(4);
+        // 10 prompt tokens and 4 beams per step
+        assert!(search.has_room(10, 0, 14));
+        assert!(!search.has_room(10, 0, 13));
+        assert!(search.has_room(10, 2, 22));
+        assert!(!search.has_room(10, 3, 22));
+        assert!(!search.has_room(10, usize::MAX / 2, usize::MAX));
+
+        // a single beam only needs one cell per step
+        assert!(BeamSearch::new(1).has_room(10, 3, 14));
+    }
+}
@@ -0,0 +1,469 @@
+//! A streaming text generation loop on top of [`LlamaContext`] and [`LlamaSampler`].
+//!
+//! [`Completion`] does what the generation loop in `examples/usage.rs` does by hand: it decodes
+//! the prompt, samples a token, stops on end-of-generation tokens, converts tokens to text
+//! (buffering characters that are split across tokens) and decodes the sampled token to get the
+//! logits for the next one. It can also stop on any of a set of strings, see [`stop`], and keep
+//! going when the context is full by discarding old tokens, see [`Completion::with_context_shift`].
+//!
+//! ```no_run
+//! # use std::io::Write;
+//! # use llama_cpp_2::completion::Completion;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! let prompt = model.str_to_token("Hello! how are you?", AddBos::Always)?;
+//! let completion = Completion::new(&mut ctx, &mut sampler, &prompt)?.with_max_tokens(64);
+//! for piece in completion {
+//!     print!("{}", piece?.text);
+//!     std::io::stdout().flush()?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod stop;
+
+use std::ops::Range;
+
+use crate::completion::stop::StopSequences;
+use crate::context::shift::{ContextShift, ContextShiftError};
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::Special;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{DecodeError, TokenToStringError};
+
+/// The sequence id a [`Completion`] generates into.
+const SEQ_ID: i32 = 0;
+
+/// An error that can occur while generating text.
+#[derive(Debug, thiserror::Error)]
+pub enum CompletionError {
+    /// The prompt was empty, so there are no logits to sample from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt ends at position {end} but the context only holds {n_ctx} tokens")]
+    PromptTooLong {
+        /// the position after the last prompt token
+        end: usize,
+        /// the size of the context
+        n_ctx: u32,
+    },
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// Converting a token to text failed.
+    #[error(transparent)]
+    TokenToStringError(#[from] TokenToStringError),
+    /// Shifting the context to make room failed.
+    #[error(transparent)]
+    ContextShiftError(#[from] ContextShiftError),
+}
+
+/// Why a [`Completion`] stopped producing tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced an end-of-generation token.
+    EndOfGeneration,
+    /// [`Completion::with_max_tokens`] tokens were generated.
+    MaxTokens,
+    /// The context is full and no [`ContextShift`] was set.
+    ContextFull,
+    /// The output contained the stop sequence with this index, see
+    /// [`Completion::with_stop_sequences`].
+    StopSequence(usize),
+    /// An error occurred.
+    Error,
+}
+
+/// A generated token and the text it completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedPiece {
+    /// The sampled token. The last piece may be the end-of-generation token if text was still
+    /// held back when it was sampled.
+    pub token: LlamaToken,
+    /// The text completed by this token. This is empty if the token ends part way through a
+    /// character; the whole character is included in the piece of the token that completes it.
+    pub text: String,
+}
+
+/// An iterator over the tokens generated for a prompt.
+///
+/// The prompt is appended to sequence `0` after any tokens already in the kv cache. The last
+/// sampled token is never decoded (there is nothing left to sample from it), so a conversation
+/// is continued by creating a new completion whose prompt starts with
+/// [`Completion::undecoded_token`], followed by the new tokens.
+#[derive(Debug)]
+pub struct Completion<'a, 'model> {
+    ctx: &'a mut LlamaContext<'model>,
+    sampler: &'a mut LlamaSampler,
+    batch: LlamaBatch,
+    /// the position the next decoded token is placed at
+    n_past: i32,
+    /// the last sampled token if it has not been decoded into the kv cache
+    undecoded: Option<LlamaToken>,
+    /// the index of the logits to sample from
+    logits_index: i32,
+    n_generated: usize,
+    max_tokens: Option<usize>,
+    special: Special,
+    utf8: Utf8Buffer,
+    stops: Option<StopSequences>,
+    context_shift: Option<ContextShift>,
+    dropped: Vec<Range<usize>>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl<'a, 'model> Completion<'a, 'model> {
+    /// Decode `prompt` and prepare to generate a completion for it.
+    ///
+    /// # Errors
+    ///
+    /// - [`CompletionError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`CompletionError::PromptTooLong`] if the prompt does not fit into the context.
+    /// - [`CompletionError::DecodeError`] if decoding the prompt failed.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context does not fit into a [`usize`]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+    ) -> Result<Self, CompletionError> {
+        if prompt.is_empty() {
+            return Err(CompletionError::EmptyPrompt);
+        }
+        let n_past = ctx.kv_cache_seq_pos_max(SEQ_ID) + 1;
+        let n_ctx = ctx.n_ctx();
+        let end = usize::try_from(n_past).unwrap_or(0) + prompt.len();
+        if end > usize::try_from(n_ctx).unwrap_or(usize::MAX) {
+            return Err(CompletionError::PromptTooLong { end, n_ctx });
+        }
+
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut completion = Self {
+            ctx,
+            sampler,
+            batch: LlamaBatch::new(1, 1),
+            n_past,
+            undecoded: None,
+            logits_index: 0,
+            n_generated: 0,
+            max_tokens: None,
+            special: Special::Tokenize,
+            utf8: Utf8Buffer::default(),
+            stops: None,
+            context_shift: None,
+            dropped: Vec::new(),
+            finish_reason: None,
+        };
+
+        let chunks = prompt.chunks(n_batch);
+        let n_chunks = chunks.len();
+        for (i, chunk) in chunks.enumerate() {
+            batch.clear();
+            let is_last_chunk = i + 1 == n_chunks;
+            for (j, &token) in chunk.iter().enumerate() {
+                let logits = is_last_chunk && j + 1 == chunk.len();
+                batch.add(token, completion.n_past, &[SEQ_ID], logits)?;
+                completion.n_past += 1;
+            }
+            completion.ctx.decode(&mut batch)?;
+        }
+        completion.logits_index = batch.n_tokens() - 1;
+        Ok(completion)
+    }
+
+    /// Stop after generating `max_tokens` tokens.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// How special tokens (such as chat template markers) are converted to text. Defaults to
+    /// [`Special::Tokenize`], which renders them.
+    #[must_use]
+    pub fn with_special(mut self, special: Special) -> Self {
+        self.special = special;
+        self
+    }
+
+    /// Stop once the output contains any of `stops`. The stop sequence itself is not included in
+    /// the output, and text that could be the start of a stop sequence is held back until it is
+    /// known not to be, so it may appear in the piece of a later token.
+    #[must_use]
+    pub fn with_stop_sequences(
+        mut self,
+        stops: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.stops = Some(StopSequences::new(stops));
+        self
+    }
+
+    /// When the context is full, make room with `context_shift` instead of stopping with
+    /// [`FinishReason::ContextFull`]. See [`Completion::dropped_ranges`] for what was removed.
+    #[must_use]
+    pub fn with_context_shift(mut self, context_shift: ContextShift) -> Self {
+        self.context_shift = Some(context_shift);
+        self
+    }
+
+    /// The position ranges dropped by context shifts, in the order they happened. Each range is
+    /// relative to the sequence after the previous shifts, so removing them in order from a
+    /// history of the tokens in sequence `0` keeps it in sync with the kv cache.
+    #[must_use]
+    pub fn dropped_ranges(&self) -> &[Range<usize>] {
+        &self.dropped
+    }
+
+    /// Why the completion stopped, or `None` if it has not.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// The last sampled token if it was not decoded, which is always the case once the completion
+    /// has finished (unless it finished before sampling anything). It is not in the kv cache, so
+    /// prepend it to the prompt of a completion that continues this one.
+    #[must_use]
+    pub fn undecoded_token(&self) -> Option<LlamaToken> {
+        self.undecoded
+    }
+
+    /// The number of tokens generated so far.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// The context being generated with.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        self.ctx
+    }
+
+    fn next_piece(&mut self) -> Result<Option<GeneratedPiece>, CompletionError> {
+        if self.max_tokens.is_some_and(|max| self.n_generated >= max) {
+            self.finish_reason = Some(FinishReason::MaxTokens);
+            return Ok(None);
+        }
+
+        let token = self.sampler.sample(&*self.ctx, self.logits_index);
+        self.undecoded = Some(token);
+        if self.ctx.model.is_eog_token(token) {
+            self.finish_reason = Some(FinishReason::EndOfGeneration);
+            // release any text that was held back
+            let text = self.utf8.flush();
+            let text = self.check_stops(&text, true);
+            return Ok((!text.is_empty()).then_some(GeneratedPiece { token, text }));
+        }
+        self.n_generated += 1;
+
+        let bytes = self.ctx.model.token_to_bytes(token, self.special)?;
+        let mut text = self.utf8.push(&bytes);
+
+        let mut context_full = u32::try_from(self.n_past).map_or(true, |n| n >= self.ctx.n_ctx());
+        if let (true, Some(context_shift)) = (context_full, self.context_shift) {
+            let n_past = usize::try_from(self.n_past).expect("n_past is not negative");
+            let dropped = context_shift.apply(self.ctx, SEQ_ID, n_past)?;
+            self.n_past -= i32::try_from(dropped.len()).expect("dropped range fits in n_past");
+            self.dropped.push(dropped);
+            context_full = false;
+        }
+        let is_last = context_full || self.max_tokens.is_some_and(|max| self.n_generated >= max);
+        if is_last {
+            // there is no next token to complete a split character
+            text.push_str(&self.utf8.flush());
+        }
+        let text = self.check_stops(&text, is_last);
+        if self.finish_reason.is_some() {
+            return Ok(Some(GeneratedPiece { token, text }));
+        }
+        if is_last {
+            if context_full {
+                self.finish_reason = Some(FinishReason::ContextFull);
+            }
+            return Ok(Some(GeneratedPiece { token, text }));
+        }
+
+        self.batch.clear();
+        self.batch.add(token, self.n_past, &[SEQ_ID], true)?;
+        self.ctx.decode(&mut self.batch)?;
+        self.undecoded = None;
+        self.n_past += 1;
+        self.logits_index = 0;
+
+        Ok(Some(GeneratedPiece { token, text }))
+    }
+
+    /// Pass `text` through the stop sequences (if any), returning the part that can be emitted
+    /// and setting the finish reason if one matched. If `is_last` any held back text is released.
+    fn check_stops(&mut self, text: &str, is_last: bool) -> String {
+        let Some(stops) = &mut self.stops else {
+            return text.to_string();
+        };
+        let check = stops.push_str(text);
+        if let Some(stop) = check.stop {
+            self.finish_reason = Some(FinishReason::StopSequence(stop));
+            return check.text;
+        }
+        let mut text = check.text;
+        if is_last {
+            text.push_str(&String::from_utf8_lossy(&stops.flush()));
+        }
+        text
+    }
+}
+
+impl Iterator for Completion<'_, '_> {
+    type Item = Result<GeneratedPiece, CompletionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finish_reason.is_some() {
+            return None;
+        }
+        match self.next_piece() {
+            Ok(piece) => piece.map(Ok),
+            Err(error) => {
+                self.finish_reason = Some(FinishReason::Error);
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Turns bytes into text as they arrive, holding back incomplete characters until the rest of
+/// their bytes arrive.
+#[derive(Debug, Default)]
+struct Utf8Buffer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Add `bytes` and return all the text that is now complete. Invalid bytes are replaced with
+    /// U+FFFD.
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(error) => {
+                    let (valid, invalid) = rest.split_at(error.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).expect("checked by from_utf8"));
+                    let Some(len) = error.error_len() else {
+                        // an incomplete character at the end
+                        rest = invalid;
+                        break;
+                    };
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    rest = &invalid[len..];
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        text
+    }
+
+    /// Return any incomplete character that is still buffered.
+    fn flush(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Completion, FinishReason, Utf8Buffer};
+    use crate::context::params::LlamaContextParams;
+    use crate::gguf::tiny::test_model;
+    use crate::model::AddBos;
+    use crate::sampling::LlamaSampler;
+    use crate::token::LlamaToken;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn a_finished_completion_can_be_continued() {
+        let (backend, model) = test_model();
+        let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+        let mut ctx = model.new_context(backend, params).unwrap();
+        let eog = (0..model.n_vocab())
+            .map(LlamaToken)
+            .filter(|&token| model.is_eog_token(token));
+        let mut sampler = LlamaSampler::chain_simple([
+            LlamaSampler::ban_tokens(model.n_vocab(), eog),
+            LlamaSampler::greedy(),
+        ]);
+
+        let prompt = model.str_to_token("hello", AddBos::Always).unwrap();
+        let mut completion = Completion::new(&mut ctx, &mut sampler, &prompt)
+            .unwrap()
+            .with_max_tokens(3);
+        let generated = completion
+            .by_ref()
+            .map(|piece| piece.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(completion.finish_reason(), Some(FinishReason::MaxTokens));
+        assert_eq!(generated.len(), 3);
+        let undecoded = completion.undecoded_token();
+        assert_eq!(undecoded, generated.last().copied());
+        // every generated token but the last is in the kv cache
+        let n_cached = prompt.len() + generated.len() - 1;
+        assert_eq!(
+            ctx.kv_cache_seq_pos_max(0),
+            i32::try_from(n_cached).unwrap() - 1
+        );
+
+        let mut next = vec![undecoded.unwrap()];
+        next.extend(model.str_to_token(" world", AddBos::Never).unwrap());
+        let mut completion = Completion::new(&mut ctx, &mut sampler, &next)
+            .unwrap()
+            .with_max_tokens(1);
+        assert!(completion.next().unwrap().is_ok());
+        assert_eq!(completion.undecoded_token().map(|_| ()), Some(()));
+        let n_cached = n_cached + next.len();
+        assert_eq!(
+            ctx.kv_cache_seq_pos_max(0),
+            i32::try_from(n_cached).unwrap() - 1
+        );
+    }
+
+    #[test]
+    fn split_characters_are_held_back() {
+        let mut buffer = Utf8Buffer::default();
+        let bytes = "a€b".as_bytes();
+        assert_eq!(buffer.push(&bytes[..2]), "a");
+        assert_eq!(buffer.push(&bytes[2..3]), "");
+        assert_eq!(buffer.push(&bytes[3..]), "€b");
+        assert_eq!(buffer.flush(), "");
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        let mut buffer = Utf8Buffer::default();
+        assert_eq!(buffer.push(b"a\xFFb\xE2\x82"), "a\u{FFFD}b");
+        assert_eq!(buffer.flush(), "\u{FFFD}");
+    }
+}
@@ -0,0 +1,191 @@
+//! Stop generating when the output contains one of a set of strings.
+//!
+//! [`StopSequences`] can be fed the output of any generation loop, either as the bytes returned by
+//! [`crate::model::LlamaModel::token_to_bytes`] or as already decoded text. Text that could be the
+//! start of a stop sequence is held back until later output shows whether it is, so a stop
+//! sequence spread over several tokens is never partially emitted.
+//!
+//! ```
+//! # use llama_cpp_2::completion::stop::StopSequences;
+//! let mut stops = StopSequences::new(["\nUser:", "</answer>"]);
+//!
+//! let check = stops.push_str("42\nUs");
+//! assert_eq!(check.text, "42");
+//! assert_eq!(check.stop, None);
+//!
+//! let check = stops.push_str("er: what?");
+//! assert_eq!(check.text, "");
+//! assert_eq!(check.stop, Some(0));
+//! assert_eq!(stops.stop_sequence(0), Some("\nUser:"));
+//! ```
+
+/// The result of feeding output to [`StopSequences`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopCheck<T> {
+    /// The output that is safe to emit. If a stop sequence matched this is everything before it,
+    /// otherwise it is everything except a trailing partial match.
+    pub text: T,
+    /// The index of the stop sequence that matched, if any.
+    pub stop: Option<usize>,
+}
+
+/// Matches generated output against a set of stop sequences.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct StopSequences {
+    stops: Vec<String>,
+    held: Vec<u8>,
+    stopped: Option<usize>,
+}
+
+impl StopSequences {
+    /// Create a matcher for `stops`. Empty stop sequences never match.
+    #[must_use]
+    pub fn new(stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            stops: stops.into_iter().map(Into::into).collect(),
+            held: Vec::new(),
+            stopped: None,
+        }
+    }
+
+    /// The stop sequence with index `i`.
+    #[must_use]
+    pub fn stop_sequence(&self, i: usize) -> Option<&str> {
+        self.stops.get(i).map(String::as_str)
+    }
+
+    /// The index of the stop sequence that matched, if one has.
+    #[must_use]
+    pub fn stopped(&self) -> Option<usize> {
+        self.stopped
+    }
+
+    /// Feed the next bytes of output. Once a stop sequence has matched, all further output is
+    /// discarded until [`Self::reset`] is called.
+    pub fn push(&mut self, bytes: &[u8]) -> StopCheck<Vec<u8>> {
+        if let Some(stop) = self.stopped {
+            return StopCheck {
+                text: Vec::new(),
+                stop: Some(stop),
+            };
+        }
+        self.held.extend_from_slice(bytes);
+
+        // the earliest match wins, then the first listed.
+        let matched = self
+            .stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| !stop.is_empty())
+            .filter_map(|(i, stop)| Some((find(&self.held, stop.as_bytes())?, i)))
+            .min();
+        if let Some((start, stop)) = matched {
+            self.held.truncate(start);
+            self.stopped = Some(stop);
+            return StopCheck {
+                text: std::mem::take(&mut self.held),
+                stop: Some(stop),
+            };
+        }
+
+        let partial = self
+            .stops
+            .iter()
+            .map(|stop| partial_match_len(&self.held, stop.as_bytes()))
+            .max()
+            .unwrap_or(0);
+        let held = self.held.split_off(self.held.len() - partial);
+        StopCheck {
+            text: std::mem::replace(&mut self.held, held),
+            stop: None,
+        }
+    }
+
+    /// Same as [`Self::push`] for output that is already text.
+    pub fn push_str(&mut self, text: &str) -> StopCheck<String> {
+        let StopCheck { text, stop } = self.push(text.as_bytes());
+        StopCheck {
+            // a stop sequence starts on a character boundary, so splitting valid text before one
+            // (or before a prefix of one) always leaves valid text and nothing is replaced.
+            text: String::from_utf8_lossy(&text).into_owned(),
+            stop,
+        }
+    }
+
+    /// Release the output held back as a possible partial match, e.g. once generation has ended
+    /// for another reason.
+    pub fn flush(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.held)
+    }
+
+    /// Forget all output seen so far (including a match), ready to check a new generation.
+    pub fn reset(&mut self) {
+        self.held.clear();
+        self.stopped = None;
+    }
+}
+
+/// The index of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The length of the longest suffix of `output` that is a proper prefix of `stop`.
+fn partial_match_len(output: &[u8], stop: &[u8]) -> usize {
+    (1..stop.len().min(output.len() + 1))
+        .rev()
+        .find(|&len| output.ends_with(&stop[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_split_over_many_pushes() {
+        let mut stops = StopSequences::new(["</answer>"]);
+        let mut output = String::new();
+        for piece in ["The answer", " is <", "/ans", "wer", "> ignored"] {
+            let check = stops.push_str(piece);
+            output.push_str(&check.text);
+            if check.stop.is_some() {
+                break;
+            }
+        }
+        assert_eq!(output, "The answer is ");
+        assert_eq!(stops.stopped(), Some(0));
+        assert_eq!(stops.push_str("more").text, "");
+    }
+
+    #[test]
+    fn partial_match_is_released_when_it_diverges() {
+        let mut stops = StopSequences::new(["abc", "xyz"]);
+        assert_eq!(stops.push_str("1ab").text, "1");
+        assert_eq!(stops.push_str("ab").text, "ab");
+        assert_eq!(stops.push_str("x").text, "ab");
+        assert_eq!(stops.flush(), b"x");
+        assert_eq!(stops.stopped(), None);
+    }
+
+    #[test]
+    fn earliest_match_wins() {
+        let mut stops = StopSequences::new(["cd", "bcde"]);
+        let check = stops.push_str("abcdef");
+        assert_eq!(check.text, "a");
+        assert_eq!(check.stop, Some(1));
+    }
+
+    #[test]
+    fn bytes_split_inside_a_character() {
+        let mut stops = StopSequences::new(["€!"]);
+        let bytes = "a€!".as_bytes();
+        assert_eq!(stops.push(&bytes[..2]).text, b"a");
+        let check = stops.push(&bytes[2..]);
+        assert_eq!(check.text, b"");
+        assert_eq!(check.stop, Some(0));
+    }
+}
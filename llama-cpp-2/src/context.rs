@@ -1,9 +1,13 @@
 //! Safe wrapper around `llama_context`.
 
+use std::ffi::c_void;
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::ptr::NonNull;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::llama_batch::LlamaBatch;
 use crate::model::{LlamaLoraAdapter, LlamaModel};
@@ -18,7 +22,11 @@ use crate::{
 
 pub mod kv_cache;
 pub mod params;
+pub mod prefix_cache;
+pub mod score;
+pub mod self_extend;
 pub mod session;
+pub mod shift;
 
 /// Safe wrapper around `llama_context`.
 #[allow(clippy::module_name_repetitions)]
@@ -28,8 +36,12 @@ pub struct LlamaContext<'a> {
     pub model: &'a LlamaModel,
     initialized_logits: Vec<i32>,
     embeddings_enabled: bool,
+    /// double boxed so it can be passed to llama.cpp as a thin pointer
+    abort_callback: Option<Box<AbortCallback>>,
 }
 
+type AbortCallback = Box<dyn FnMut() -> bool + Send>;
+
 impl Debug for LlamaContext<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LlamaContext")
@@ -49,6 +61,7 @@ impl<'model> LlamaContext<'model> {
             model: llama_model,
             initialized_logits: Vec::new(),
             embeddings_enabled,
+            abort_callback: None,
         }
     }
 
@@ -70,6 +83,13 @@ impl<'model> LlamaContext<'model> {
         unsafe { llama_cpp_sys_2::llama_n_ctx(self.context.as_ptr()) }
     }
 
+    /// Gets the maximum number of sequences, see
+    /// [`crate::context::params::LlamaContextParams::with_n_seq_max`].
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
     /// Decodes the batch.
     ///
     /// # Errors
@@ -296,6 +316,89 @@ impl<'model> LlamaContext<'model> {
         unsafe { slice::from_raw_parts(data, len) }
     }
 
+    /// Set a callback that is polled while a batch is computed. Returning `true` aborts the
+    /// computation, and [`LlamaContext::decode`] returns [`DecodeError::Aborted`].
+    ///
+    /// The callback may be called from a thread other than the one calling `decode`. A panic in
+    /// the callback aborts the computation. After an aborted decode the kv cache may hold some
+    /// of the batch's tokens; remove them before reusing the sequence.
+    ///
+    /// ```no_run
+    /// # use std::time::{Duration, Instant};
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let deadline = Instant::now() + Duration::from_secs(5);
+    /// ctx.set_abort_callback(move || Instant::now() > deadline);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_abort_callback(&mut self, callback: impl FnMut() -> bool + Send + 'static) {
+        unsafe extern "C" fn call_abort_callback(data: *mut c_void) -> bool {
+            let callback = unsafe { &mut *data.cast::<AbortCallback>() };
+            catch_unwind(AssertUnwindSafe(callback)).unwrap_or(true)
+        }
+
+        let mut callback: Box<AbortCallback> = Box::new(Box::new(callback));
+        let data = std::ptr::addr_of_mut!(*callback).cast::<c_void>();
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                Some(call_abort_callback),
+                data,
+            );
+        }
+        self.abort_callback = Some(callback);
+    }
+
+    /// Abort computations once `flag` is set, e.g. from another thread when a client
+    /// disconnects. The flag is not reset by an aborted decode.
+    ///
+    /// ```no_run
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::DecodeError;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut batch = LlamaBatch::new(512, 1);
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let cancelled = Arc::new(AtomicBool::new(false));
+    /// ctx.set_abort_flag(Arc::clone(&cancelled));
+    ///
+    /// // on another thread: cancelled.store(true, Ordering::Relaxed);
+    /// match ctx.decode(&mut batch) {
+    ///     Err(DecodeError::Aborted) => println!("cancelled"),
+    ///     result => result?,
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_abort_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.set_abort_callback(move || flag.load(Ordering::Relaxed));
+    }
+
+    /// Remove the abort callback set by [`LlamaContext::set_abort_callback`] or
+    /// [`LlamaContext::set_abort_flag`].
+    pub fn clear_abort_callback(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            );
+        }
+        self.abort_callback = None;
+    }
+
     /// Reset the timings for the context.
     pub fn reset_timings(&mut self) {
         unsafe { llama_cpp_sys_2::llama_perf_context_reset(self.context.as_ptr()) }
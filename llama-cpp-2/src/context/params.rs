@@ -207,6 +207,37 @@ impl LlamaContextParams {
         self.context_params.n_ubatch
     }
 
+    /// Set the maximum number of sequences (i.e. distinct states for recurrent models) the
+    /// context can hold.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_seq_max(4);
+    /// assert_eq!(params.n_seq_max(), 4);
+    /// ```
+    #[must_use]
+    pub fn with_n_seq_max(mut self, n_seq_max: u32) -> Self {
+        self.context_params.n_seq_max = n_seq_max;
+        self
+    }
+
+    /// Get the maximum number of sequences
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.n_seq_max(), 1);
+    /// ```
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        self.context_params.n_seq_max
+    }
+
     /// Set the `flash_attention` parameter
     ///
     /// # Examples
@@ -0,0 +1,470 @@
+//! Reuse the kv cache of prompts that share a prefix (e.g. a long system prompt).
+//!
+//! A [`PrefixCache`] owns a set of sequence ids in a [`LlamaContext`] and remembers which tokens
+//! are resident in each. [`PrefixCache::load`] finds the sequence sharing the longest prefix with
+//! a new prompt, copies (or keeps) that prefix and only decodes the rest of the prompt.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::context::prefix_cache::PrefixCache;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let mut ctx = model.new_context(&backend, params)?;
+//! let mut cache = PrefixCache::new(&ctx, 0..4)?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! for question in ["What is 2 + 2?", "What is 3 + 3?"] {
+//!     let prompt = format!("You are a helpful assistant.\nUser: {question}\nAssistant:");
+//!     let prompt = model.str_to_token(&prompt, AddBos::Always)?;
+//!     let loaded = cache.load(&mut ctx, &prompt)?;
+//!     let token = sampler.sample(&ctx, loaded.logits_index);
+//!     // ... decode `token` into `loaded.seq_id` and tell the cache about it.
+//!     cache.extend(loaded.seq_id, &[token]);
+//! }
+//! println!("{:?}", cache.stats());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// An error that can occur while loading a prompt through a [`PrefixCache`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum PrefixCacheError {
+    /// The prompt was empty, so there are no logits to sample from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt has {len} tokens but the context only holds {n_ctx} tokens")]
+    PromptTooLong {
+        /// the length of the prompt
+        len: usize,
+        /// the size of the context
+        n_ctx: u32,
+    },
+    /// Decoding the rest of the prompt failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// A sequence id is negative or not below the number of sequences of the context.
+    #[error("sequence id {seq_id} is not in 0..{n_seq_max}")]
+    InvalidSeqId {
+        /// the invalid sequence id
+        seq_id: i32,
+        /// the number of sequences of the context
+        n_seq_max: u32,
+    },
+    /// A sequence id or position did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// Hit and miss statistics of a [`PrefixCache`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct PrefixCacheStats {
+    /// prompts that reused at least one cached token
+    pub hits: u64,
+    /// prompts that had to be decoded from scratch
+    pub misses: u64,
+    /// prompt tokens that were reused from the cache
+    pub reused_tokens: u64,
+    /// prompt tokens that were decoded
+    pub decoded_tokens: u64,
+}
+
+impl PrefixCacheStats {
+    /// The fraction of prompt tokens that were reused, or `0` if no prompt was loaded.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn token_hit_rate(&self) -> f64 {
+        let total = self.reused_tokens + self.decoded_tokens;
+        if total == 0 {
+            0.0
+        } else {
+            self.reused_tokens as f64 / total as f64
+        }
+    }
+}
+
+/// A prompt that was loaded by [`PrefixCache::load`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedPrompt {
+    /// The sequence holding the prompt. Generation should continue in this sequence.
+    pub seq_id: i32,
+    /// The number of prompt tokens reused from the cache.
+    pub n_reused: usize,
+    /// The number of prompt tokens that were decoded.
+    pub n_decoded: usize,
+    /// The index of the logits of the last prompt token in the last decoded batch.
+    pub logits_index: i32,
+}
+
+/// The tokens resident in one sequence.
+#[derive(Debug, Clone)]
+struct Slot {
+    seq_id: i32,
+    tokens: Vec<LlamaToken>,
+    /// when the slot was last used, for picking which slot to evict
+    last_used: u64,
+}
+
+/// Where to load a prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Plan {
+    /// the slot to copy the reused prefix from
+    src: usize,
+    /// the slot to load the prompt into
+    dest: usize,
+    /// the number of tokens to reuse
+    n_reuse: usize,
+}
+
+/// Tracks the token prefixes resident in a set of sequences and reuses them for new prompts.
+///
+/// The cache assumes it owns its sequences: each holds tokens at positions `0..n`. If a sequence
+/// is modified other than by appending the tokens passed to [`PrefixCache::extend`], call
+/// [`PrefixCache::invalidate`].
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct PrefixCache {
+    slots: Vec<Slot>,
+    clock: u64,
+    stats: PrefixCacheStats,
+}
+
+impl PrefixCache {
+    /// Create a cache using the sequences `seq_ids` of `ctx`, which should be empty. The context
+    /// must be created with enough sequences (see
+    /// [`crate::context::params::LlamaContextParams::with_n_seq_max`]).
+    ///
+    /// # Errors
+    ///
+    /// - [`PrefixCacheError::InvalidSeqId`] if a sequence id is negative or not below the
+    ///   context's `n_seq_max`.
+    ///
+    /// # Panics
+    ///
+    /// - `seq_ids` is empty.
+    pub fn new(
+        ctx: &LlamaContext,
+        seq_ids: impl IntoIterator<Item = i32>,
+    ) -> Result<Self, PrefixCacheError> {
+        let n_seq_max = ctx.n_seq_max();
+        let seq_ids = seq_ids.into_iter().collect::<Vec<_>>();
+        if let Some(&seq_id) = seq_ids
+            .iter()
+            .find(|&&seq_id| u32::try_from(seq_id).map_or(true, |id| id >= n_seq_max))
+        {
+            return Err(PrefixCacheError::InvalidSeqId { seq_id, n_seq_max });
+        }
+        Ok(Self::with_seq_ids(seq_ids))
+    }
+
+    /// Create a cache using `seq_ids` without checking them against a context.
+    fn with_seq_ids(seq_ids: Vec<i32>) -> Self {
+        let slots = seq_ids
+            .into_iter()
+            .map(|seq_id| Slot {
+                seq_id,
+                tokens: Vec::new(),
+                last_used: 0,
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            !slots.is_empty(),
+            "a prefix cache needs at least one sequence"
+        );
+        Self {
+            slots,
+            clock: 0,
+            stats: PrefixCacheStats::default(),
+        }
+    }
+
+    /// Load `prompt` into one of the cache's sequences, reusing the longest cached prefix and
+    /// decoding the rest. The last token is always decoded so its logits are available.
+    ///
+    /// If a sequence holds a prefix of `prompt` (e.g. an earlier turn of the same conversation),
+    /// the prompt is loaded into that sequence. Otherwise the least recently used sequence is
+    /// replaced, with the longest matching prefix copied into it from whichever sequence holds it.
+    ///
+    /// # Errors
+    ///
+    /// - [`PrefixCacheError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`PrefixCacheError::PromptTooLong`] if `prompt` does not fit into the context.
+    /// - [`PrefixCacheError::DecodeError`] if decoding failed. The sequence is then forgotten.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context does not fit into a [`usize`]
+    pub fn load(
+        &mut self,
+        ctx: &mut LlamaContext,
+        prompt: &[LlamaToken],
+    ) -> Result<LoadedPrompt, PrefixCacheError> {
+        if prompt.is_empty() {
+            return Err(PrefixCacheError::EmptyPrompt);
+        }
+        let n_ctx = ctx.n_ctx();
+        if prompt.len() > usize::try_from(n_ctx).unwrap_or(usize::MAX) {
+            return Err(PrefixCacheError::PromptTooLong {
+                len: prompt.len(),
+                n_ctx,
+            });
+        }
+
+        let Plan {
+            src,
+            dest,
+            mut n_reuse,
+        } = self.plan(prompt);
+        let seq_id = self.slots[dest].seq_id;
+        let dest_seq = Some(u32::try_from(seq_id).expect("sequence ids are not negative"));
+        let n_reuse_pos = u32::try_from(n_reuse).map_err(KvCacheConversionError::P0TooLarge)?;
+        if src == dest {
+            // drop whatever follows the shared prefix. This can fail for recurrent models, in
+            // which case the whole sequence is decoded again.
+            if !ctx.clear_kv_cache_seq(dest_seq, Some(n_reuse_pos), None)? {
+                ctx.clear_kv_cache_seq(dest_seq, None, None)?;
+                n_reuse = 0;
+            }
+        } else {
+            ctx.clear_kv_cache_seq(dest_seq, None, None)?;
+            let src_seq = self.slots[src].seq_id;
+            ctx.copy_kv_cache_seq(src_seq, seq_id, None, Some(n_reuse_pos))?;
+        }
+
+        self.clock += 1;
+        let slot = &mut self.slots[dest];
+        slot.last_used = self.clock;
+        slot.tokens.clear();
+        let logits_index = match decode_suffix(ctx, seq_id, prompt, n_reuse) {
+            Ok(logits_index) => logits_index,
+            Err(error) => {
+                // the sequence is in an unknown state
+                ctx.clear_kv_cache_seq(dest_seq, None, None)?;
+                return Err(error);
+            }
+        };
+        slot.tokens.extend_from_slice(prompt);
+
+        let n_decoded = prompt.len() - n_reuse;
+        if n_reuse > 0 {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        self.stats.reused_tokens += n_reuse as u64;
+        self.stats.decoded_tokens += n_decoded as u64;
+
+        Ok(LoadedPrompt {
+            seq_id,
+            n_reused: n_reuse,
+            n_decoded,
+            logits_index,
+        })
+    }
+
+    /// Record that `tokens` were decoded into `seq_id` after the tokens already there, e.g. the
+    /// tokens generated for a prompt. Sequences not owned by the cache are ignored.
+    pub fn extend(&mut self, seq_id: i32, tokens: &[LlamaToken]) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.seq_id == seq_id) {
+            slot.tokens.extend_from_slice(tokens);
+        }
+    }
+
+    /// Forget what is in `seq_id`, e.g. after modifying it directly. The kv cache is not changed;
+    /// the sequence is cleared the next time it is used.
+    pub fn invalidate(&mut self, seq_id: i32) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.seq_id == seq_id) {
+            slot.tokens.clear();
+        }
+    }
+
+    /// The tokens the cache believes are resident in `seq_id`, or `None` if the cache does not
+    /// own it.
+    #[must_use]
+    pub fn tokens(&self, seq_id: i32) -> Option<&[LlamaToken]> {
+        self.slots
+            .iter()
+            .find(|slot| slot.seq_id == seq_id)
+            .map(|slot| slot.tokens.as_slice())
+    }
+
+    /// The hit and miss statistics since the cache was created or [`Self::reset_stats`].
+    #[must_use]
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    /// Reset the statistics.
+    pub fn reset_stats(&mut self) {
+        self.stats = PrefixCacheStats::default();
+    }
+
+    /// Decide where to load `prompt` and how much of it to reuse.
+    fn plan(&self, prompt: &[LlamaToken]) -> Plan {
+        // the last token is decoded again, even if it is cached, to get its logits.
+        let reusable = |slot: &Slot| common_prefix_len(&slot.tokens, prompt).min(prompt.len() - 1);
+
+        // prefer the most recently used slot on ties, so a conversation stays in its sequence.
+        let (src, n_reuse) = self
+            .slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| (i, reusable(slot)))
+            .max_by_key(|&(i, n_reuse)| (n_reuse, self.slots[i].last_used))
+            .expect("there is at least one slot");
+
+        // extending (or repeating) the conversation held in `src` does not lose anything. This
+        // compares the uncapped prefix, so re-sending the same prompt stays in its sequence.
+        let cached = &self.slots[src].tokens;
+        if common_prefix_len(cached, prompt) == cached.len() {
+            return Plan {
+                src,
+                dest: src,
+                n_reuse,
+            };
+        }
+        let dest = self
+            .slots
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, slot)| slot.last_used)
+            .map(|(i, _)| i)
+            .expect("there is at least one slot");
+        Plan { src, dest, n_reuse }
+    }
+}
+
+/// Decode `prompt[n_reuse..]` into `seq_id` at positions following the reused prefix, returning
+/// the index of the logits of the last token.
+fn decode_suffix(
+    ctx: &mut LlamaContext,
+    seq_id: i32,
+    prompt: &[LlamaToken],
+    n_reuse: usize,
+) -> Result<i32, PrefixCacheError> {
+    let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+    let mut batch = LlamaBatch::new(n_batch, 1);
+    let mut pos = i32::try_from(n_reuse).map_err(KvCacheConversionError::P0TooLarge)?;
+    let chunks = prompt[n_reuse..].chunks(n_batch);
+    let n_chunks = chunks.len();
+    for (i, chunk) in chunks.enumerate() {
+        batch.clear();
+        let is_last_chunk = i + 1 == n_chunks;
+        for (j, &token) in chunk.iter().enumerate() {
+            batch.add(token, pos, &[seq_id], is_last_chunk && j + 1 == chunk.len())?;
+            pos += 1;
+        }
+        ctx.decode(&mut batch)?;
+    }
+    Ok(batch.n_tokens() - 1)
+}
+
+/// The length of the longest common prefix of `a` and `b`.
+fn common_prefix_len(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().copied().map(LlamaToken).collect()
+    }
+
+    fn cache(contents: &[(&[i32], u64)]) -> PrefixCache {
+        let mut cache =
+            PrefixCache::with_seq_ids((0..i32::try_from(contents.len()).unwrap()).collect());
+        for (slot, &(ids, last_used)) in cache.slots.iter_mut().zip(contents) {
+            slot.tokens = tokens(ids);
+            slot.last_used = last_used;
+        }
+        cache
+    }
+
+    #[test]
+    fn sequence_ids_must_fit_the_context() {
+        use crate::context::params::LlamaContextParams;
+        use crate::gguf::tiny::test_model;
+        use std::num::NonZeroU32;
+
+        let (backend, model) = test_model();
+        let params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(64))
+            .with_n_seq_max(2);
+        let ctx = model.new_context(backend, params).unwrap();
+        assert!(PrefixCache::new(&ctx, [0, 1]).is_ok());
+        for seq_id in [-1, 2] {
+            assert!(matches!(
+                PrefixCache::new(&ctx, [0, seq_id]),
+                Err(PrefixCacheError::InvalidSeqId { seq_id: id, n_seq_max: 2 }) if id == seq_id
+            ));
+        }
+    }
+
+    #[test]
+    fn continuing_a_conversation_stays_in_its_sequence() {
+        let cache = cache(&[(&[1, 2, 3], 1), (&[1, 2, 7, 8], 2)]);
+        let plan = cache.plan(&tokens(&[1, 2, 3, 4, 5]));
+        assert_eq!(
+            plan,
+            Plan {
+                src: 0,
+                dest: 0,
+                n_reuse: 3
+            }
+        );
+    }
+
+    #[test]
+    fn shared_prefix_is_copied_into_the_least_recently_used_sequence() {
+        let cache = cache(&[(&[1, 2, 3], 3), (&[9], 1), (&[1, 2, 7, 8], 2)]);
+        let plan = cache.plan(&tokens(&[1, 2, 7, 9]));
+        assert_eq!(
+            plan,
+            Plan {
+                src: 2,
+                dest: 1,
+                n_reuse: 3
+            }
+        );
+    }
+
+    #[test]
+    fn last_prompt_token_is_always_decoded() {
+        let cache = cache(&[(&[1, 2, 3], 1)]);
+        let plan = cache.plan(&tokens(&[1, 2, 3]));
+        assert_eq!(plan.n_reuse, 2);
+        assert_eq!(plan.dest, 0);
+    }
+
+    #[test]
+    fn repeating_a_prompt_stays_in_its_sequence() {
+        let cache = cache(&[(&[1, 2, 3], 2), (&[9], 1)]);
+        let plan = cache.plan(&tokens(&[1, 2, 3]));
+        assert_eq!(
+            plan,
+            Plan {
+                src: 0,
+                dest: 0,
+                n_reuse: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Score token sequences by their likelihood under the model.
+//!
+//! [`LlamaContext::score`] computes the log-probability of a continuation given a context, which
+//! is what multiple-choice evaluation and reranking need. [`LlamaContext::perplexity`] computes
+//! the perplexity of a long text the same way as llama.cpp's `perplexity` tool.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let question = model.str_to_token("Q: What is the capital of France?\nA:", AddBos::Always)?;
+//! let mut best = None;
+//! for answer in [" Paris", " London", " Berlin"] {
+//!     let answer_tokens = model.str_to_token(answer, AddBos::Never)?;
+//!     let score = ctx.score(&question, &answer_tokens)?;
+//!     if best.as_ref().is_none_or(|(_, logprob)| score.logprob > *logprob) {
+//!         best = Some((answer, score.logprob));
+//!     }
+//! }
+//! println!("{best:?}");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::logprobs::{TokenLogprob, TokenLogprobs};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// The sequence used for scoring.
+const SEQ_ID: i32 = 0;
+
+/// An error that can occur while scoring tokens.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ScoreError {
+    /// There is no context token to predict the first continuation token from.
+    #[error("the context is empty")]
+    EmptyContext,
+    /// The context and continuation do not fit into the context window.
+    #[error("{len} tokens do not fit into a context of {n_ctx} tokens")]
+    TooLong {
+        /// the number of tokens to decode
+        len: usize,
+        /// the size of the context
+        n_ctx: u32,
+    },
+    /// The text is shorter than a single chunk.
+    #[error("the text has {len} tokens but a chunk needs {n_ctx} tokens")]
+    TooShort {
+        /// the number of tokens in the text
+        len: usize,
+        /// the size of the context, which is the chunk size
+        n_ctx: u32,
+    },
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// Clearing the kv cache failed.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// The log-likelihood of a continuation, see [`LlamaContext::score`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuationScore {
+    /// the log-probability of each continuation token given everything before it
+    pub tokens: Vec<TokenLogprob>,
+    /// the sum of the log-probabilities of the continuation tokens
+    pub logprob: f64,
+}
+
+impl ContinuationScore {
+    /// The average log-probability per token. This is `0` for an empty continuation.
+    #[must_use]
+    pub fn mean_logprob(&self) -> f64 {
+        if self.tokens.is_empty() {
+            return 0.0;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let len = self.tokens.len() as f64;
+        self.logprob / len
+    }
+
+    /// The perplexity of the continuation: `exp(-mean_logprob)`.
+    #[must_use]
+    pub fn perplexity(&self) -> f64 {
+        (-self.mean_logprob()).exp()
+    }
+}
+
+/// The result of [`LlamaContext::perplexity`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Perplexity {
+    /// the running perplexity estimate after each chunk, as printed by llama.cpp
+    pub chunks: Vec<f64>,
+    /// the total negative log-likelihood of the scored tokens
+    pub nll: f64,
+    /// the number of scored tokens
+    pub n_scored: usize,
+}
+
+impl Perplexity {
+    /// The perplexity over all chunks: `exp(nll / n_scored)`.
+    #[must_use]
+    pub fn value(&self) -> f64 {
+        if self.n_scored == 0 {
+            return f64::NAN;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let n_scored = self.n_scored as f64;
+        (self.nll / n_scored).exp()
+    }
+
+    fn add_chunk(&mut self, nll: f64, n_scored: usize) {
+        self.nll += nll;
+        self.n_scored += n_scored;
+        self.chunks.push(self.value());
+    }
+}
+
+impl LlamaContext<'_> {
+    /// The log-probability of each token of `continuation` given `context` and the preceding
+    /// continuation tokens.
+    ///
+    /// Sequence `0` of the kv cache is cleared before and after scoring.
+    ///
+    /// # Errors
+    ///
+    /// - [`ScoreError::EmptyContext`] if `context` is empty.
+    /// - [`ScoreError::TooLong`] if `context` and `continuation` do not fit into the context.
+    /// - [`ScoreError::DecodeError`] if decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context does not fit into a [`usize`]
+    pub fn score(
+        &mut self,
+        context: &[LlamaToken],
+        continuation: &[LlamaToken],
+    ) -> Result<ContinuationScore, ScoreError> {
+        if context.is_empty() {
+            return Err(ScoreError::EmptyContext);
+        }
+        let tokens = [context, continuation].concat();
+        self.check_fits(tokens.len())?;
+
+        let mut scored = Vec::with_capacity(continuation.len());
+        self.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), None, None)?;
+        let decoded = self.decode_scored(&tokens, context.len() - 1, |i, logits| {
+            scored.push(logprob(logits, tokens[i + 1]));
+        });
+        self.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), None, None)?;
+        decoded?;
+
+        let logprob = scored.iter().map(|t| f64::from(t.logprob)).sum();
+        Ok(ContinuationScore {
+            tokens: scored,
+            logprob,
+        })
+    }
+
+    /// The perplexity of `tokens`, computed like llama.cpp's `perplexity` tool.
+    ///
+    /// The text is split into chunks of `n_ctx` tokens and each chunk is decoded from an empty
+    /// kv cache. Only the second half of each chunk is scored, so every scored token has at
+    /// least `n_ctx / 2` tokens of context. If `tokens` starts with the BOS token, the first
+    /// token of every chunk is replaced by it. Tokens after the last full chunk are ignored.
+    ///
+    /// Sequence `0` of the kv cache is cleared before every chunk and after the last (or a failed)
+    /// one.
+    ///
+    /// # Errors
+    ///
+    /// - [`ScoreError::TooShort`] if `tokens` is shorter than a chunk.
+    /// - [`ScoreError::DecodeError`] if decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - `n_ctx` or the batch size of the context does not fit into a [`usize`]
+    pub fn perplexity(&mut self, tokens: &[LlamaToken]) -> Result<Perplexity, ScoreError> {
+        let n_ctx = self.n_ctx();
+        let chunk_len = usize::try_from(n_ctx).expect("n_ctx does not fit into a usize");
+        if chunk_len < 2 || tokens.len() < chunk_len {
+            return Err(ScoreError::TooShort {
+                len: tokens.len(),
+                n_ctx,
+            });
+        }
+        let bos = self.model.token_bos();
+        let add_bos = tokens.first() == Some(&bos);
+
+        let mut perplexity = Perplexity::default();
+        for chunk in tokens.chunks_exact(chunk_len) {
+            let mut chunk = chunk.to_vec();
+            if add_bos {
+                chunk[0] = bos;
+            }
+            let mut nll = 0.0;
+            let mut n_scored = 0;
+            self.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), None, None)?;
+            let decoded = self.decode_scored(&chunk, chunk_len / 2, |i, logits| {
+                nll -= f64::from(logprob(logits, chunk[i + 1]).logprob);
+                n_scored += 1;
+            });
+            if let Err(error) = decoded {
+                self.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), None, None)?;
+                return Err(error);
+            }
+            perplexity.add_chunk(nll, n_scored);
+        }
+        self.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), None, None)?;
+        Ok(perplexity)
+    }
+
+    fn check_fits(&self, len: usize) -> Result<(), ScoreError> {
+        let n_ctx = self.n_ctx();
+        if usize::try_from(n_ctx).is_ok_and(|n_ctx| len <= n_ctx) {
+            Ok(())
+        } else {
+            Err(ScoreError::TooLong { len, n_ctx })
+        }
+    }
+
+    /// Decode `tokens` into an empty sequence, calling `on_logits(i, logits)` with the logits
+    /// that predict `tokens[i + 1]` for every `i` from `first_scored` on.
+    fn decode_scored(
+        &mut self,
+        tokens: &[LlamaToken],
+        first_scored: usize,
+        mut on_logits: impl FnMut(usize, &[f32]),
+    ) -> Result<(), ScoreError> {
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch does not fit into a usize");
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            let start = chunk_index * n_batch;
+            batch.clear();
+            for (i, &token) in (start..).zip(chunk) {
+                let pos = i32::try_from(i).map_err(|_| ScoreError::TooLong {
+                    len: tokens.len(),
+                    n_ctx: self.n_ctx(),
+                })?;
+                let scored = first_scored <= i && i + 1 < tokens.len();
+                batch.add(token, pos, &[SEQ_ID], scored)?;
+            }
+            self.decode(&mut batch)?;
+            for (i, batch_index) in (start..start + chunk.len()).zip(0..) {
+                if first_scored <= i && i + 1 < tokens.len() {
+                    on_logits(i, self.get_logits_ith(batch_index));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The log-probability of `token` under `logits`.
+fn logprob(logits: &[f32], token: LlamaToken) -> TokenLogprob {
+    TokenLogprobs::from_logits(logits, token, 0).chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logprob_does_not_collect_top_tokens() {
+        let logits = [1.0, 3.0, 2.0];
+        let logprobs = TokenLogprobs::from_logits(&logits, LlamaToken(2), 0);
+        assert_eq!(logprobs.top.capacity(), 0);
+        let expected = crate::token::logprobs::log_softmax(&logits)[2];
+        assert!((logprob(&logits, LlamaToken(2)).logprob - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn uniform_logits_have_vocab_size_perplexity() {
+        let logits = [0.5; 8];
+        let tokens = (0..4)
+            .map(|token| logprob(&logits, LlamaToken(token)))
+            .collect::<Vec<_>>();
+        let logprob = tokens.iter().map(|t| f64::from(t.logprob)).sum();
+        let score = ContinuationScore { tokens, logprob };
+        assert!((score.perplexity() - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn perplexity_accumulates_over_chunks() {
+        let mut perplexity = Perplexity::default();
+        perplexity.add_chunk(2.0 * 4.0_f64.ln(), 2);
+        perplexity.add_chunk(2.0 * 16.0_f64.ln(), 2);
+        assert!((perplexity.chunks[0] - 4.0).abs() < 1e-9);
+        // the geometric mean of 4 and 16
+        assert!((perplexity.value() - 8.0).abs() < 1e-9);
+        assert_eq!(perplexity.chunks.len(), 2);
+        assert!(Perplexity::default().value().is_nan());
+    }
+}
@@ -0,0 +1,299 @@
+//! Self-extend (grouped attention): run a model on more tokens than it was trained on by
+//! compressing the positions of older tokens, as `llama-cli --grp-attn-n --grp-attn-w` does.
+//!
+//! Tokens within the last `window` positions keep their exact positions. Older tokens are merged
+//! into groups of `group_factor` that share a position, so a sequence of `n` tokens only spans
+//! roughly `n / group_factor` positions.
+//!
+//! ```no_run
+//! # use std::num::NonZeroU8;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::context::self_extend::SelfExtend;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let long_document = "";
+//! // a model trained on 4096 tokens with a 16k context
+//! let params = LlamaContextParams::default().with_n_ctx(std::num::NonZeroU32::new(16384));
+//! let mut ctx = model.new_context(&backend, params)?;
+//! let mut self_extend = SelfExtend::new(NonZeroU8::new(4).unwrap(), 1024)?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! let prompt = model.str_to_token(long_document, AddBos::Always)?;
+//! let mut logits = self_extend.decode(&mut ctx, 0, &prompt)?;
+//! for _ in 0..128 {
+//!     let token = sampler.sample(&ctx, logits);
+//!     if model.is_eog_token(token) {
+//!         break;
+//!     }
+//!     logits = self_extend.decode(&mut ctx, 0, &[token])?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::num::NonZeroU8;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// An error that can occur while configuring or using [`SelfExtend`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum SelfExtendError {
+    /// The window must be a non-zero multiple of the group factor.
+    #[error("window {window} is not a non-zero multiple of the group factor {group_factor}")]
+    InvalidWindow {
+        /// the group factor
+        group_factor: u8,
+        /// the window width
+        window: u32,
+    },
+    /// There were no tokens to decode.
+    #[error("no tokens to decode")]
+    NoTokens,
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// A position did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// An update to the positions of a range of the kv cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PositionUpdate {
+    /// add `delta` to positions in `[p0, p1)`
+    Add { p0: i32, p1: i32, delta: i32 },
+    /// divide positions in `[p0, p1)` by the group factor
+    Div { p0: i32, p1: i32 },
+}
+
+/// Decodes tokens into a sequence while compressing the positions of older tokens.
+///
+/// A `SelfExtend` tracks the state of one sequence, which must be empty when it is created, and
+/// all tokens must be decoded into that sequence through [`SelfExtend::decode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfExtend {
+    group_factor: NonZeroU8,
+    window: i32,
+    /// the position the next token is placed at
+    n_past: i32,
+    /// the start of the part of the sequence that has not been grouped yet
+    grouped_until: i32,
+}
+
+impl SelfExtend {
+    /// Create a self-extend state with the given group factor (`--grp-attn-n`) and window width
+    /// (`--grp-attn-w`). A group factor of 1 disables grouping.
+    ///
+    /// # Errors
+    ///
+    /// - [`SelfExtendError::InvalidWindow`] if `window` is not a non-zero multiple of
+    ///   `group_factor`.
+    pub fn new(group_factor: NonZeroU8, window: u32) -> Result<Self, SelfExtendError> {
+        let invalid = || SelfExtendError::InvalidWindow {
+            group_factor: group_factor.get(),
+            window,
+        };
+        if window == 0 || !window.is_multiple_of(u32::from(group_factor.get())) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            group_factor,
+            window: i32::try_from(window).map_err(|_| invalid())?,
+            n_past: 0,
+            grouped_until: 0,
+        })
+    }
+
+    /// The group factor.
+    #[must_use]
+    pub fn group_factor(&self) -> NonZeroU8 {
+        self.group_factor
+    }
+
+    /// The window width.
+    #[must_use]
+    pub fn window(&self) -> u32 {
+        self.window.unsigned_abs()
+    }
+
+    /// The position the next decoded token is placed at. This is less than the number of decoded
+    /// tokens once grouping has started.
+    #[must_use]
+    pub fn n_past(&self) -> i32 {
+        self.n_past
+    }
+
+    /// Decode `tokens` into `seq_id` after the tokens already decoded, grouping older positions
+    /// whenever a window fills up. Long inputs are split into batches that each fit into the
+    /// current window.
+    ///
+    /// Returns the index of the logits of the last token in the last batch.
+    ///
+    /// # Errors
+    ///
+    /// - [`SelfExtendError::NoTokens`] if `tokens` is empty.
+    /// - [`SelfExtendError::DecodeError`] if decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context does not fit into a [`usize`]
+    pub fn decode(
+        &mut self,
+        ctx: &mut LlamaContext,
+        seq_id: i32,
+        tokens: &[LlamaToken],
+    ) -> Result<i32, SelfExtendError> {
+        if tokens.is_empty() {
+            return Err(SelfExtendError::NoTokens);
+        }
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut rest = tokens;
+        while !rest.is_empty() {
+            for update in self.compress() {
+                apply_update(ctx, seq_id, self.group_factor, update)?;
+            }
+            let chunk_len = rest.len().min(n_batch).min(self.n_until_full());
+            let (chunk, remaining) = rest.split_at(chunk_len);
+            rest = remaining;
+
+            batch.clear();
+            for (i, &token) in chunk.iter().enumerate() {
+                let logits = rest.is_empty() && i + 1 == chunk.len();
+                batch.add(token, self.n_past, &[seq_id], logits)?;
+                self.n_past += 1;
+            }
+            ctx.decode(&mut batch)?;
+        }
+        Ok(batch.n_tokens() - 1)
+    }
+
+    /// The number of tokens that can be decoded before the current window is full.
+    fn n_until_full(&self) -> usize {
+        if self.group_factor.get() == 1 {
+            return usize::MAX;
+        }
+        usize::try_from(self.grouped_until + self.window - self.n_past)
+            .expect("the window is compressed before it overflows")
+    }
+
+    /// Group every full window, returning the position updates to apply to the kv cache. This
+    /// is the same computation as `llama-cli`.
+    fn compress(&mut self) -> Vec<PositionUpdate> {
+        let n = i32::from(self.group_factor.get());
+        let w = self.window;
+        let mut updates = Vec::new();
+        if n == 1 {
+            return updates;
+        }
+        while self.n_past >= self.grouped_until + w {
+            let ga_i = self.grouped_until;
+            let ib = (n * ga_i) / w;
+            let bd = (w / n) * (n - 1);
+            let dd = (w / n) - ib * bd - w;
+
+            updates.push(PositionUpdate::Add {
+                p0: ga_i,
+                p1: self.n_past,
+                delta: ib * bd,
+            });
+            updates.push(PositionUpdate::Div {
+                p0: ga_i + ib * bd,
+                p1: ga_i + ib * bd + w,
+            });
+            updates.push(PositionUpdate::Add {
+                p0: ga_i + ib * bd + w,
+                p1: self.n_past + ib * bd,
+                delta: dd,
+            });
+
+            self.n_past -= bd;
+            self.grouped_until += w / n;
+        }
+        updates
+    }
+}
+
+fn apply_update(
+    ctx: &mut LlamaContext,
+    seq_id: i32,
+    group_factor: NonZeroU8,
+    update: PositionUpdate,
+) -> Result<(), KvCacheConversionError> {
+    let p0 = |p0: i32| u32::try_from(p0).map_err(KvCacheConversionError::P0TooLarge);
+    let p1 = |p1: i32| u32::try_from(p1).map_err(KvCacheConversionError::P1TooLarge);
+    match update {
+        PositionUpdate::Add {
+            p0: a,
+            p1: b,
+            delta,
+        } => ctx.kv_cache_seq_add(seq_id, Some(p0(a)?), Some(p1(b)?), delta),
+        PositionUpdate::Div { p0: a, p1: b } => {
+            ctx.kv_cache_seq_div(seq_id, Some(p0(a)?), Some(p1(b)?), group_factor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `n` tokens one at a time, applying the updates to a list of token positions.
+    fn positions(self_extend: &mut SelfExtend, n: usize) -> Vec<i32> {
+        let mut positions = Vec::new();
+        let group_factor = i32::from(self_extend.group_factor.get());
+        for _ in 0..n {
+            for update in self_extend.compress() {
+                match update {
+                    PositionUpdate::Add { p0, p1, delta } => positions
+                        .iter_mut()
+                        .filter(|pos| (p0..p1).contains(*pos))
+                        .for_each(|pos| *pos += delta),
+                    PositionUpdate::Div { p0, p1 } => positions
+                        .iter_mut()
+                        .filter(|pos| (p0..p1).contains(*pos))
+                        .for_each(|pos| *pos /= group_factor),
+                }
+            }
+            positions.push(self_extend.n_past);
+            self_extend.n_past += 1;
+        }
+        positions
+    }
+
+    #[test]
+    fn older_positions_are_grouped() {
+        let mut self_extend = SelfExtend::new(NonZeroU8::new(2).unwrap(), 4).unwrap();
+        assert_eq!(
+            positions(&mut self_extend, 10),
+            [0, 0, 1, 1, 2, 2, 3, 3, 4, 5]
+        );
+        assert_eq!(self_extend.n_past(), 6);
+    }
+
+    #[test]
+    fn group_factor_one_is_a_no_op() {
+        let mut self_extend = SelfExtend::new(NonZeroU8::MIN, 4).unwrap();
+        assert_eq!(positions(&mut self_extend, 6), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn window_must_be_a_multiple_of_the_group_factor() {
+        let group_factor = NonZeroU8::new(4).unwrap();
+        assert!(SelfExtend::new(group_factor, 6).is_err());
+        assert!(SelfExtend::new(group_factor, 0).is_err());
+        assert!(SelfExtend::new(group_factor, 8).is_ok());
+    }
+}
@@ -3,8 +3,13 @@
 use crate::context::LlamaContext;
 use crate::token::LlamaToken;
 use std::ffi::{CString, NulError};
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
+/// The magic number at the start of a sequence state file ("ggsq").
+const STATE_SEQ_MAGIC: u32 = 0x6767_7371;
+
 /// Failed to save a Session file
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum SaveSessionError {
@@ -44,6 +49,43 @@ pub enum LoadSessionError {
         /// The maximum length
         max_tokens: usize,
     },
+
+    /// The file does not start with the header of a sequence state file
+    #[error("{0} is not a sequence state file")]
+    NotASeqStateFile(PathBuf),
+}
+
+/// Failed to take a snapshot of the state of a context
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum StateSnapshotError {
+    /// llama.cpp failed to copy the state
+    #[error("Failed to copy the state of the context ({size} bytes)")]
+    FailedToCopy {
+        /// The size of the buffer the state was copied into
+        size: usize,
+    },
+}
+
+/// Failed to restore the state of a context from a snapshot
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum RestoreStateError {
+    /// llama.cpp failed to read the state, e.g. it is truncated or from an incompatible context
+    #[error("Failed to restore the state of the context from {len} bytes")]
+    FailedToRestore {
+        /// The length of the snapshot
+        len: usize,
+    },
+
+    /// llama.cpp read less than the whole snapshot, so it is probably not a snapshot of this kind
+    /// of context. llama.cpp only reports how much it read after applying it, so the state read
+    /// from the first `read` bytes has already replaced the previous state.
+    #[error("only {read} bytes of the {len} byte snapshot were read, the state they hold was applied anyway")]
+    TrailingData {
+        /// The number of bytes read
+        read: usize,
+        /// The length of the snapshot
+        len: usize,
+    },
 }
 
 impl LlamaContext<'_> {
@@ -134,6 +176,202 @@ impl LlamaContext<'_> {
         }
     }
 
+    /// Copy the state of the context (rng, logits, embeddings and kv cache) into memory.
+    ///
+    /// The snapshot can be kept anywhere and later passed to [`LlamaContext::restore_state`] on
+    /// this or another context created from the same model with the same parameters.
+    ///
+    /// # Errors
+    ///
+    /// Fails if llama.cpp fails to copy the state.
+    pub fn state_snapshot(&self) -> Result<Vec<u8>, StateSnapshotError> {
+        let size = unsafe { llama_cpp_sys_2::llama_state_get_size(self.context.as_ptr()) };
+        let mut state = Vec::with_capacity(size);
+        let written = unsafe {
+            llama_cpp_sys_2::llama_state_get_data(self.context.as_ptr(), state.as_mut_ptr(), size)
+        };
+        if written == 0 || written > size {
+            return Err(StateSnapshotError::FailedToCopy { size });
+        }
+        // SAFETY: llama.cpp wrote `written` bytes, which fits in the capacity
+        unsafe {
+            state.set_len(written);
+        }
+        Ok(state)
+    }
+
+    /// Restore the state of the context from a snapshot taken by
+    /// [`LlamaContext::state_snapshot`], replacing the kv cache, logits, embeddings and rng.
+    ///
+    /// # Errors
+    ///
+    /// Fails if llama.cpp cannot read the snapshot or does not read all of it, e.g. because it is
+    /// truncated or was taken from a context with a different model or parameters. In both cases
+    /// the state of the context may already have been changed, see
+    /// [`RestoreStateError::TrailingData`].
+    pub fn restore_state(&mut self, state: &[u8]) -> Result<(), RestoreStateError> {
+        let len = state.len();
+        let read = unsafe {
+            llama_cpp_sys_2::llama_state_set_data(self.context.as_ptr(), state.as_ptr(), len)
+        };
+        check_restored(read, len)
+    }
+
+    /// Copy the state of a single sequence (its part of the kv cache) into memory.
+    ///
+    /// The snapshot can be restored into any sequence of a context created from the same model
+    /// with [`LlamaContext::restore_seq_state`], e.g. to evict an idle conversation from a shared
+    /// context.
+    ///
+    /// # Errors
+    ///
+    /// Fails if llama.cpp fails to copy the state.
+    pub fn seq_state_snapshot(&self, seq_id: i32) -> Result<Vec<u8>, StateSnapshotError> {
+        let ctx = self.context.as_ptr();
+        let size = unsafe { llama_cpp_sys_2::llama_state_seq_get_size(ctx, seq_id) };
+        let mut state = Vec::with_capacity(size);
+        let written = unsafe {
+            llama_cpp_sys_2::llama_state_seq_get_data(ctx, state.as_mut_ptr(), size, seq_id)
+        };
+        if written == 0 || written > size {
+            return Err(StateSnapshotError::FailedToCopy { size });
+        }
+        // SAFETY: llama.cpp wrote `written` bytes, which fits in the capacity
+        unsafe {
+            state.set_len(written);
+        }
+        Ok(state)
+    }
+
+    /// Restore a snapshot taken by [`LlamaContext::seq_state_snapshot`] into `dest_seq_id`.
+    /// Anything already in `dest_seq_id` is removed first.
+    ///
+    /// # Errors
+    ///
+    /// Fails if llama.cpp cannot read the snapshot or does not read all of it, e.g. because there
+    /// are not enough free cells in the kv cache or it was taken with a different model. On
+    /// [`RestoreStateError::TrailingData`] the part that was read is already in `dest_seq_id`.
+    pub fn restore_seq_state(
+        &mut self,
+        dest_seq_id: i32,
+        state: &[u8],
+    ) -> Result<(), RestoreStateError> {
+        let len = state.len();
+        let read = unsafe {
+            llama_cpp_sys_2::llama_state_seq_set_data(
+                self.context.as_ptr(),
+                state.as_ptr(),
+                len,
+                dest_seq_id,
+            )
+        };
+        check_restored(read, len)
+    }
+
+    /// Save the state of a single sequence to a file.
+    ///
+    /// # Parameters
+    ///
+    /// * `path` - The file to save to.
+    /// * `seq_id` - The sequence to save.
+    /// * `tokens` - The tokens of the sequence, stored in the file and returned by [`LlamaContext::load_seq_state_file`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the path is not a valid utf8, is not a valid c string, or llama.cpp fails to save the file.
+    pub fn save_seq_state_file(
+        &self,
+        path: impl AsRef<Path>,
+        seq_id: i32,
+        tokens: &[LlamaToken],
+    ) -> Result<(), SaveSessionError> {
+        let path = path.as_ref();
+        let path = path
+            .to_str()
+            .ok_or_else(|| SaveSessionError::PathToStrError(path.to_path_buf()))?;
+
+        let cstr = CString::new(path)?;
+
+        let written = unsafe {
+            llama_cpp_sys_2::llama_state_seq_save_file(
+                self.context.as_ptr(),
+                cstr.as_ptr(),
+                seq_id,
+                tokens.as_ptr().cast::<llama_cpp_sys_2::llama_token>(),
+                tokens.len(),
+            )
+        };
+        if written == 0 {
+            Err(SaveSessionError::FailedToSave)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Load a file saved by [`LlamaContext::save_seq_state_file`] into `dest_seq_id`, returning the
+    /// tokens stored with it. Anything already in `dest_seq_id` is removed first.
+    ///
+    /// # Parameters
+    ///
+    /// * `path` - The file to load from.
+    /// * `dest_seq_id` - The sequence to load into. This does not need to be the sequence that was saved.
+    /// * `max_tokens` - The maximum number of tokens to load. The token count in the file header is checked against this before anything is loaded.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the path is not a valid utf8, is not a valid c string, the file is not a sequence
+    /// state file, stores more than `max_tokens` tokens, or llama.cpp fails to load it.
+    pub fn load_seq_state_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        dest_seq_id: i32,
+        max_tokens: usize,
+    ) -> Result<Vec<LlamaToken>, LoadSessionError> {
+        let path = path.as_ref();
+        let n_tokens = read_seq_state_header(path)?;
+        if n_tokens > max_tokens {
+            return Err(LoadSessionError::InsufficientMaxLength {
+                n_out: n_tokens,
+                max_tokens,
+            });
+        }
+
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| LoadSessionError::PathToStrError(path.to_path_buf()))?;
+        let cstr = CString::new(path_str)?;
+        let mut tokens: Vec<LlamaToken> = Vec::with_capacity(n_tokens);
+        let mut n_out = 0;
+
+        // SAFETY: cast is valid as LlamaToken is repr(transparent)
+        let tokens_out = tokens.as_mut_ptr().cast::<llama_cpp_sys_2::llama_token>();
+
+        let read = unsafe {
+            llama_cpp_sys_2::llama_state_seq_load_file(
+                self.context.as_ptr(),
+                cstr.as_ptr(),
+                dest_seq_id,
+                tokens_out,
+                n_tokens,
+                std::ptr::addr_of_mut!(n_out),
+            )
+        };
+        if read == 0 {
+            return Err(LoadSessionError::FailedToLoad);
+        }
+        if n_out > n_tokens {
+            return Err(LoadSessionError::InsufficientMaxLength {
+                n_out,
+                max_tokens: n_tokens,
+            });
+        }
+        // SAFETY: we checked that n_out <= the capacity and llama.cpp promises that n_out tokens will be written
+        unsafe {
+            tokens.set_len(n_out);
+        }
+        Ok(tokens)
+    }
+
     /// Returns the maximum size in bytes of the state (rng, logits, embedding
     /// and `kv_cache`) - will often be smaller after compacting tokens
     #[must_use]
@@ -162,3 +400,82 @@ impl LlamaContext<'_> {
         unsafe { llama_cpp_sys_2::llama_set_state_data(self.context.as_ptr(), src.as_ptr()) }
     }
 }
+
+/// Turn the number of bytes llama.cpp read from a `len` byte snapshot into a result.
+fn check_restored(read: usize, len: usize) -> Result<(), RestoreStateError> {
+    if read == 0 {
+        Err(RestoreStateError::FailedToRestore { len })
+    } else if read != len {
+        Err(RestoreStateError::TrailingData { read, len })
+    } else {
+        Ok(())
+    }
+}
+
+/// Read the header of a sequence state file, returning the number of tokens stored in it.
+fn read_seq_state_header(path: &Path) -> Result<usize, LoadSessionError> {
+    let mut header = [0; 12];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map_err(|_| LoadSessionError::FailedToLoad)?;
+    let field = |i: usize| {
+        u32::from_ne_bytes(
+            header[i * 4..(i + 1) * 4]
+                .try_into()
+                .expect("slice has length 4"),
+        )
+    };
+    // the second field is the version, which llama.cpp checks itself.
+    if field(0) != STATE_SEQ_MAGIC {
+        return Err(LoadSessionError::NotASeqStateFile(path.to_path_buf()));
+    }
+    Ok(usize::try_from(field(2)).expect("token count does not fit into a usize"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_state_header() {
+        let path = std::env::temp_dir().join(format!("seq-state-{}.bin", std::process::id()));
+        let header = |magic: u32| {
+            [magic, 2, 5]
+                .iter()
+                .flat_map(|field| field.to_ne_bytes())
+                .collect::<Vec<_>>()
+        };
+
+        std::fs::write(&path, header(STATE_SEQ_MAGIC)).unwrap();
+        assert_eq!(read_seq_state_header(&path), Ok(5));
+
+        std::fs::write(&path, header(0x6767_736e)).unwrap();
+        assert_eq!(
+            read_seq_state_header(&path),
+            Err(LoadSessionError::NotASeqStateFile(path.clone()))
+        );
+
+        std::fs::write(&path, &header(STATE_SEQ_MAGIC)[..8]).unwrap();
+        assert_eq!(
+            read_seq_state_header(&path),
+            Err(LoadSessionError::FailedToLoad)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restored_length_is_checked() {
+        assert_eq!(check_restored(16, 16), Ok(()));
+        assert_eq!(
+            check_restored(0, 16),
+            Err(RestoreStateError::FailedToRestore { len: 16 })
+        );
+        let trailing = check_restored(12, 16).unwrap_err();
+        assert_eq!(
+            trailing,
+            RestoreStateError::TrailingData { read: 12, len: 16 }
+        );
+        assert!(trailing.to_string().contains("applied anyway"));
+    }
+}
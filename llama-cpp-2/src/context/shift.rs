@@ -0,0 +1,164 @@
+//! Free up space in a full context by discarding old tokens, as `llama-cli` does.
+//!
+//! ```
+//! # use llama_cpp_2::context::shift::ContextShift;
+//! // keep the first 4 tokens (e.g. a system prompt) and discard half of the rest
+//! let shift = ContextShift::default().with_n_keep(4);
+//! assert_eq!(shift.dropped_range(20), Some(4..12));
+//! ```
+
+use std::ops::Range;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+
+/// An error that can occur while shifting a context.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ContextShiftError {
+    /// All tokens in the sequence are pinned, so none can be discarded.
+    #[error("all {n_past} tokens are pinned (n_keep = {n_keep})")]
+    NothingToDiscard {
+        /// the number of tokens in the sequence
+        n_past: usize,
+        /// the number of pinned tokens
+        n_keep: usize,
+    },
+    /// llama.cpp could not remove part of the sequence, e.g. because the model is recurrent.
+    #[error("failed to remove tokens {0:?} from the kv cache")]
+    RemoveFailed(Range<usize>),
+    /// A sequence id or position did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// A policy for making room in a sequence when the context is full.
+///
+/// The first `n_keep` tokens are pinned. Of the rest, the oldest `discard_fraction` are removed
+/// from the kv cache and the remaining tokens are shifted back to fill the gap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ContextShift {
+    n_keep: usize,
+    discard_fraction: f32,
+}
+
+impl Default for ContextShift {
+    /// Pin nothing and discard half of the tokens, like `llama-cli`.
+    fn default() -> Self {
+        Self {
+            n_keep: 0,
+            discard_fraction: 0.5,
+        }
+    }
+}
+
+impl ContextShift {
+    /// Never discard the first `n_keep` tokens (e.g. the BOS token and a system prompt).
+    #[must_use]
+    pub fn with_n_keep(mut self, n_keep: usize) -> Self {
+        self.n_keep = n_keep;
+        self
+    }
+
+    /// Discard this fraction of the tokens that are not pinned. At least one token is always
+    /// discarded.
+    ///
+    /// # Panics
+    ///
+    /// - `discard_fraction` is not in `(0, 1]`
+    #[must_use]
+    pub fn with_discard_fraction(mut self, discard_fraction: f32) -> Self {
+        assert!(
+            discard_fraction > 0.0 && discard_fraction <= 1.0,
+            "discard_fraction must be in (0, 1], was {discard_fraction}"
+        );
+        self.discard_fraction = discard_fraction;
+        self
+    }
+
+    /// The number of pinned tokens.
+    #[must_use]
+    pub fn n_keep(&self) -> usize {
+        self.n_keep
+    }
+
+    /// The fraction of tokens that are not pinned to discard.
+    #[must_use]
+    pub fn discard_fraction(&self) -> f32 {
+        self.discard_fraction
+    }
+
+    /// The positions that would be dropped from a sequence holding `n_past` tokens, or `None` if
+    /// they are all pinned.
+    #[must_use]
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn dropped_range(&self, n_past: usize) -> Option<Range<usize>> {
+        let n_left = n_past.checked_sub(self.n_keep).filter(|&n| n > 0)?;
+        let n_discard =
+            ((n_left as f64 * f64::from(self.discard_fraction)) as usize).clamp(1, n_left);
+        Some(self.n_keep..self.n_keep + n_discard)
+    }
+
+    /// Make room in `seq_id`, which holds tokens at positions `0..n_past`, by removing the
+    /// dropped range and shifting later tokens back.
+    ///
+    /// Returns the range of positions that was dropped. Removing the same range from a history
+    /// of the tokens in the sequence keeps it in sync; the sequence then holds
+    /// `n_past - range.len()` tokens.
+    ///
+    /// # Errors
+    ///
+    /// - [`ContextShiftError::NothingToDiscard`] if all tokens are pinned.
+    /// - [`ContextShiftError::RemoveFailed`] if llama.cpp could not remove the tokens.
+    /// - [`ContextShiftError::KvCacheConversionError`] if a position or `seq_id` does not fit.
+    pub fn apply(
+        &self,
+        ctx: &mut LlamaContext,
+        seq_id: i32,
+        n_past: usize,
+    ) -> Result<Range<usize>, ContextShiftError> {
+        let dropped = self
+            .dropped_range(n_past)
+            .ok_or(ContextShiftError::NothingToDiscard {
+                n_past,
+                n_keep: self.n_keep,
+            })?;
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let start = u32::try_from(dropped.start).map_err(KvCacheConversionError::P0TooLarge)?;
+        let end = u32::try_from(dropped.end).map_err(KvCacheConversionError::P1TooLarge)?;
+        let past = u32::try_from(n_past).map_err(KvCacheConversionError::P1TooLarge)?;
+        let n_discard = i32::try_from(dropped.len()).map_err(KvCacheConversionError::P1TooLarge)?;
+
+        if !ctx.clear_kv_cache_seq(Some(seq), Some(start), Some(end))? {
+            return Err(ContextShiftError::RemoveFailed(dropped));
+        }
+        ctx.kv_cache_seq_add(seq_id, Some(end), Some(past), -n_discard)?;
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContextShift;
+
+    #[test]
+    fn dropped_range() {
+        let shift = ContextShift::default();
+        assert_eq!(shift.dropped_range(10), Some(0..5));
+        assert_eq!(shift.dropped_range(1), Some(0..1));
+        assert_eq!(shift.dropped_range(0), None);
+
+        let shift = shift.with_n_keep(3).with_discard_fraction(0.25);
+        assert_eq!(shift.dropped_range(11), Some(3..5));
+        assert_eq!(shift.dropped_range(4), Some(3..4));
+        assert_eq!(shift.dropped_range(3), None);
+
+        let shift = shift.with_discard_fraction(1.0);
+        assert_eq!(shift.dropped_range(11), Some(3..11));
+    }
+}
@@ -0,0 +1,943 @@
+//! A pure-Rust reader for GGUF files.
+//!
+//! [`GgufFile`] parses the header, the metadata and the tensor infos of a `.gguf` file without
+//! calling into llama.cpp or loading any weights, so it is cheap enough to inspect models before
+//! deciding how (or where) to load them. Versions 2 and 3 of the format are supported.
+//!
+//! See [`writer`] for editing GGUF files and [`tiny`] for generating tiny models for tests.
+//!
+//! ```no_run
+//! # use llama_cpp_2::gguf::GgufFile;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let gguf = GgufFile::open("path/to/model.gguf")?;
+//! println!("architecture: {:?}", gguf.architecture());
+//! println!("context length: {:?}", gguf.context_length());
+//! println!("tokenizer: {:?}", gguf.tokenizer_model());
+//! for tensor in gguf.tensors() {
+//!     println!("{} {:?} {}", tensor.name, tensor.dims, tensor.ggml_type);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::c_char;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::ops::Deref;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::model::params::kv_overrides::ParamOverrideValue;
+
+pub mod tiny;
+pub mod writer;
+
+/// The magic bytes at the start of every GGUF file.
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+
+/// The alignment of the tensor data if `general.alignment` is not set.
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// How deeply arrays may be nested. llama.cpp itself only uses flat arrays.
+const MAX_NESTING: usize = 8;
+
+/// An error that can occur while reading a GGUF file.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufError {
+    /// Opening or mapping the file failed.
+    #[error("failed to read the file: {0}")]
+    Io(#[from] std::io::Error),
+    /// The file does not start with [`GGUF_MAGIC`].
+    #[error("not a gguf file, the magic is {0:?}")]
+    BadMagic([u8; 4]),
+    /// The file has a version other than 2 or 3.
+    #[error("unsupported gguf version {0}")]
+    UnsupportedVersion(u32),
+    /// The file is big endian, which the operation does not support.
+    #[error("big endian gguf files are not supported here")]
+    UnsupportedBigEndian,
+    /// The file ended in the middle of the header.
+    #[error("unexpected end of file at offset {offset}")]
+    UnexpectedEof {
+        /// the offset of the value that did not fit
+        offset: usize,
+    },
+    /// A string was not valid utf8.
+    #[error("the string at offset {offset} is not valid utf8")]
+    InvalidUtf8 {
+        /// the offset of the string
+        offset: usize,
+    },
+    /// A value had an unknown type.
+    #[error("unknown value type {value_type} at offset {offset}")]
+    UnknownValueType {
+        /// the type id
+        value_type: u32,
+        /// the offset of the type id
+        offset: usize,
+    },
+    /// Arrays were nested more deeply than any real file does.
+    #[error("arrays are nested too deeply at offset {offset}")]
+    TooDeeplyNested {
+        /// the offset of the innermost array
+        offset: usize,
+    },
+    /// `general.alignment` is not a power of two.
+    #[error("invalid alignment {0}")]
+    InvalidAlignment(u64),
+    /// The tensor has a type whose size is unknown.
+    #[error("the size of tensors of type {0} is unknown")]
+    UnknownTensorType(GgmlType),
+    /// The data of a tensor does not lie within the file.
+    #[error("the data of tensor {0} is out of bounds")]
+    TensorOutOfBounds(String),
+}
+
+/// The type of a metadata value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufValueType {
+    /// `u8`
+    U8,
+    /// `i8`
+    I8,
+    /// `u16`
+    U16,
+    /// `i16`
+    I16,
+    /// `u32`
+    U32,
+    /// `i32`
+    I32,
+    /// `f32`
+    F32,
+    /// `bool`
+    Bool,
+    /// a utf8 string
+    Str,
+    /// an array of values of a single type
+    Array,
+    /// `u64`
+    U64,
+    /// `i64`
+    I64,
+    /// `f64`
+    F64,
+}
+
+impl GgufValueType {
+    const ALL: [Self; 13] = [
+        Self::U8,
+        Self::I8,
+        Self::U16,
+        Self::I16,
+        Self::U32,
+        Self::I32,
+        Self::F32,
+        Self::Bool,
+        Self::Str,
+        Self::Array,
+        Self::U64,
+        Self::I64,
+        Self::F64,
+    ];
+
+    /// The id of the type in the file.
+    #[must_use]
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// The type with the id `id`, if there is one.
+    #[must_use]
+    pub fn from_id(id: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|value_type| value_type.id() == id)
+    }
+
+    /// The smallest number of bytes a value of this type takes in the file.
+    fn min_encoded_size(self) -> usize {
+        match self {
+            Self::U8 | Self::I8 | Self::Bool => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 | Self::F32 => 4,
+            // the length of a string
+            Self::U64 | Self::I64 | Self::F64 | Self::Str => 8,
+            // the element type and the length of an array
+            Self::Array => 12,
+        }
+    }
+}
+
+/// A metadata value.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufValue {
+    /// `u8`
+    U8(u8),
+    /// `i8`
+    I8(i8),
+    /// `u16`
+    U16(u16),
+    /// `i16`
+    I16(i16),
+    /// `u32`
+    U32(u32),
+    /// `i32`
+    I32(i32),
+    /// `f32`
+    F32(f32),
+    /// `bool`
+    Bool(bool),
+    /// a utf8 string
+    Str(String),
+    /// an array of values, all of the given type
+    Array(GgufValueType, Vec<GgufValue>),
+    /// `u64`
+    U64(u64),
+    /// `i64`
+    I64(i64),
+    /// `f64`
+    F64(f64),
+}
+
+impl GgufValue {
+    /// The type of the value.
+    #[must_use]
+    pub fn value_type(&self) -> GgufValueType {
+        match self {
+            Self::U8(_) => GgufValueType::U8,
+            Self::I8(_) => GgufValueType::I8,
+            Self::U16(_) => GgufValueType::U16,
+            Self::I16(_) => GgufValueType::I16,
+            Self::U32(_) => GgufValueType::U32,
+            Self::I32(_) => GgufValueType::I32,
+            Self::F32(_) => GgufValueType::F32,
+            Self::Bool(_) => GgufValueType::Bool,
+            Self::Str(_) => GgufValueType::Str,
+            Self::Array(..) => GgufValueType::Array,
+            Self::U64(_) => GgufValueType::U64,
+            Self::I64(_) => GgufValueType::I64,
+            Self::F64(_) => GgufValueType::F64,
+        }
+    }
+
+    /// The value as a string, if it is one.
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value as a bool, if it is one.
+    #[must_use]
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as an `i64`, if it is an integer that fits.
+    #[must_use]
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Self::U8(value) => Some(i64::from(value)),
+            Self::I8(value) => Some(i64::from(value)),
+            Self::U16(value) => Some(i64::from(value)),
+            Self::I16(value) => Some(i64::from(value)),
+            Self::U32(value) => Some(i64::from(value)),
+            Self::I32(value) => Some(i64::from(value)),
+            Self::U64(value) => i64::try_from(value).ok(),
+            Self::I64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value as a `u64`, if it is a non-negative integer.
+    #[must_use]
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U64(value) => Some(value),
+            _ => self.as_i64().and_then(|value| u64::try_from(value).ok()),
+        }
+    }
+
+    /// The value as an `f64`, if it is a float.
+    #[must_use]
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(value) => Some(f64::from(value)),
+            Self::F64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The elements of the value, if it is an array.
+    #[must_use]
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array(_, values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// The value as a kv override of the same key. Arrays, integers that do not fit into an
+    /// `i64` and strings of 128 bytes or more have no override.
+    ///
+    /// ```
+    /// # use llama_cpp_2::gguf::GgufValue;
+    /// # use llama_cpp_2::model::params::kv_overrides::ParamOverrideValue;
+    /// assert_eq!(GgufValue::U32(4096).to_override(), Some(ParamOverrideValue::Int(4096)));
+    /// assert_eq!(GgufValue::U64(u64::MAX).to_override(), None);
+    /// ```
+    #[must_use]
+    pub fn to_override(&self) -> Option<ParamOverrideValue> {
+        match self {
+            Self::Bool(value) => Some(ParamOverrideValue::Bool(*value)),
+            Self::F32(_) | Self::F64(_) => self.as_f64().map(ParamOverrideValue::Float),
+            Self::Str(value) => {
+                let mut c_string = [0; 128];
+                if value.len() >= c_string.len() || value.contains('\0') {
+                    return None;
+                }
+                for (c, &byte) in c_string.iter_mut().zip(value.as_bytes()) {
+                    *c = c_char::from_ne_bytes([byte]);
+                }
+                Some(ParamOverrideValue::Str(c_string))
+            }
+            Self::Array(..) => None,
+            _ => self.as_i64().map(ParamOverrideValue::Int),
+        }
+    }
+}
+
+/// The type of the elements of a tensor, a `ggml_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GgmlType(pub u32);
+
+/// name, elements per block and bytes per block of every known [`GgmlType`], by id
+const GGML_TYPES: [(&str, u64, u64); 36] = [
+    ("F32", 1, 4),
+    ("F16", 1, 2),
+    ("Q4_0", 32, 18),
+    ("Q4_1", 32, 20),
+    ("Q4_2", 0, 0),
+    ("Q4_3", 0, 0),
+    ("Q5_0", 32, 22),
+    ("Q5_1", 32, 24),
+    ("Q8_0", 32, 34),
+    ("Q8_1", 32, 36),
+    ("Q2_K", 256, 84),
+    ("Q3_K", 256, 110),
+    ("Q4_K", 256, 144),
+    ("Q5_K", 256, 176),
+    ("Q6_K", 256, 210),
+    ("Q8_K", 256, 292),
+    ("IQ2_XXS", 256, 66),
+    ("IQ2_XS", 256, 74),
+    ("IQ3_XXS", 256, 98),
+    ("IQ1_S", 256, 50),
+    ("IQ4_NL", 32, 18),
+    ("IQ3_S", 256, 110),
+    ("IQ2_S", 256, 82),
+    ("IQ4_XS", 256, 136),
+    ("I8", 1, 1),
+    ("I16", 1, 2),
+    ("I32", 1, 4),
+    ("I64", 1, 8),
+    ("F64", 1, 8),
+    ("IQ1_M", 256, 56),
+    ("BF16", 1, 2),
+    ("Q4_0_4_4", 32, 18),
+    ("Q4_0_4_8", 32, 18),
+    ("Q4_0_8_8", 32, 18),
+    ("TQ1_0", 256, 54),
+    ("TQ2_0", 256, 66),
+];
+
+impl GgmlType {
+    /// 32 bit floats
+    pub const F32: Self = Self(0);
+    /// 16 bit floats
+    pub const F16: Self = Self(1);
+    /// 8 bit quantization in blocks of 32
+    pub const Q8_0: Self = Self(8);
+    /// bfloat16
+    pub const BF16: Self = Self(30);
+
+    fn info(self) -> Option<(&'static str, u64, u64)> {
+        let info = GGML_TYPES.get(usize::try_from(self.0).ok()?)?;
+        (info.1 != 0).then_some(*info)
+    }
+
+    /// The name of the type as used by llama.cpp, e.g. `Q4_K`.
+    #[must_use]
+    pub fn name(self) -> Option<&'static str> {
+        self.info().map(|(name, _, _)| name)
+    }
+
+    /// The number of elements that are quantized together.
+    #[must_use]
+    pub fn block_size(self) -> Option<u64> {
+        self.info().map(|(_, block_size, _)| block_size)
+    }
+
+    /// The number of bytes a block takes.
+    #[must_use]
+    pub fn type_size(self) -> Option<u64> {
+        self.info().map(|(_, _, type_size)| type_size)
+    }
+}
+
+impl Display for GgmlType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown type {}", self.0),
+        }
+    }
+}
+
+/// The name, shape, type and location of a tensor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufTensorInfo {
+    /// the name of the tensor, e.g. `blk.0.attn_q.weight`
+    pub name: String,
+    /// the size of each dimension, innermost first
+    pub dims: Vec<u64>,
+    /// the type of the elements
+    pub ggml_type: GgmlType,
+    /// the offset of the data from the start of the data section
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// The number of elements of the tensor, or `None` if it does not fit into a [`u64`].
+    #[must_use]
+    pub fn n_elements(&self) -> Option<u64> {
+        self.dims.iter().copied().try_fold(1u64, u64::checked_mul)
+    }
+
+    /// The size of the data of the tensor in bytes, or `None` if the size of its type is unknown,
+    /// the rows are not a whole number of blocks or the size does not fit into a [`u64`].
+    #[must_use]
+    pub fn n_bytes(&self) -> Option<u64> {
+        let block_size = self.ggml_type.block_size()?;
+        let row = *self.dims.first().unwrap_or(&1);
+        if !row.is_multiple_of(block_size) {
+            return None;
+        }
+        (self.n_elements()? / block_size).checked_mul(self.ggml_type.type_size()?)
+    }
+}
+
+#[derive(Debug)]
+enum Data {
+    Mmap(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Data::Mmap(mmap) => mmap,
+            Data::Owned(bytes) => bytes,
+        }
+    }
+}
+
+/// A parsed GGUF file.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufFile {
+    data: Data,
+    version: u32,
+    metadata: Vec<(String, GgufValue)>,
+    tensors: Vec<GgufTensorInfo>,
+    alignment: u64,
+    data_offset: u64,
+    big_endian: bool,
+}
+
+impl GgufFile {
+    /// Memory-map and parse the file at `path`. Only the header is read; tensor data is only
+    /// paged in when accessed through [`GgufFile::tensor_data`].
+    ///
+    /// The file must not be modified while it is open.
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufError::Io`] if the file could not be opened or mapped.
+    /// - any other [`GgufError`] if the file is not a valid GGUF file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GgufError> {
+        let file = File::open(path)?;
+        // SAFETY: the file must not be modified while it is mapped, as documented above.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::parse(Data::Mmap(mmap))
+    }
+
+    /// Parse a GGUF file held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Any [`GgufError`] other than [`GgufError::Io`] if `bytes` are not a valid GGUF file.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, GgufError> {
+        Self::parse(Data::Owned(bytes))
+    }
+
+    fn parse(data: Data) -> Result<Self, GgufError> {
+        let mut reader = Reader {
+            bytes: &data,
+            pos: 0,
+            big_endian: false,
+        };
+        let magic = reader.array()?;
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::BadMagic(magic));
+        }
+        let version = reader.array()?;
+        // version 3 added big endian files, which are recognised by their swapped version
+        let version = match (u32::from_le_bytes(version), u32::from_be_bytes(version)) {
+            (le @ (2 | 3), _) => le,
+            (_, 3) => {
+                reader.big_endian = true;
+                3
+            }
+            (le, _) => return Err(GgufError::UnsupportedVersion(le)),
+        };
+        let n_tensors = reader.u64()?;
+        let n_kv = reader.u64()?;
+
+        // a key is at least its length, then the value type and the smallest value
+        let mut metadata = Vec::with_capacity(reader.capacity(n_kv, 8 + 4 + 1));
+        for _ in 0..n_kv {
+            let key = reader.string()?;
+            let value_type = reader.value_type()?;
+            let value = reader.value(value_type, 0)?;
+            metadata.push((key, value));
+        }
+
+        // a tensor is at least the length of its name, the number of dims, the type and the offset
+        let mut tensors = Vec::with_capacity(reader.capacity(n_tensors, 8 + 4 + 4 + 8));
+        for _ in 0..n_tensors {
+            let name = reader.string()?;
+            let n_dims = reader.u32()?;
+            let dims = (0..n_dims)
+                .map(|_| reader.u64())
+                .collect::<Result<_, _>>()?;
+            let ggml_type = GgmlType(reader.u32()?);
+            let offset = reader.u64()?;
+            tensors.push(GgufTensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.iter().find(|(key, _)| key == "general.alignment") {
+            Some((_, value)) => value
+                .as_u64()
+                .filter(|alignment| alignment.is_power_of_two())
+                .ok_or(GgufError::InvalidAlignment(value.as_u64().unwrap_or(0)))?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_offset = (reader.pos as u64).next_multiple_of(alignment);
+        let big_endian = reader.big_endian;
+
+        Ok(Self {
+            data,
+            version,
+            metadata,
+            tensors,
+            alignment,
+            data_offset,
+            big_endian,
+        })
+    }
+
+    /// The version of the file format.
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Whether the file is big endian. Its tensor data is then big endian too.
+    #[must_use]
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// All metadata key-value pairs, in the order they appear in the file.
+    #[must_use]
+    pub fn metadata(&self) -> &[(String, GgufValue)] {
+        &self.metadata
+    }
+
+    /// The metadata value of `key`.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// The infos of all tensors, in the order they appear in the file.
+    #[must_use]
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    /// The info of the tensor called `name`.
+    #[must_use]
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|tensor| tensor.name == name)
+    }
+
+    /// The alignment of the tensor data.
+    #[must_use]
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// The offset of the data section from the start of the file.
+    #[must_use]
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    /// The raw data of `tensor`.
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufError::UnknownTensorType`] if the size of the tensor's type is unknown.
+    /// - [`GgufError::TensorOutOfBounds`] if the data does not lie within the file.
+    pub fn tensor_data(&self, tensor: &GgufTensorInfo) -> Result<&[u8], GgufError> {
+        let n_bytes = tensor
+            .n_bytes()
+            .ok_or(GgufError::UnknownTensorType(tensor.ggml_type))?;
+        let out_of_bounds = || GgufError::TensorOutOfBounds(tensor.name.clone());
+        let start = self
+            .data_offset
+            .checked_add(tensor.offset)
+            .ok_or_else(out_of_bounds)?;
+        let end = start.checked_add(n_bytes).ok_or_else(out_of_bounds)?;
+        let range = usize::try_from(start).map_err(|_| out_of_bounds())?
+            ..usize::try_from(end).map_err(|_| out_of_bounds())?;
+        self.data.get(range).ok_or_else(out_of_bounds)
+    }
+
+    /// `general.architecture`, e.g. `llama`.
+    #[must_use]
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    /// `general.name`, the name of the model.
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.get("general.name")?.as_str()
+    }
+
+    /// `<architecture>.context_length`, the context the model was trained with.
+    #[must_use]
+    pub fn context_length(&self) -> Option<u64> {
+        let key = format!("{}.context_length", self.architecture()?);
+        self.get(&key)?.as_u64()
+    }
+
+    /// `tokenizer.ggml.model`, the type of tokenizer, e.g. `llama` or `gpt2`.
+    #[must_use]
+    pub fn tokenizer_model(&self) -> Option<&str> {
+        self.get("tokenizer.ggml.model")?.as_str()
+    }
+
+    /// `tokenizer.chat_template`, the jinja chat template.
+    #[must_use]
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template")?.as_str()
+    }
+}
+
+/// Reads the little (or big) endian values of the header.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+macro_rules! read_numbers {
+    ($($ty:ident),*) => {
+        $(
+            fn $ty(&mut self) -> Result<$ty, GgufError> {
+                let bytes = self.array()?;
+                Ok(if self.big_endian {
+                    $ty::from_be_bytes(bytes)
+                } else {
+                    $ty::from_le_bytes(bytes)
+                })
+            }
+        )*
+    };
+}
+
+impl<'a> Reader<'a> {
+    read_numbers!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], GgufError> {
+        let eof = GgufError::UnexpectedEof { offset: self.pos };
+        let end = self.pos.checked_add(n).ok_or(eof)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(GgufError::UnexpectedEof { offset: self.pos })?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    /// A capacity for `n` items of at least `min_item_size` bytes that is not more than the rest
+    /// of the file can hold, so a corrupt count does not allocate unbounded memory.
+    fn capacity(&self, n: u64, min_item_size: usize) -> usize {
+        let max_items = (self.bytes.len() - self.pos) / min_item_size;
+        usize::try_from(n).map_or(max_items, |n| n.min(max_items))
+    }
+
+    fn string(&mut self) -> Result<String, GgufError> {
+        let offset = self.pos;
+        let len = self.u64()?;
+        let len = usize::try_from(len).map_err(|_| GgufError::UnexpectedEof { offset })?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| GgufError::InvalidUtf8 { offset })
+    }
+
+    fn value_type(&mut self) -> Result<GgufValueType, GgufError> {
+        let offset = self.pos;
+        let value_type = self.u32()?;
+        GgufValueType::from_id(value_type).ok_or(GgufError::UnknownValueType { value_type, offset })
+    }
+
+    fn value(&mut self, value_type: GgufValueType, depth: usize) -> Result<GgufValue, GgufError> {
+        Ok(match value_type {
+            GgufValueType::U8 => GgufValue::U8(self.u8()?),
+            GgufValueType::I8 => GgufValue::I8(self.i8()?),
+            GgufValueType::U16 => GgufValue::U16(self.u16()?),
+            GgufValueType::I16 => GgufValue::I16(self.i16()?),
+            GgufValueType::U32 => GgufValue::U32(self.u32()?),
+            GgufValueType::I32 => GgufValue::I32(self.i32()?),
+            GgufValueType::F32 => GgufValue::F32(self.f32()?),
+            GgufValueType::Bool => GgufValue::Bool(self.u8()? != 0),
+            GgufValueType::Str => GgufValue::Str(self.string()?),
+            GgufValueType::U64 => GgufValue::U64(self.u64()?),
+            GgufValueType::I64 => GgufValue::I64(self.i64()?),
+            GgufValueType::F64 => GgufValue::F64(self.f64()?),
+            GgufValueType::Array => {
+                if depth == MAX_NESTING {
+                    return Err(GgufError::TooDeeplyNested { offset: self.pos });
+                }
+                let element_type = self.value_type()?;
+                let len = self.u64()?;
+                let mut values =
+                    Vec::with_capacity(self.capacity(len, element_type.min_encoded_size()));
+                for _ in 0..len {
+                    values.push(self.value(element_type, depth + 1)?);
+                }
+                GgufValue::Array(element_type, values)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a little endian GGUF file by hand.
+    #[derive(Default)]
+    struct Builder {
+        kv: Vec<u8>,
+        n_kv: u64,
+        tensors: Vec<u8>,
+        n_tensors: u64,
+    }
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    impl Builder {
+        fn kv(mut self, key: &str, value_type: GgufValueType, value: &[u8]) -> Self {
+            string(&mut self.kv, key);
+            self.kv.extend_from_slice(&value_type.id().to_le_bytes());
+            self.kv.extend_from_slice(value);
+            self.n_kv += 1;
+            self
+        }
+
+        fn kv_str(self, key: &str, value: &str) -> Self {
+            let mut bytes = Vec::new();
+            string(&mut bytes, value);
+            self.kv(key, GgufValueType::Str, &bytes)
+        }
+
+        fn tensor(mut self, name: &str, dims: &[u64], ggml_type: GgmlType, offset: u64) -> Self {
+            string(&mut self.tensors, name);
+            self.tensors
+                .extend_from_slice(&u32::try_from(dims.len()).unwrap().to_le_bytes());
+            for dim in dims {
+                self.tensors.extend_from_slice(&dim.to_le_bytes());
+            }
+            self.tensors.extend_from_slice(&ggml_type.0.to_le_bytes());
+            self.tensors.extend_from_slice(&offset.to_le_bytes());
+            self.n_tensors += 1;
+            self
+        }
+
+        fn build(self, version: u32, data: &[u8]) -> Vec<u8> {
+            let mut out = GGUF_MAGIC.to_vec();
+            out.extend_from_slice(&version.to_le_bytes());
+            out.extend_from_slice(&self.n_tensors.to_le_bytes());
+            out.extend_from_slice(&self.n_kv.to_le_bytes());
+            out.extend_from_slice(&self.kv);
+            out.extend_from_slice(&self.tensors);
+            out.resize(out.len().next_multiple_of(32), 0);
+            out.extend_from_slice(data);
+            out
+        }
+    }
+
+    fn model() -> Vec<u8> {
+        let mut array = GgufValueType::Str.id().to_le_bytes().to_vec();
+        array.extend_from_slice(&2u64.to_le_bytes());
+        string(&mut array, "<s>");
+        string(&mut array, "</s>");
+
+        let data = (0..8u8).collect::<Vec<_>>();
+        Builder::default()
+            .kv_str("general.architecture", "llama")
+            .kv(
+                "llama.context_length",
+                GgufValueType::U32,
+                &4096u32.to_le_bytes(),
+            )
+            .kv(
+                "llama.rope.freq_base",
+                GgufValueType::F32,
+                &1e4f32.to_le_bytes(),
+            )
+            .kv("general.quantized", GgufValueType::Bool, &[1])
+            .kv_str("tokenizer.ggml.model", "llama")
+            .kv("tokenizer.ggml.tokens", GgufValueType::Array, &array)
+            .tensor("a", &[2, 1], GgmlType::F16, 0)
+            .tensor("b", &[1], GgmlType::F32, 4)
+            .build(3, &data)
+    }
+
+    #[test]
+    fn reads_metadata_and_tensors() {
+        let gguf = GgufFile::from_bytes(model()).unwrap();
+        assert_eq!(gguf.version(), 3);
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.context_length(), Some(4096));
+        assert_eq!(gguf.tokenizer_model(), Some("llama"));
+        assert_eq!(gguf.chat_template(), None);
+        assert_eq!(gguf.get("general.quantized"), Some(&GgufValue::Bool(true)));
+        assert_eq!(
+            gguf.get("tokenizer.ggml.tokens"),
+            Some(&GgufValue::Array(
+                GgufValueType::Str,
+                vec![GgufValue::Str("<s>".into()), GgufValue::Str("</s>".into())]
+            ))
+        );
+        assert_eq!(
+            gguf.get("llama.rope.freq_base").unwrap().to_override(),
+            Some(ParamOverrideValue::Float(1e4))
+        );
+
+        let b = gguf.tensor("b").unwrap();
+        assert_eq!(b.ggml_type.to_string(), "F32");
+        assert_eq!(gguf.tensor_data(b).unwrap(), [4, 5, 6, 7]);
+        assert_eq!(gguf.tensor_data(&gguf.tensors()[0]).unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_memory_mapped_files() {
+        let path = std::env::temp_dir().join(format!("gguf-test-{}.gguf", std::process::id()));
+        std::fs::write(&path, model()).unwrap();
+        let gguf = GgufFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let gguf = gguf.unwrap();
+        assert_eq!(gguf.tensors().len(), 2);
+        assert_eq!(gguf.data_offset() % 32, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(matches!(
+            GgufFile::from_bytes(b"GGML\x03\0\0\0".to_vec()),
+            Err(GgufError::BadMagic(_))
+        ));
+        assert!(matches!(
+            GgufFile::from_bytes(Builder::default().build(1, &[])),
+            Err(GgufError::UnsupportedVersion(1))
+        ));
+        let mut truncated = model();
+        truncated.truncate(60);
+        assert!(matches!(
+            GgufFile::from_bytes(truncated),
+            Err(GgufError::UnexpectedEof { .. })
+        ));
+        let huge_count = Builder {
+            n_kv: u64::MAX,
+            ..Builder::default()
+        };
+        assert!(matches!(
+            GgufFile::from_bytes(huge_count.build(2, &[])),
+            Err(GgufError::UnexpectedEof { .. })
+        ));
+    }
+
+    #[test]
+    fn tensor_sizes_do_not_overflow() {
+        let info = |dims: &[u64]| GgufTensorInfo {
+            name: "t".to_owned(),
+            dims: dims.to_vec(),
+            ggml_type: GgmlType::F32,
+            offset: 0,
+        };
+        assert_eq!(info(&[2, 3]).n_elements(), Some(6));
+        assert_eq!(info(&[2, 3]).n_bytes(), Some(24));
+        assert_eq!(info(&[1 << 32, 1 << 32]).n_elements(), None);
+        assert_eq!(info(&[1 << 32, 1 << 32]).n_bytes(), None);
+        // the element count fits but the byte count does not
+        assert_eq!(info(&[1 << 31, 1 << 31]).n_elements(), Some(1 << 62));
+        assert_eq!(info(&[1 << 31, 1 << 31]).n_bytes(), None);
+    }
+
+    #[test]
+    fn capacity_is_bounded_by_the_bytes_left() {
+        let bytes = [0; 100];
+        let reader = Reader {
+            bytes: &bytes,
+            pos: 4,
+            big_endian: false,
+        };
+        assert_eq!(reader.capacity(u64::MAX, 12), 8);
+        assert_eq!(reader.capacity(3, 12), 3);
+        assert_eq!(reader.capacity(u64::MAX, 1), 96);
+    }
+}
@@ -0,0 +1,362 @@
+//! Tiny llama models with random weights, for exercising the loading and decoding paths in tests
+//! on machines without a real model or a GPU.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::gguf::tiny::TinyModel;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::llama_batch::LlamaBatch;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let path = std::env::temp_dir().join("tiny.gguf");
+//! TinyModel::default().write_to_file(&path)?;
+//!
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, &path, &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let tokens = model.str_to_token("hello world", AddBos::Always)?;
+//! let mut batch = LlamaBatch::new(tokens.len(), 1);
+//! batch.add_sequence(&tokens, 0, false)?;
+//! ctx.decode(&mut batch)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+
+use crate::gguf::writer::{GgufWriteError, GgufWriter};
+use crate::gguf::{GgmlType, GgufValue, GgufValueType};
+
+/// `llama_token_type` values used in `tokenizer.ggml.token_type`
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// The shape of a tiny llama model with random `F32` weights and a sentencepiece vocabulary of
+/// the control tokens, the 256 byte tokens, `▁` and the ascii letters and digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TinyModel {
+    n_layer: u32,
+    n_embd: u32,
+    n_head: u32,
+    n_head_kv: u32,
+    n_ff: u32,
+    n_ctx_train: u32,
+    seed: u64,
+}
+
+impl Default for TinyModel {
+    /// 2 layers, an embedding size of 64, 4 attention heads sharing 2 kv heads, a feed forward
+    /// size of 128 and a training context of 512 tokens.
+    fn default() -> Self {
+        Self {
+            n_layer: 2,
+            n_embd: 64,
+            n_head: 4,
+            n_head_kv: 2,
+            n_ff: 128,
+            n_ctx_train: 512,
+            seed: 0x5eed,
+        }
+    }
+}
+
+impl TinyModel {
+    /// Set the number of layers.
+    #[must_use]
+    pub fn with_n_layer(mut self, n_layer: u32) -> Self {
+        self.n_layer = n_layer;
+        self
+    }
+
+    /// Set the embedding size and the number of attention and kv heads.
+    ///
+    /// # Panics
+    ///
+    /// - `n_embd` is not a multiple of `n_head`, or `n_head` is not a multiple of `n_head_kv`
+    #[must_use]
+    pub fn with_attention(mut self, n_embd: u32, n_head: u32, n_head_kv: u32) -> Self {
+        assert!(
+            n_head > 0 && n_embd.is_multiple_of(n_head),
+            "n_embd must be a multiple of n_head"
+        );
+        assert!(
+            n_head_kv > 0 && n_head.is_multiple_of(n_head_kv),
+            "n_head must be a multiple of n_head_kv"
+        );
+        self.n_embd = n_embd;
+        self.n_head = n_head;
+        self.n_head_kv = n_head_kv;
+        self
+    }
+
+    /// Set the size of the feed forward layers.
+    #[must_use]
+    pub fn with_n_ff(mut self, n_ff: u32) -> Self {
+        self.n_ff = n_ff;
+        self
+    }
+
+    /// Set the context length the model claims to be trained with.
+    #[must_use]
+    pub fn with_n_ctx_train(mut self, n_ctx_train: u32) -> Self {
+        self.n_ctx_train = n_ctx_train;
+        self
+    }
+
+    /// Set the seed of the random weights. Models with the same shape and seed are identical.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The tokens of the vocabulary with their `llama_token_type`.
+    #[must_use]
+    pub fn vocab() -> Vec<(String, i32)> {
+        let mut vocab = vec![
+            ("<unk>".to_owned(), TOKEN_TYPE_UNKNOWN),
+            ("<s>".to_owned(), TOKEN_TYPE_CONTROL),
+            ("</s>".to_owned(), TOKEN_TYPE_CONTROL),
+        ];
+        vocab.extend((0..=255u8).map(|byte| (format!("<0x{byte:02X}>"), TOKEN_TYPE_BYTE)));
+        vocab.push(("▁".to_owned(), TOKEN_TYPE_NORMAL));
+        vocab.extend(
+            ('a'..='z')
+                .chain('A'..='Z')
+                .chain('0'..='9')
+                .map(|c| (c.to_string(), TOKEN_TYPE_NORMAL)),
+        );
+        vocab
+    }
+
+    /// The metadata and tensors of the model.
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)] // the tensors are valid by construction
+    pub fn build(&self) -> GgufWriter<'static> {
+        let vocab = Self::vocab();
+        let n_vocab = vocab.len() as u64;
+        let mut writer = GgufWriter::new();
+        let mut set = |key: &str, value| {
+            writer.set_kv(key, value);
+        };
+        set("general.architecture", GgufValue::Str("llama".to_owned()));
+        set("general.name", GgufValue::Str("tiny".to_owned()));
+        // all tensors are F32
+        set("general.file_type", GgufValue::U32(0));
+        set("llama.context_length", GgufValue::U32(self.n_ctx_train));
+        set("llama.embedding_length", GgufValue::U32(self.n_embd));
+        set("llama.block_count", GgufValue::U32(self.n_layer));
+        set("llama.feed_forward_length", GgufValue::U32(self.n_ff));
+        set("llama.attention.head_count", GgufValue::U32(self.n_head));
+        set(
+            "llama.attention.head_count_kv",
+            GgufValue::U32(self.n_head_kv),
+        );
+        set(
+            "llama.attention.layer_norm_rms_epsilon",
+            GgufValue::F32(1e-5),
+        );
+        set(
+            "llama.rope.dimension_count",
+            GgufValue::U32(self.n_embd / self.n_head),
+        );
+        set("llama.rope.freq_base", GgufValue::F32(10_000.0));
+        set("tokenizer.ggml.model", GgufValue::Str("llama".to_owned()));
+        let (tokens, token_types): (Vec<_>, Vec<_>) = vocab
+            .into_iter()
+            .map(|(token, token_type)| (GgufValue::Str(token), GgufValue::I32(token_type)))
+            .unzip();
+        let scores = (0..tokens.len())
+            .map(|_| GgufValue::F32(0.0))
+            .collect::<Vec<_>>();
+        set(
+            "tokenizer.ggml.tokens",
+            GgufValue::Array(GgufValueType::Str, tokens),
+        );
+        set(
+            "tokenizer.ggml.scores",
+            GgufValue::Array(GgufValueType::F32, scores),
+        );
+        set(
+            "tokenizer.ggml.token_type",
+            GgufValue::Array(GgufValueType::I32, token_types),
+        );
+        set("tokenizer.ggml.unknown_token_id", GgufValue::U32(0));
+        set("tokenizer.ggml.bos_token_id", GgufValue::U32(1));
+        set("tokenizer.ggml.eos_token_id", GgufValue::U32(2));
+        set("tokenizer.ggml.add_bos_token", GgufValue::Bool(true));
+
+        let n_embd = u64::from(self.n_embd);
+        let n_embd_kv = n_embd / u64::from(self.n_head) * u64::from(self.n_head_kv);
+        let n_ff = u64::from(self.n_ff);
+        let mut rng = seed_state(self.seed);
+        let mut add = |name: String, dims: Vec<u64>, weight: Option<f32>| {
+            let n_elements = dims.iter().product::<u64>();
+            let data = (0..n_elements)
+                .flat_map(|_| {
+                    weight
+                        .unwrap_or_else(|| random_weight(&mut rng))
+                        .to_le_bytes()
+                })
+                .collect::<Vec<u8>>();
+            writer
+                .add_tensor(name, dims, GgmlType::F32, data)
+                .expect("tensor names are unique and sizes match");
+        };
+        add("token_embd.weight".to_owned(), vec![n_embd, n_vocab], None);
+        add("output_norm.weight".to_owned(), vec![n_embd], Some(1.0));
+        add("output.weight".to_owned(), vec![n_embd, n_vocab], None);
+        for layer in 0..self.n_layer {
+            let name = |tensor: &str| format!("blk.{layer}.{tensor}.weight");
+            add(name("attn_norm"), vec![n_embd], Some(1.0));
+            add(name("attn_q"), vec![n_embd, n_embd], None);
+            add(name("attn_k"), vec![n_embd, n_embd_kv], None);
+            add(name("attn_v"), vec![n_embd, n_embd_kv], None);
+            add(name("attn_output"), vec![n_embd, n_embd], None);
+            add(name("ffn_norm"), vec![n_embd], Some(1.0));
+            add(name("ffn_gate"), vec![n_embd, n_ff], None);
+            add(name("ffn_down"), vec![n_ff, n_embd], None);
+            add(name("ffn_up"), vec![n_embd, n_ff], None);
+        }
+        writer
+    }
+
+    /// Write the model to `path`.
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufWriteError::Io`] if writing the file failed.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), GgufWriteError> {
+        self.build().write_to_file(path)
+    }
+}
+
+/// The initial xorshift state for `seed`. The seed is mixed with splitmix64 so that small or
+/// similar seeds give unrelated weights, and the state is never `0`, which xorshift cannot leave.
+fn seed_state(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) | 1
+}
+
+/// A uniformly distributed weight in `[-0.1, 0.1)` from a xorshift generator.
+fn random_weight(state: &mut u64) -> f32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    // the top 24 bits are exactly representable as an f32
+    #[allow(clippy::cast_precision_loss)]
+    let unit = (*state >> 40) as f32 / (1u64 << 24) as f32;
+    (unit - 0.5) * 0.2
+}
+
+/// The backend and a default [`TinyModel`], loaded once and shared by the tests of every module
+/// as the backend can only be initialized once per process.
+#[cfg(test)]
+pub(crate) fn test_model() -> &'static (crate::llama_backend::LlamaBackend, crate::model::LlamaModel)
+{
+    use crate::llama_backend::LlamaBackend;
+    use crate::model::params::LlamaModelParams;
+    use crate::model::LlamaModel;
+    use std::sync::OnceLock;
+
+    static MODEL: OnceLock<(LlamaBackend, LlamaModel)> = OnceLock::new();
+    MODEL.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("tiny-{}.gguf", std::process::id()));
+        TinyModel::default().write_to_file(&path).unwrap();
+        let backend = LlamaBackend::init().unwrap();
+        let model = LlamaModel::load_from_file(&backend, &path, &LlamaModelParams::default());
+        std::fs::remove_file(&path).unwrap();
+        (backend, model.unwrap())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GgufFile;
+
+    #[test]
+    fn tiny_models_are_valid_gguf_files() {
+        let model = TinyModel::default().with_n_layer(1);
+        let mut bytes = Vec::new();
+        model.build().write(&mut bytes).unwrap();
+        let gguf = GgufFile::from_bytes(bytes).unwrap();
+
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.context_length(), Some(512));
+        let n_vocab = TinyModel::vocab().len() as u64;
+        assert_eq!(
+            gguf.get("tokenizer.ggml.tokens")
+                .and_then(GgufValue::as_array)
+                .map(<[_]>::len),
+            Some(TinyModel::vocab().len())
+        );
+        // 3 global tensors and 9 per layer
+        assert_eq!(gguf.tensors().len(), 12);
+        let embd = gguf.tensor("token_embd.weight").unwrap();
+        assert_eq!(embd.dims, [64, n_vocab]);
+        assert_eq!(
+            gguf.tensor_data(embd).unwrap().len() as u64,
+            64 * n_vocab * 4
+        );
+        assert_eq!(gguf.tensor("blk.0.attn_k.weight").unwrap().dims, [64, 32]);
+    }
+
+    #[test]
+    fn the_vocab_has_every_byte_and_the_same_seed_gives_the_same_model() {
+        let vocab = TinyModel::vocab();
+        assert!(vocab.iter().any(|(token, _)| token == "<0x0A>"));
+        assert_eq!(vocab[1], ("<s>".to_owned(), TOKEN_TYPE_CONTROL));
+
+        let bytes = |model: TinyModel| {
+            let mut bytes = Vec::new();
+            model.build().write(&mut bytes).unwrap();
+            bytes
+        };
+        let model = TinyModel::default().with_n_layer(1);
+        assert_eq!(bytes(model), bytes(model));
+        assert_ne!(bytes(model), bytes(model.with_seed(1)));
+    }
+
+    #[test]
+    fn tiny_models_load_and_decode() {
+        use crate::context::params::LlamaContextParams;
+        use crate::llama_batch::LlamaBatch;
+        use crate::model::AddBos;
+        use std::num::NonZeroU32;
+
+        let (backend, model) = test_model();
+        let n_vocab = TinyModel::vocab().len();
+        assert_eq!(usize::try_from(model.n_vocab()).unwrap(), n_vocab);
+        assert_eq!(model.rope_type(), Some(crate::model::RopeType::Norm));
+
+        let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+        let mut ctx = model.new_context(backend, params).unwrap();
+        let tokens = model.str_to_token("hello world", AddBos::Always).unwrap();
+        assert_eq!(tokens[0], model.token_bos());
+        assert!(tokens.len() > 2);
+        let mut batch = LlamaBatch::new(tokens.len(), 1);
+        batch.add_sequence(&tokens, 0, false).unwrap();
+        ctx.decode(&mut batch).unwrap();
+
+        let logits = ctx.get_logits_ith(batch.n_tokens() - 1);
+        assert_eq!(logits.len(), n_vocab);
+        assert!(logits.iter().all(|logit| logit.is_finite()));
+    }
+
+    #[test]
+    fn a_zero_seed_gives_random_weights() {
+        let mut state = seed_state(0);
+        let weights = (0..8)
+            .map(|_| random_weight(&mut state))
+            .collect::<Vec<_>>();
+        assert!(weights.iter().all(|weight| (-0.1..0.1).contains(weight)));
+        assert!(weights
+            .windows(2)
+            .any(|pair| (pair[0] - pair[1]).abs() > 1e-6));
+    }
+}
@@ -0,0 +1,470 @@
+//! Write GGUF files, either from scratch or as an edited copy of an existing file.
+//!
+//! ```no_run
+//! # use llama_cpp_2::gguf::writer::GgufWriter;
+//! # use llama_cpp_2::gguf::{GgufFile, GgufValue};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let gguf = GgufFile::open("model.gguf")?;
+//! let mut writer = GgufWriter::from_gguf(&gguf)?;
+//! writer.set_kv("llama.context_length", GgufValue::U32(8192));
+//! writer.set_kv("tokenizer.chat_template", GgufValue::Str("{{ messages }}".to_owned()));
+//! writer.write_to_file("model-patched.gguf")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::gguf::{GgmlType, GgufError, GgufFile, GgufValue, DEFAULT_ALIGNMENT, GGUF_MAGIC};
+
+/// The version of the files written.
+const VERSION: u32 = 3;
+
+/// An error that can occur while writing a GGUF file.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufWriteError {
+    /// Writing the file failed.
+    #[error("failed to write the file: {0}")]
+    Io(#[from] std::io::Error),
+    /// A tensor with the same name was already added.
+    #[error("duplicate tensor {0}")]
+    DuplicateTensor(String),
+    /// The size of the data does not match the shape and type of the tensor.
+    #[error("tensor {name} has {actual} bytes of data but its shape and type need {expected:?}")]
+    TensorSize {
+        /// the name of the tensor
+        name: String,
+        /// the size needed by the shape and type, if the type is known
+        expected: Option<u64>,
+        /// the size of the data
+        actual: usize,
+    },
+    /// An array contains an element of a different type than the array.
+    #[error("the array {0} contains elements of different types")]
+    MixedArray(String),
+    /// `general.alignment` is not a power of two.
+    #[error("invalid alignment {0}")]
+    InvalidAlignment(u64),
+}
+
+/// A tensor to write.
+#[derive(Debug, Clone)]
+struct Tensor<'a> {
+    name: String,
+    dims: Vec<u64>,
+    ggml_type: GgmlType,
+    data: Cow<'a, [u8]>,
+}
+
+/// A GGUF file that is being assembled.
+///
+/// Tensor data copied from a [`GgufFile`] is borrowed, so copying a large memory-mapped model
+/// does not read it into memory.
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufWriter<'a> {
+    metadata: Vec<(String, GgufValue)>,
+    tensors: Vec<Tensor<'a>>,
+}
+
+impl<'a> GgufWriter<'a> {
+    /// An empty file without metadata or tensors.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of the metadata and tensors of `gguf`.
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufError::UnsupportedBigEndian`] if `gguf` is big endian, as files are always written
+    ///   little endian and the tensor data cannot be converted.
+    /// - [`GgufError::UnknownTensorType`] if the size of a tensor cannot be determined.
+    /// - [`GgufError::TensorOutOfBounds`] if the data of a tensor is not within the file.
+    pub fn from_gguf(gguf: &'a GgufFile) -> Result<Self, GgufError> {
+        if gguf.is_big_endian() {
+            return Err(GgufError::UnsupportedBigEndian);
+        }
+        let tensors = gguf
+            .tensors()
+            .iter()
+            .map(|tensor| {
+                Ok(Tensor {
+                    name: tensor.name.clone(),
+                    dims: tensor.dims.clone(),
+                    ggml_type: tensor.ggml_type,
+                    data: Cow::Borrowed(gguf.tensor_data(tensor)?),
+                })
+            })
+            .collect::<Result<_, GgufError>>()?;
+        Ok(Self {
+            metadata: gguf.metadata().to_vec(),
+            tensors,
+        })
+    }
+
+    /// All metadata key-value pairs, in the order they will be written.
+    #[must_use]
+    pub fn metadata(&self) -> &[(String, GgufValue)] {
+        &self.metadata
+    }
+
+    /// Set the value of `key`, returning the old value. An existing key keeps its position, a
+    /// new key is appended.
+    pub fn set_kv(&mut self, key: impl Into<String>, value: GgufValue) -> Option<GgufValue> {
+        let key = key.into();
+        if let Some((_, old)) = self.metadata.iter_mut().find(|(k, _)| *k == key) {
+            return Some(std::mem::replace(old, value));
+        }
+        self.metadata.push((key, value));
+        None
+    }
+
+    /// Remove `key`, returning its value.
+    pub fn remove_kv(&mut self, key: &str) -> Option<GgufValue> {
+        let index = self.metadata.iter().position(|(k, _)| k == key)?;
+        Some(self.metadata.remove(index).1)
+    }
+
+    /// The names of the tensors, in the order they will be written.
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.tensors.iter().map(|tensor| tensor.name.as_str())
+    }
+
+    /// Append a tensor. `dims` are innermost first, as in [`crate::gguf::GgufTensorInfo`].
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufWriteError::DuplicateTensor`] if a tensor called `name` was already added.
+    /// - [`GgufWriteError::TensorSize`] if the length of `data` does not match `dims` and
+    ///   `ggml_type`.
+    pub fn add_tensor(
+        &mut self,
+        name: impl Into<String>,
+        dims: Vec<u64>,
+        ggml_type: GgmlType,
+        data: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), GgufWriteError> {
+        let name = name.into();
+        if self.tensors.iter().any(|tensor| tensor.name == name) {
+            return Err(GgufWriteError::DuplicateTensor(name));
+        }
+        let data = data.into();
+        let tensor = Tensor {
+            name,
+            dims,
+            ggml_type,
+            data,
+        };
+        let expected = tensor.info(0).n_bytes();
+        if expected != u64::try_from(tensor.data.len()).ok() {
+            return Err(GgufWriteError::TensorSize {
+                name: tensor.name,
+                expected,
+                actual: tensor.data.len(),
+            });
+        }
+        self.tensors.push(tensor);
+        Ok(())
+    }
+
+    /// Remove the tensor called `name`, returning whether it existed.
+    pub fn remove_tensor(&mut self, name: &str) -> bool {
+        let len = self.tensors.len();
+        self.tensors.retain(|tensor| tensor.name != name);
+        self.tensors.len() != len
+    }
+
+    /// Write the file (as GGUF version 3) to `out`.
+    ///
+    /// # Errors
+    ///
+    /// - [`GgufWriteError::Io`] if writing failed.
+    /// - [`GgufWriteError::MixedArray`] if an array has elements of the wrong type.
+    /// - [`GgufWriteError::InvalidAlignment`] if `general.alignment` is not a power of two.
+    #[allow(clippy::missing_panics_doc)] // the number of dimensions and the padding are small
+    pub fn write(&self, mut out: impl Write) -> Result<(), GgufWriteError> {
+        let alignment = self.alignment()?;
+        let mut header = GGUF_MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+        for (key, value) in &self.metadata {
+            write_string(&mut header, key);
+            header.extend_from_slice(&value.value_type().id().to_le_bytes());
+            write_value(&mut header, key, value)?;
+        }
+
+        let mut offset = 0;
+        for tensor in &self.tensors {
+            let info = tensor.info(offset);
+            write_string(&mut header, &info.name);
+            let n_dims = u32::try_from(info.dims.len()).expect("a tensor has few dimensions");
+            header.extend_from_slice(&n_dims.to_le_bytes());
+            for dim in &info.dims {
+                header.extend_from_slice(&dim.to_le_bytes());
+            }
+            header.extend_from_slice(&info.ggml_type.0.to_le_bytes());
+            header.extend_from_slice(&info.offset.to_le_bytes());
+            offset = (offset + tensor.data.len() as u64).next_multiple_of(alignment);
+        }
+
+        let mut written = header.len() as u64;
+        out.write_all(&header)?;
+        for tensor in &self.tensors {
+            pad(&mut out, &mut written, alignment)?;
+            out.write_all(&tensor.data)?;
+            written += tensor.data.len() as u64;
+        }
+        pad(&mut out, &mut written, alignment)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Write the file to `path`. The file is written next to `path` and then renamed, so a
+    /// reader never sees a partially written file.
+    ///
+    /// On unix `path` may be the file this writer was copied from. On windows a file that is
+    /// memory mapped cannot be replaced, so a copy of a [`GgufFile::open`]ed file must be
+    /// written to a different path.
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufWriter::write`].
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), GgufWriteError> {
+        // unique per writer, so concurrent writes to the same path do not clobber each other
+        static N_WRITES: AtomicU64 = AtomicU64::new(0);
+        let path = path.as_ref();
+        let n_writes = N_WRITES.fetch_add(1, Ordering::Relaxed);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.{n_writes}.tmp", std::process::id()));
+        let result = File::create(&tmp)
+            .map_err(GgufWriteError::from)
+            .and_then(|file| self.write(BufWriter::new(file)))
+            .and_then(|()| std::fs::rename(&tmp, path).map_err(GgufWriteError::from));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn alignment(&self) -> Result<u64, GgufWriteError> {
+        let Some((_, value)) = self
+            .metadata
+            .iter()
+            .find(|(key, _)| key == "general.alignment")
+        else {
+            return Ok(DEFAULT_ALIGNMENT);
+        };
+        let alignment = value.as_u64().unwrap_or(0);
+        if alignment.is_power_of_two() {
+            Ok(alignment)
+        } else {
+            Err(GgufWriteError::InvalidAlignment(alignment))
+        }
+    }
+}
+
+impl Tensor<'_> {
+    fn info(&self, offset: u64) -> crate::gguf::GgufTensorInfo {
+        crate::gguf::GgufTensorInfo {
+            name: self.name.clone(),
+            dims: self.dims.clone(),
+            ggml_type: self.ggml_type,
+            offset,
+        }
+    }
+}
+
+fn pad(out: &mut impl Write, written: &mut u64, alignment: u64) -> std::io::Result<()> {
+    let padding = written.next_multiple_of(alignment) - *written;
+    out.write_all(&vec![
+        0;
+        usize::try_from(padding)
+            .expect("alignment fits into a usize")
+    ])?;
+    *written += padding;
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, key: &str, value: &GgufValue) -> Result<(), GgufWriteError> {
+    match value {
+        GgufValue::U8(value) => out.push(*value),
+        GgufValue::I8(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::U16(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::I16(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::U32(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::I32(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::F32(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::Bool(value) => out.push(u8::from(*value)),
+        GgufValue::Str(value) => write_string(out, value),
+        GgufValue::U64(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::I64(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::F64(value) => out.extend_from_slice(&value.to_le_bytes()),
+        GgufValue::Array(element_type, values) => {
+            out.extend_from_slice(&element_type.id().to_le_bytes());
+            out.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                if value.value_type() != *element_type {
+                    return Err(GgufWriteError::MixedArray(key.to_owned()));
+                }
+                write_value(out, key, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GgufValueType;
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn write(writer: &GgufWriter) -> GgufFile {
+        let mut bytes = Vec::new();
+        writer.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len() % 32, 0);
+        GgufFile::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn concurrent_writes_to_the_same_path_do_not_collide() {
+        let dir = std::env::temp_dir().join(format!("gguf-writes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.gguf");
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    let mut writer = GgufWriter::new();
+                    writer.set_kv("general.file_type", GgufValue::U32(i));
+                    writer.write_to_file(path).unwrap();
+                });
+            }
+        });
+        let gguf = GgufFile::open(&path).unwrap();
+        assert!(gguf.get("general.file_type").is_some());
+        drop(gguf);
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn written_files_can_be_read() {
+        let mut writer = GgufWriter::new();
+        writer.set_kv("general.architecture", GgufValue::Str("llama".to_owned()));
+        writer.set_kv(
+            "tokenizer.ggml.scores",
+            GgufValue::Array(GgufValueType::F32, vec![GgufValue::F32(-1.0)]),
+        );
+        writer
+            .add_tensor("a", vec![3], GgmlType::F32, f32_bytes(&[1.0, 2.0, 3.0]))
+            .unwrap();
+        writer
+            .add_tensor("b", vec![2, 2], GgmlType::F32, f32_bytes(&[4.0; 4]))
+            .unwrap();
+
+        let gguf = write(&writer);
+        assert_eq!(gguf.metadata(), writer.metadata());
+        let a = gguf.tensor("a").unwrap();
+        assert_eq!(a.offset, 0);
+        assert_eq!(gguf.tensor_data(a).unwrap(), f32_bytes(&[1.0, 2.0, 3.0]));
+        let b = gguf.tensor("b").unwrap();
+        assert_eq!(b.offset, 32);
+        assert_eq!(gguf.tensor_data(b).unwrap(), f32_bytes(&[4.0; 4]));
+    }
+
+    #[test]
+    fn copies_rewrite_metadata_and_keep_tensors() {
+        let mut writer = GgufWriter::new();
+        writer.set_kv("llama.context_length", GgufValue::U32(2048));
+        writer.set_kv("general.name", GgufValue::Str("tiny".to_owned()));
+        writer
+            .add_tensor("a", vec![1], GgmlType::F32, f32_bytes(&[7.0]))
+            .unwrap();
+        let original = write(&writer);
+
+        let mut copy = GgufWriter::from_gguf(&original).unwrap();
+        assert_eq!(
+            copy.set_kv("llama.context_length", GgufValue::U32(4096)),
+            Some(GgufValue::U32(2048))
+        );
+        copy.remove_kv("general.name");
+        copy.set_kv(
+            "tokenizer.chat_template",
+            GgufValue::Str("{{ x }}".to_owned()),
+        );
+        let copy = write(&copy);
+
+        assert_eq!(
+            copy.metadata(),
+            [
+                ("llama.context_length".to_owned(), GgufValue::U32(4096)),
+                (
+                    "tokenizer.chat_template".to_owned(),
+                    GgufValue::Str("{{ x }}".to_owned())
+                ),
+            ]
+        );
+        let a = copy.tensor("a").unwrap();
+        assert_eq!(copy.tensor_data(a).unwrap(), f32_bytes(&[7.0]));
+    }
+
+    #[test]
+    fn big_endian_files_are_not_copied() {
+        let mut bytes = GGUF_MAGIC.to_vec();
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&0u64.to_be_bytes());
+        bytes.extend_from_slice(&0u64.to_be_bytes());
+        let gguf = GgufFile::from_bytes(bytes).unwrap();
+        assert!(gguf.is_big_endian());
+        assert!(matches!(
+            GgufWriter::from_gguf(&gguf),
+            Err(GgufError::UnsupportedBigEndian)
+        ));
+    }
+
+    #[test]
+    fn invalid_tensors_and_arrays_are_rejected() {
+        let mut writer = GgufWriter::new();
+        assert!(matches!(
+            writer.add_tensor("a", vec![2], GgmlType::F32, f32_bytes(&[1.0])),
+            Err(GgufWriteError::TensorSize { .. })
+        ));
+        writer
+            .add_tensor("a", vec![1], GgmlType::F32, f32_bytes(&[1.0]))
+            .unwrap();
+        assert!(matches!(
+            writer.add_tensor("a", vec![1], GgmlType::F32, f32_bytes(&[1.0])),
+            Err(GgufWriteError::DuplicateTensor(_))
+        ));
+
+        writer.set_kv(
+            "mixed",
+            GgufValue::Array(GgufValueType::U8, vec![GgufValue::I8(1)]),
+        );
+        assert!(matches!(
+            writer.write(Vec::new()),
+            Err(GgufWriteError::MixedArray(key)) if key == "mixed"
+        ));
+    }
+}
@@ -0,0 +1,1103 @@
+//! Convert a JSON Schema into a GBNF grammar for structured output.
+//!
+//! This follows the rules generated by `json-schema-to-grammar` in llama.cpp, so the output can be
+//! handed straight to [`crate::sampling::LlamaSampler::grammar`] with `"root"` as the root rule.
+//!
+//! Supported keywords are `type` (including arrays of types), `properties`, `required`,
+//! `additionalProperties`, `enum`, `const`, `items`, `prefixItems`, `minItems`, `maxItems`,
+//! `pattern`, `minLength`, `maxLength`, `format` (`date`, `time`, `date-time` and `uuid`), local
+//! `$ref`s and `oneOf`/`anyOf`. Other annotations such as `description` are ignored. Numeric bounds
+//! are not enforced.
+//!
+//! ```
+//! # use llama_cpp_2::grammar::json_schema::json_schema_to_grammar;
+//! let schema = serde_json::json!({
+//!     "type": "object",
+//!     "properties": { "answer": { "enum": ["yes", "no"] } },
+//!     "required": ["answer"]
+//! });
+//! let grammar = json_schema_to_grammar(&schema).unwrap();
+//! assert!(grammar.contains(r#"root ::= "{" space answer-kv "}" space"#));
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::{write_escaped, GrammarParseError, LlamaGrammar};
+
+/// An error converting a JSON Schema into a grammar.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum JsonSchemaError {
+    /// The schema (or a sub-schema) uses a construct the converter does not understand.
+    #[error("unsupported schema: {0}")]
+    UnsupportedSchema(String),
+    /// A `$ref` was not a local reference or did not point anywhere in the schema.
+    #[error("could not resolve reference {0}")]
+    UnresolvedRef(String),
+    /// A keyword had a value of the wrong type.
+    #[error("invalid value for {keyword}: {value}")]
+    InvalidKeyword {
+        /// the keyword
+        keyword: &'static str,
+        /// the offending value
+        value: String,
+    },
+    /// A `pattern` could not be translated into a grammar.
+    #[error("invalid pattern {pattern:?}: {reason}")]
+    InvalidPattern {
+        /// the pattern
+        pattern: String,
+        /// why it was rejected
+        reason: &'static str,
+    },
+    /// The generated grammar did not parse. This is a bug in the converter.
+    #[error(transparent)]
+    Grammar(#[from] GrammarParseError),
+}
+
+/// Convert a JSON Schema into GBNF.
+///
+/// # Errors
+///
+/// See [`JsonSchemaError`].
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String, JsonSchemaError> {
+    let mut converter = SchemaConverter::new(schema);
+    converter.visit(schema, "")?;
+    Ok(converter.format_grammar())
+}
+
+impl LlamaGrammar {
+    /// Convert a JSON Schema into a grammar. See [`json_schema_to_grammar`] for what is supported.
+    ///
+    /// ```
+    /// # use llama_cpp_2::grammar::LlamaGrammar;
+    /// let schema = serde_json::json!({ "type": "array", "items": { "type": "integer" } });
+    /// let grammar = LlamaGrammar::from_json_schema(&schema).unwrap();
+    /// assert!(grammar.symbol_id("integer").is_some());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// See [`JsonSchemaError`].
+    pub fn from_json_schema(schema: &Value) -> Result<Self, JsonSchemaError> {
+        Ok(Self::from_str(&json_schema_to_grammar(schema)?)?)
+    }
+}
+
+const SPACE_RULE: &str = r#"| " " | "\n" [ \t]{0,20}"#;
+
+/// A named rule the converter can pull in, along with the rules it refers to.
+struct BuiltinRule {
+    name: &'static str,
+    content: &'static str,
+    deps: &'static [&'static str],
+}
+
+const PRIMITIVE_RULES: &[BuiltinRule] = &[
+    BuiltinRule {
+        name: "boolean",
+        content: r#"("true" | "false") space"#,
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "decimal-part",
+        content: "[0-9]{1,16}",
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "integral-part",
+        content: "[0] | [1-9] [0-9]{0,15}",
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "number",
+        content: r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+        deps: &["integral-part", "decimal-part"],
+    },
+    BuiltinRule {
+        name: "integer",
+        content: r#"("-"? integral-part) space"#,
+        deps: &["integral-part"],
+    },
+    BuiltinRule {
+        name: "value",
+        content: "object | array | string | number | boolean | null",
+        deps: &["object", "array", "string", "number", "boolean", "null"],
+    },
+    BuiltinRule {
+        name: "object",
+        content: r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+        deps: &["string", "value"],
+    },
+    BuiltinRule {
+        name: "array",
+        content: r#""[" space ( value ("," space value)* )? "]" space"#,
+        deps: &["value"],
+    },
+    BuiltinRule {
+        name: "uuid",
+        content: r#""\"" [0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12} "\"" space"#,
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "char",
+        content: r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "string",
+        content: r#""\"" char* "\"" space"#,
+        deps: &["char"],
+    },
+    BuiltinRule {
+        name: "null",
+        content: r#""null" space"#,
+        deps: &[],
+    },
+];
+
+const STRING_FORMAT_RULES: &[BuiltinRule] = &[
+    BuiltinRule {
+        name: "date",
+        content: r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [1-2] [0-9] | "3" [0-1] )"#,
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "time",
+        content: r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{3} )? ( "Z" | ( "+" | "-" ) ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+        deps: &[],
+    },
+    BuiltinRule {
+        name: "date-time",
+        content: r#"date "T" time"#,
+        deps: &["date", "time"],
+    },
+    BuiltinRule {
+        name: "date-string",
+        content: r#""\"" date "\"" space"#,
+        deps: &["date"],
+    },
+    BuiltinRule {
+        name: "time-string",
+        content: r#""\"" time "\"" space"#,
+        deps: &["time"],
+    },
+    BuiltinRule {
+        name: "date-time-string",
+        content: r#""\"" date-time "\"" space"#,
+        deps: &["date-time"],
+    },
+];
+
+fn builtin_rule(name: &str) -> Option<&'static BuiltinRule> {
+    PRIMITIVE_RULES
+        .iter()
+        .chain(STRING_FORMAT_RULES)
+        .find(|rule| rule.name == name)
+}
+
+/// Names the converter adds on its own; schema derived rules must not take them.
+fn is_reserved_name(name: &str) -> bool {
+    matches!(name, "root" | "space" | "dot") || builtin_rule(name).is_some()
+}
+
+/// Replace everything that is not allowed in a rule name with `-`.
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+    sanitized
+}
+
+/// Join `parent` and `child` into a rule name.
+fn child_name(parent: &str, child: &str) -> String {
+    if parent.is_empty() {
+        child.to_string()
+    } else {
+        format!("{parent}-{child}")
+    }
+}
+
+/// Format `s` as a GBNF string literal.
+fn format_literal(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        write_escaped(&mut literal, u32::from(c), false).expect("writing to a string cannot fail");
+    }
+    literal.push('"');
+    literal
+}
+
+/// `item` repeated between `min` and `max` (unbounded if `None`) times, with `separator` between
+/// each repetition.
+fn build_repetition(item: &str, min: u64, max: Option<u64>, separator: Option<&str>) -> String {
+    if max == Some(0) {
+        return String::new();
+    }
+    if min == 0 && max == Some(1) {
+        return format!("{item}?");
+    }
+    let Some(separator) = separator else {
+        return match (min, max) {
+            (0, None) => format!("{item}*"),
+            (1, None) => format!("{item}+"),
+            (min, None) => format!("{item}{{{min},}}"),
+            (min, Some(max)) if min == max => format!("{item}{{{min}}}"),
+            (min, Some(max)) => format!("{item}{{{min},{max}}}"),
+        };
+    };
+    let rest = build_repetition(
+        &format!("({separator} {item})"),
+        min.saturating_sub(1),
+        max.map(|max| max - 1),
+        None,
+    );
+    let result = if rest.is_empty() {
+        item.to_string()
+    } else {
+        format!("{item} {rest}")
+    };
+    if min == 0 {
+        format!("({result})?")
+    } else {
+        result
+    }
+}
+
+fn as_count(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &'static str,
+) -> Result<Option<u64>, JsonSchemaError> {
+    schema
+        .get(keyword)
+        .map(|value| {
+            value
+                .as_u64()
+                .ok_or_else(|| JsonSchemaError::InvalidKeyword {
+                    keyword,
+                    value: value.to_string(),
+                })
+        })
+        .transpose()
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: BTreeMap<String, String>,
+    /// `$ref`s that have been (or are being) visited and the rule they map to.
+    refs: HashMap<String, String>,
+}
+
+impl<'a> SchemaConverter<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            rules: BTreeMap::from([("space".to_string(), SPACE_RULE.to_string())]),
+            refs: HashMap::from([("#".to_string(), LlamaGrammar::ROOT.to_string())]),
+        }
+    }
+
+    fn format_grammar(&self) -> String {
+        let mut grammar = String::new();
+        for (name, rule) in &self.rules {
+            writeln!(grammar, "{name} ::= {rule}").expect("writing to a string cannot fail");
+        }
+        grammar
+    }
+
+    /// Add a rule, picking a fresh name if `name` is already taken by a different rule.
+    fn add_rule(&mut self, name: &str, rule: String) -> String {
+        let name = sanitize_name(name);
+        let mut key = name.clone();
+        let mut i = 0;
+        while self
+            .rules
+            .get(&key)
+            .is_some_and(|existing| *existing != rule)
+        {
+            key = format!("{name}{i}");
+            i += 1;
+        }
+        self.rules.insert(key.clone(), rule);
+        key
+    }
+
+    fn add_builtin(&mut self, name: &str, rule: &BuiltinRule) -> String {
+        let key = self.add_rule(name, rule.content.to_string());
+        for &dep in rule.deps {
+            if !self.rules.contains_key(dep) {
+                let dep_rule = builtin_rule(dep).expect("builtin rules only depend on builtins");
+                self.add_builtin(dep, dep_rule);
+            }
+        }
+        key
+    }
+
+    fn add_primitive(&mut self, name: &str) -> String {
+        let rule = builtin_rule(name).expect("unknown primitive rule");
+        self.add_builtin(name, rule)
+    }
+
+    fn resolve_ref(&mut self, reference: &str) -> Result<String, JsonSchemaError> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let resolved = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| JsonSchemaError::UnresolvedRef(reference.to_string()))?;
+        let mut name = sanitize_name(reference.rsplit('/').next().unwrap_or_default());
+        if is_reserved_name(&name) {
+            name.push('-');
+        }
+        let base = name.clone();
+        let mut i = 0;
+        while self.rules.contains_key(&name) || self.refs.values().any(|n| *n == name) {
+            name = format!("{base}{i}");
+            i += 1;
+        }
+        self.refs.insert(reference.to_string(), name.clone());
+        self.visit(resolved, &name)
+    }
+
+    fn visit_union(
+        &mut self,
+        name: &str,
+        alternatives: &[Value],
+    ) -> Result<String, JsonSchemaError> {
+        let mut rules = Vec::with_capacity(alternatives.len());
+        for (i, alternative) in alternatives.iter().enumerate() {
+            let alternative_name = if name.is_empty() {
+                format!("alternative-{i}")
+            } else {
+                format!("{name}-{i}")
+            };
+            rules.push(self.visit(alternative, &alternative_name)?);
+        }
+        Ok(rules.join(" | "))
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, JsonSchemaError> {
+        let rule_name = if is_reserved_name(name) {
+            format!("{name}-")
+        } else if name.is_empty() {
+            LlamaGrammar::ROOT.to_string()
+        } else {
+            name.to_string()
+        };
+
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive_or_root(&rule_name, "value")),
+            Value::Object(schema) => schema,
+            _ => return Err(JsonSchemaError::UnsupportedSchema(schema.to_string())),
+        };
+        let schema_type = schema.get("type");
+        let is_type = |t: &str| schema_type.is_none_or(|schema_type| schema_type == t);
+
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| JsonSchemaError::InvalidKeyword {
+                    keyword: "$ref",
+                    value: reference.to_string(),
+                })?;
+            let rule = self.resolve_ref(reference)?;
+            if rule == rule_name {
+                return Ok(rule);
+            }
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if let Some((keyword, alternatives)) = ["oneOf", "anyOf"]
+            .into_iter()
+            .find_map(|keyword| Some((keyword, schema.get(keyword)?)))
+        {
+            let alternatives =
+                alternatives
+                    .as_array()
+                    .ok_or_else(|| JsonSchemaError::InvalidKeyword {
+                        keyword,
+                        value: alternatives.to_string(),
+                    })?;
+            let rule = self.visit_union(name, alternatives)?;
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if let Some(Value::Array(types)) = schema_type {
+            let alternatives = types
+                .iter()
+                .map(|t| {
+                    let mut alternative = schema.clone();
+                    alternative.insert("type".to_string(), t.clone());
+                    Value::Object(alternative)
+                })
+                .collect::<Vec<_>>();
+            let rule = self.visit_union(name, &alternatives)?;
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if let Some(value) = schema.get("const") {
+            let rule = format!("{} space", format_literal(&value.to_string()));
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .filter(|values| !values.is_empty())
+                .ok_or_else(|| JsonSchemaError::InvalidKeyword {
+                    keyword: "enum",
+                    value: values.to_string(),
+                })?;
+            let values = values
+                .iter()
+                .map(|value| format_literal(&value.to_string()))
+                .collect::<Vec<_>>();
+            let rule = format!("({}) space", values.join(" | "));
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        let additional_properties = schema.get("additionalProperties");
+        if is_type("object")
+            && (schema.contains_key("properties")
+                || additional_properties.is_some_and(|additional| *additional != Value::Bool(true)))
+        {
+            let properties = match schema.get("properties") {
+                None => Vec::new(),
+                Some(Value::Object(properties)) => properties.iter().collect(),
+                Some(properties) => {
+                    return Err(JsonSchemaError::InvalidKeyword {
+                        keyword: "properties",
+                        value: properties.to_string(),
+                    })
+                }
+            };
+            let required = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|required| {
+                    required
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            let rule =
+                self.build_object_rule(&properties, &required, name, additional_properties)?;
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if let Some(disallowed) = ["allOf", "not", "if"]
+            .into_iter()
+            .find(|keyword| schema.contains_key(*keyword))
+        {
+            return Err(JsonSchemaError::UnsupportedSchema(format!(
+                "the {disallowed} keyword is not supported"
+            )));
+        }
+
+        if is_type("array") && (schema.contains_key("items") || schema.contains_key("prefixItems"))
+        {
+            let items = schema
+                .get("items")
+                .or_else(|| schema.get("prefixItems"))
+                .expect("checked above");
+            let rule = if let Value::Array(items) = items {
+                let mut rule = r#""[" space "#.to_string();
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        rule.push_str(r#" "," space "#);
+                    }
+                    rule.push_str(&self.visit(item, &child_name(name, &format!("tuple-{i}")))?);
+                }
+                rule.push_str(r#" "]" space"#);
+                rule
+            } else {
+                let item = self.visit(items, &child_name(name, "item"))?;
+                let min = as_count(schema, "minItems")?.unwrap_or(0);
+                let max = as_count(schema, "maxItems")?;
+                format!(
+                    r#""[" space {} "]" space"#,
+                    build_repetition(&item, min, max, Some(r#""," space"#))
+                )
+            };
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        if is_type("string") {
+            if let Some(pattern) = schema.get("pattern") {
+                let pattern = pattern
+                    .as_str()
+                    .ok_or_else(|| JsonSchemaError::InvalidKeyword {
+                        keyword: "pattern",
+                        value: pattern.to_string(),
+                    })?;
+                return self.visit_pattern(pattern, &rule_name);
+            }
+            let format = schema
+                .get("format")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if matches!(
+                format,
+                "uuid" | "uuid1" | "uuid2" | "uuid3" | "uuid4" | "uuid5"
+            ) {
+                let uuid = builtin_rule("uuid").expect("uuid is a primitive");
+                let name = if rule_name == LlamaGrammar::ROOT {
+                    LlamaGrammar::ROOT
+                } else {
+                    format
+                };
+                return Ok(self.add_builtin(name, uuid));
+            }
+            if let Some(format_rule) = builtin_rule(&format!("{format}-string")) {
+                let rule = self.add_builtin(format_rule.name, format_rule);
+                return Ok(self.add_rule(&rule_name, rule));
+            }
+        }
+
+        if schema_type.is_some_and(|t| t == "string")
+            && (schema.contains_key("minLength") || schema.contains_key("maxLength"))
+        {
+            let char_rule = self.add_primitive("char");
+            let min = as_count(schema, "minLength")?.unwrap_or(0);
+            let max = as_count(schema, "maxLength")?;
+            let rule = format!(
+                r#""\"" {} "\"" space"#,
+                build_repetition(&char_rule, min, max, None)
+            );
+            return Ok(self.add_rule(&rule_name, rule));
+        }
+
+        match schema_type {
+            None => Ok(self.primitive_or_root(&rule_name, "value")),
+            Some(Value::String(schema_type))
+                if PRIMITIVE_RULES.iter().any(|rule| rule.name == schema_type) =>
+            {
+                Ok(self.primitive_or_root(&rule_name, schema_type))
+            }
+            Some(schema_type) => Err(JsonSchemaError::UnsupportedSchema(format!(
+                "unknown type {schema_type}"
+            ))),
+        }
+    }
+
+    /// The rule for a primitive, or the root rule if this is the root of the schema.
+    fn primitive_or_root(&mut self, rule_name: &str, primitive: &str) -> String {
+        if rule_name == LlamaGrammar::ROOT {
+            let rule = builtin_rule(primitive).expect("unknown primitive rule");
+            self.add_builtin(LlamaGrammar::ROOT, rule)
+        } else {
+            self.add_primitive(primitive)
+        }
+    }
+
+    /// Required properties come first in schema order, followed by the optional ones. Optional
+    /// properties may be skipped but keep their relative order.
+    fn build_object_rule(
+        &mut self,
+        properties: &[(&String, &Value)],
+        required: &[&str],
+        name: &str,
+        additional_properties: Option<&Value>,
+    ) -> Result<String, JsonSchemaError> {
+        let mut required_kvs = Vec::new();
+        // (key used for naming, kv rule, may repeat)
+        let mut optional_kvs = Vec::new();
+        for &(property, schema) in properties {
+            let value_rule = self.visit(schema, &child_name(name, property))?;
+            let key = format_literal(&Value::String(property.clone()).to_string());
+            let kv_rule = self.add_rule(
+                &child_name(name, &format!("{property}-kv")),
+                format!(r#"{key} space ":" space {value_rule}"#),
+            );
+            if required.contains(&property.as_str()) {
+                required_kvs.push(kv_rule);
+            } else {
+                optional_kvs.push((property.clone(), kv_rule, false));
+            }
+        }
+
+        match additional_properties {
+            Some(Value::Bool(true) | Value::Object(_)) => {
+                let sub_name = child_name(name, "additional");
+                let value_rule = match additional_properties {
+                    Some(schema @ Value::Object(_)) => {
+                        self.visit(schema, &format!("{sub_name}-value"))?
+                    }
+                    _ => self.add_primitive("value"),
+                };
+                let key_rule = self.add_primitive("string");
+                let kv_rule = self.add_rule(
+                    &format!("{sub_name}-kv"),
+                    format!(r#"{key_rule} ":" space {value_rule}"#),
+                );
+                optional_kvs.push(("additional".to_string(), kv_rule, true));
+            }
+            None | Some(Value::Bool(false)) => {}
+            Some(additional_properties) => {
+                return Err(JsonSchemaError::InvalidKeyword {
+                    keyword: "additionalProperties",
+                    value: additional_properties.to_string(),
+                })
+            }
+        }
+
+        let mut rule = r#""{" space "#.to_string();
+        rule.push_str(&required_kvs.join(r#" "," space "#));
+        if !optional_kvs.is_empty() {
+            rule.push_str(" (");
+            if !required_kvs.is_empty() {
+                rule.push_str(r#" "," space ( "#);
+            }
+            let alternatives = (0..optional_kvs.len())
+                .map(|i| self.optional_kvs_rule(&optional_kvs[i..], name, false))
+                .collect::<Vec<_>>();
+            rule.push_str(&alternatives.join(" | "));
+            if !required_kvs.is_empty() {
+                rule.push_str(" )");
+            }
+            rule.push_str(" )?");
+        }
+        rule.push_str(r#" "}" space"#);
+        Ok(rule)
+    }
+
+    /// A rule matching the first of `kvs` (optionally, if `first_is_optional`) followed by any
+    /// subset of the rest.
+    fn optional_kvs_rule(
+        &mut self,
+        kvs: &[(String, String, bool)],
+        name: &str,
+        first_is_optional: bool,
+    ) -> String {
+        let Some(((key, kv_rule, repeats), rest)) = kvs.split_first() else {
+            return String::new();
+        };
+        let comma_ref = format!(r#"( "," space {kv_rule} )"#);
+        let mut rule = match (first_is_optional, repeats) {
+            (true, true) => format!("{comma_ref}*"),
+            (true, false) => format!("{comma_ref}?"),
+            (false, true) => format!("{kv_rule} {comma_ref}*"),
+            (false, false) => kv_rule.clone(),
+        };
+        if !rest.is_empty() {
+            let rest_rule = self.optional_kvs_rule(rest, name, true);
+            let rest_name = self.add_rule(&child_name(name, &format!("{key}-rest")), rest_rule);
+            rule.push(' ');
+            rule.push_str(&rest_name);
+        }
+        rule
+    }
+
+    fn visit_pattern(&mut self, pattern: &str, rule_name: &str) -> Result<String, JsonSchemaError> {
+        let invalid = |reason| JsonSchemaError::InvalidPattern {
+            pattern: pattern.to_string(),
+            reason,
+        };
+        let inner = pattern
+            .strip_prefix('^')
+            .and_then(|pattern| pattern.strip_suffix('$'))
+            .ok_or_else(|| invalid("pattern must start with '^' and end with '$'"))?;
+        let mut parser = PatternParser {
+            chars: inner.chars().collect(),
+            pos: 0,
+            converter: self,
+        };
+        let rule = parser.alternatives(0).map_err(invalid)?;
+        Ok(self.add_rule(rule_name, format!(r#""\"" ({rule}) "\"" space"#)))
+    }
+}
+
+/// A single item of a translated regular expression.
+enum Piece {
+    /// A literal character. Consecutive literals are merged into one string.
+    Literal(char),
+    /// A grammar item that binds as tightly as a single character.
+    Item(String),
+    /// An item with a repetition operator applied.
+    Repeated(String),
+}
+
+impl Piece {
+    fn into_item(self) -> String {
+        match self {
+            Piece::Literal(c) => format_literal(&c.to_string()),
+            Piece::Item(item) => item,
+            Piece::Repeated(item) => format!("({item})"),
+        }
+    }
+}
+
+/// Translates the subset of regular expressions supported by llama.cpp into GBNF.
+struct PatternParser<'a, 'b> {
+    chars: Vec<char>,
+    pos: usize,
+    converter: &'b mut SchemaConverter<'a>,
+}
+
+impl PatternParser<'_, '_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn join(sequence: Vec<Piece>) -> String {
+        let mut items = Vec::new();
+        let mut literal = String::new();
+        for piece in sequence {
+            if let Piece::Literal(c) = piece {
+                literal.push(c);
+                continue;
+            }
+            if !literal.is_empty() {
+                items.push(format_literal(&std::mem::take(&mut literal)));
+            }
+            items.push(match piece {
+                Piece::Repeated(item) | Piece::Item(item) => item,
+                Piece::Literal(_) => unreachable!(),
+            });
+        }
+        if !literal.is_empty() || items.is_empty() {
+            items.push(format_literal(&literal));
+        }
+        items.join(" ")
+    }
+
+    /// Parse alternatives up to the end of the pattern, or the closing parenthesis of a group if
+    /// `depth` is non-zero.
+    fn alternatives(&mut self, depth: usize) -> Result<String, &'static str> {
+        let mut alternatives = Vec::new();
+        let mut sequence = Vec::new();
+        loop {
+            let Some(c) = self.next() else {
+                if depth > 0 {
+                    return Err("unbalanced parentheses");
+                }
+                break;
+            };
+            match c {
+                ')' if depth == 0 => return Err("unbalanced parentheses"),
+                ')' => break,
+                '|' => alternatives.push(Self::join(std::mem::take(&mut sequence))),
+                '(' => {
+                    if self.peek() == Some('?') {
+                        self.next();
+                        if self.next() != Some(':') {
+                            return Err("only non-capturing groups are supported");
+                        }
+                    }
+                    let group = self.alternatives(depth + 1)?;
+                    sequence.push(Piece::Item(format!("({group})")));
+                }
+                '[' => sequence.push(Piece::Item(self.class()?)),
+                '.' => {
+                    let dot = self.converter.add_rule("dot", r"[^\x0A\x0D]".to_string());
+                    sequence.push(Piece::Item(dot));
+                }
+                '*' | '+' | '?' | '{' => {
+                    let item = sequence.pop().ok_or("nothing to repeat")?.into_item();
+                    let repeated = if c == '{' {
+                        let (min, max) = self.braces()?;
+                        build_repetition(&item, min, max, None)
+                    } else {
+                        format!("{item}{c}")
+                    };
+                    // lazy quantifiers match the same strings
+                    if self.peek() == Some('?') {
+                        self.next();
+                    }
+                    sequence.push(Piece::Repeated(repeated));
+                }
+                '\\' => sequence.push(self.escape()?),
+                '^' | '$' => return Err("anchors are only supported at the ends of the pattern"),
+                c => sequence.push(Piece::Literal(c)),
+            }
+        }
+        alternatives.push(Self::join(sequence));
+        Ok(alternatives.join(" | "))
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}` after the opening brace.
+    fn braces(&mut self) -> Result<(u64, Option<u64>), &'static str> {
+        let mut contents = String::new();
+        loop {
+            match self.next() {
+                Some('}') => break,
+                Some(c) => contents.push(c),
+                None => return Err("unbalanced curly brackets"),
+            }
+        }
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u64>()
+                .map_err(|_| "invalid repetition count")
+        };
+        match contents.split_once(',') {
+            None => {
+                let n = parse(&contents)?;
+                Ok((n, Some(n)))
+            }
+            Some((min, max)) => {
+                let min = if min.trim().is_empty() {
+                    0
+                } else {
+                    parse(min)?
+                };
+                let max = if max.trim().is_empty() {
+                    None
+                } else {
+                    Some(parse(max)?)
+                };
+                if max.is_some_and(|max| max < min) {
+                    return Err("invalid repetition count");
+                }
+                Ok((min, max))
+            }
+        }
+    }
+
+    /// The class for a shorthand such as `\d`, if `c` is one.
+    fn shorthand_class(c: char) -> Option<(&'static str, bool)> {
+        match c {
+            'd' => Some(("0-9", false)),
+            'w' => Some(("a-zA-Z0-9_", false)),
+            's' => Some((r" \t\n\r\f\v", false)),
+            'D' => Some(("0-9", true)),
+            'W' => Some(("a-zA-Z0-9_", true)),
+            'S' => Some((r" \t\n\r\f\v", true)),
+            _ => None,
+        }
+    }
+
+    /// The character for an escape other than a shorthand class.
+    fn escaped_char(c: char) -> Result<char, &'static str> {
+        match c {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'f' => Ok('\u{0C}'),
+            'v' => Ok('\u{0B}'),
+            '0' => Ok('\0'),
+            c if c.is_ascii_alphanumeric() => Err("unsupported escape"),
+            c => Ok(c),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Piece, &'static str> {
+        let c = self.next().ok_or("trailing backslash")?;
+        if let Some((class, negated)) = Self::shorthand_class(c) {
+            let caret = if negated { "^" } else { "" };
+            return Ok(Piece::Item(format!("[{caret}{class}]")));
+        }
+        Self::escaped_char(c).map(Piece::Literal)
+    }
+
+    /// Parse a character class after the opening bracket.
+    fn class(&mut self) -> Result<String, &'static str> {
+        let mut class = String::from("[");
+        if self.peek() == Some('^') {
+            self.next();
+            class.push('^');
+        }
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or("unbalanced square brackets")?;
+            match c {
+                ']' if !first => break,
+                '\\' => {
+                    let escaped = self.next().ok_or("unbalanced square brackets")?;
+                    match Self::shorthand_class(escaped) {
+                        Some((shorthand, false)) => class.push_str(shorthand),
+                        Some((_, true)) => {
+                            return Err("negated shorthands are not supported in classes")
+                        }
+                        None => Self::push_class_char(&mut class, Self::escaped_char(escaped)?),
+                    }
+                }
+                '-' if !first && self.peek().is_some_and(|c| c != ']') => class.push('-'),
+                c => Self::push_class_char(&mut class, c),
+            }
+            first = false;
+        }
+        class.push(']');
+        Ok(class)
+    }
+
+    fn push_class_char(class: &mut String, c: char) {
+        write_escaped(class, u32::from(c), true).expect("writing to a string cannot fail");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::tests::accepts;
+    use serde_json::json;
+
+    fn grammar(schema: &Value) -> LlamaGrammar {
+        LlamaGrammar::from_json_schema(schema).unwrap()
+    }
+
+    fn fixture(name: &str) -> LlamaGrammar {
+        let path = std::path::Path::new("src/grammar").join(name);
+        LlamaGrammar::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn check_object_matches_json_fixture() {
+        let fixture = fixture("json.gbnf");
+        let object = grammar(&json!({ "type": "object" }));
+        for input in [
+            "{}",
+            r#"{"a":1}"#,
+            r#"{"a":[1,-2.5e3,"x\"y",true,false,null],"b":{"c":{}}}"#,
+            r#"{"é":"café"}"#,
+        ] {
+            assert!(accepts(&fixture, input), "fixture rejected {input}");
+            assert!(accepts(&object, input), "schema rejected {input}");
+        }
+        for input in ["", "[]", r#"{"a"}"#, r#"{"a":01}"#, "{,}"] {
+            assert!(!accepts(&fixture, input), "fixture accepted {input}");
+            assert!(!accepts(&object, input), "schema accepted {input}");
+        }
+    }
+
+    #[test]
+    fn check_array_matches_json_arr_fixture() {
+        let fixture = fixture("json_arr.gbnf");
+        let array = grammar(&json!({ "type": "array" }));
+        for input in ["[\n]", "[\n1,\n\"two\",\n{\"three\": [3]}]", "[\n[],\n{}]"] {
+            assert!(accepts(&fixture, input), "fixture rejected {input:?}");
+            assert!(accepts(&array, input), "schema rejected {input:?}");
+        }
+        for input in ["[\n1,\n]", "[\n1 2]", "{}"] {
+            assert!(!accepts(&fixture, input), "fixture accepted {input:?}");
+            assert!(!accepts(&array, input), "schema accepted {input:?}");
+        }
+    }
+
+    #[test]
+    fn check_required_and_optional_properties() {
+        let grammar = grammar(&json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "email": { "type": "string", "format": "date" },
+                "tags": { "type": "array", "items": { "type": "string" }, "maxItems": 2 }
+            },
+            "required": ["name"]
+        }));
+        assert!(accepts(&grammar, r#"{"name":"x"}"#));
+        assert!(accepts(&grammar, r#"{ "name": "x", "age": 3 }"#));
+        assert!(accepts(
+            &grammar,
+            r#"{"name":"x","email":"2024-01-31","tags":["a","b"]}"#
+        ));
+        assert!(!accepts(&grammar, r#"{"age":3}"#));
+        assert!(!accepts(&grammar, r#"{"name":"x","tags":["a","b","c"]}"#));
+        assert!(!accepts(&grammar, r#"{"name":"x","age":3.5}"#));
+        assert!(!accepts(&grammar, r#"{"name":"x","other":1}"#));
+    }
+
+    #[test]
+    fn check_additional_properties() {
+        let grammar = grammar(&json!({
+            "type": "object",
+            "properties": { "a": { "type": "integer" } },
+            "additionalProperties": { "type": "boolean" }
+        }));
+        assert!(accepts(&grammar, r#"{"a":1,"b":true,"c":false}"#));
+        assert!(accepts(&grammar, r#"{"b":true}"#));
+        assert!(!accepts(&grammar, r#"{"b":1}"#));
+    }
+
+    #[test]
+    fn check_enum_const_and_unions() {
+        let grammar = grammar(&json!({
+            "type": "array",
+            "prefixItems": [
+                { "enum": ["red", 1, null] },
+                { "const": { "k": "v" } },
+                { "oneOf": [{ "type": "number" }, { "type": "boolean" }] },
+                { "type": ["string", "null"] }
+            ]
+        }));
+        assert!(accepts(&grammar, r#"["red",{"k":"v"},1.5,"s"]"#));
+        assert!(accepts(&grammar, r#"[null, {"k":"v"}, true, null]"#));
+        assert!(!accepts(&grammar, r#"["blue",{"k":"v"},1.5,"s"]"#));
+        assert!(!accepts(&grammar, r#"[1,{"k":"w"},1.5,"s"]"#));
+        assert!(!accepts(&grammar, r#"[1,{"k":"v"},"x","s"]"#));
+    }
+
+    #[test]
+    fn check_refs() {
+        let grammar = grammar(&json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "integer" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    },
+                    "required": ["value"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        }));
+        assert!(accepts(
+            &grammar,
+            r#"{"value":1,"children":[{"value":2},{"value":3,"children":[]}]}"#
+        ));
+        assert!(!accepts(&grammar, r#"{"value":1,"children":[{}]}"#));
+
+        assert_eq!(
+            json_schema_to_grammar(&json!({ "$ref": "#/$defs/missing" })),
+            Err(JsonSchemaError::UnresolvedRef(
+                "#/$defs/missing".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn check_string_constraints() {
+        let pattern =
+            grammar(&json!({ "type": "string", "pattern": r"^(?:[a-c]\d{2,3}|x-?)+\.$" }));
+        assert!(accepts(&pattern, r#""a12x.""#));
+        assert!(accepts(&pattern, r#""x-b999.""#));
+        assert!(!accepts(&pattern, r#""a1.""#));
+        assert!(!accepts(&pattern, r#""d12.""#));
+
+        let length = grammar(&json!({ "type": "string", "minLength": 2, "maxLength": 3 }));
+        assert!(accepts(&length, r#""ab""#));
+        assert!(accepts(&length, r#""a\nc""#));
+        assert!(!accepts(&length, r#""a""#));
+        assert!(!accepts(&length, r#""abcd""#));
+
+        let uuid = grammar(&json!({ "type": "string", "format": "uuid" }));
+        assert!(accepts(&uuid, r#""123e4567-e89b-12d3-a456-426614174000""#));
+        assert!(!accepts(&uuid, r#""123e4567""#));
+
+        assert!(matches!(
+            json_schema_to_grammar(&json!({ "type": "string", "pattern": "abc" })),
+            Err(JsonSchemaError::InvalidPattern { .. })
+        ));
+    }
+}
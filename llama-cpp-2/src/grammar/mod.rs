@@ -0,0 +1,797 @@
+//! A pure rust GBNF grammar parser.
+//!
+//! This is a translation of the grammar parser in llama.cpp to rust. Parsing a grammar up front
+//! means malformed grammars are reported with a line and column instead of llama.cpp silently
+//! returning a null sampler.
+//!
+//! ```
+//! # use llama_cpp_2::grammar::LlamaGrammar;
+//! # use std::str::FromStr;
+//! let grammar = LlamaGrammar::from_str(r#"root ::= "yes" | "no""#).unwrap();
+//! assert_eq!(grammar.to_string(), "root ::= \"yes\" | \"no\"\n");
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+use llama_cpp_sys_2::{llama_grammar_element, llama_gretype};
+
+pub mod json_schema;
+
+/// The kind of error encountered while parsing a grammar.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GrammarParseErrorKind {
+    /// The input ended in the middle of a rule.
+    #[error("unexpected end of input")]
+    UnexpectedEndOfInput,
+    /// A rule name was expected.
+    #[error("expected a rule name")]
+    ExpectedName,
+    /// A rule name was not followed by `::=`.
+    #[error("expected ::= after rule name {0:?}")]
+    ExpectedDefinition(String),
+    /// A rule was followed by something other than a newline.
+    #[error("expected a newline or end of input after rule {0:?}")]
+    ExpectedNewline(String),
+    /// A group was not closed.
+    #[error("expected ')' to close group in rule {0:?}")]
+    ExpectedClosingParen(String),
+    /// A repetition operator had nothing to repeat.
+    #[error("expected an item before repetition operator in rule {0:?}")]
+    ExpectedItemBeforeRepetition(String),
+    /// A `{m,n}` repetition did not start with an integer.
+    #[error("expected an integer in repetition of rule {0:?}")]
+    ExpectedInteger(String),
+    /// A `{m,n}` repetition was not well formed.
+    #[error("expected ',' or '}}' in repetition of rule {0:?}")]
+    MalformedRepetition(String),
+    /// A `{m,n}` repetition had a maximum lower than its minimum.
+    #[error("repetition maximum {max} is lower than minimum {min}")]
+    InvalidRepetitionRange {
+        /// the minimum number of repetitions
+        min: u32,
+        /// the maximum number of repetitions
+        max: u32,
+    },
+    /// A repetition count did not fit into a u32.
+    #[error("repetition count {0:?} is too large")]
+    RepetitionCountTooLarge(String),
+    /// An unknown escape sequence was found.
+    #[error("unknown escape \\{0}")]
+    UnknownEscape(char),
+    /// A hex escape did not have the expected number of digits.
+    #[error("expected {expected_size} hex digits, found {actual:?}")]
+    IncorrectHexSize {
+        /// the number of digits the escape requires
+        expected_size: usize,
+        /// the digits that were found
+        actual: String,
+    },
+    /// A rule was referenced but never defined.
+    #[error("undefined rule {0:?}")]
+    UndefinedRule(String),
+    /// A rule can reach itself without consuming any input.
+    #[error("left recursion detected in rule {0:?}")]
+    LeftRecursion(String),
+    /// The grammar does not define the root rule.
+    #[error("grammar does not define a {0:?} rule")]
+    MissingRoot(String),
+}
+
+/// There was an error parsing a grammar.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+#[error("{kind} at line {line}, column {column}")]
+#[allow(clippy::module_name_repetitions)]
+pub struct GrammarParseError {
+    /// what went wrong
+    pub kind: GrammarParseErrorKind,
+    /// the line the error occurred on (1-based)
+    pub line: usize,
+    /// the column the error occurred on, in characters (1-based)
+    pub column: usize,
+}
+
+impl GrammarParseError {
+    fn at(src: &str, offset: usize, kind: GrammarParseErrorKind) -> Self {
+        let before = &src[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = before[line_start..].chars().count() + 1;
+        Self { kind, line, column }
+    }
+}
+
+/// A parsed GBNF grammar.
+///
+/// The grammar is validated when it is parsed (every referenced rule is defined, there is no left
+/// recursion and there is a `root` rule), so [`crate::sampling::LlamaSampler::from_grammar`] does
+/// not fail because of the grammar itself. It only fails with
+/// [`crate::LlamaSamplerError::NullReturn`] if llama.cpp cannot create the sampler, for example
+/// when it runs out of memory or rejects a grammar feature this parser accepts.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaGrammar {
+    parse: ParseState,
+}
+
+impl LlamaGrammar {
+    /// The name of the rule generation starts from.
+    pub const ROOT: &'static str = "root";
+
+    /// The rules of the grammar, indexed by symbol id. Each rule is terminated by a
+    /// `LLAMA_GRETYPE_END` element.
+    #[must_use]
+    pub fn rules(&self) -> &[Vec<llama_grammar_element>] {
+        &self.parse.rules
+    }
+
+    /// The symbol id of the rule named `name`, if there is one.
+    #[must_use]
+    pub fn symbol_id(&self, name: &str) -> Option<u32> {
+        self.parse.symbol_ids.get(name).copied()
+    }
+
+    /// The symbol id of the root rule.
+    ///
+    /// # Panics
+    ///
+    /// Never - a [`LlamaGrammar`] cannot be constructed without a root rule.
+    #[must_use]
+    pub fn root_id(&self) -> u32 {
+        self.symbol_id(Self::ROOT)
+            .expect("a parsed grammar always has a root rule")
+    }
+}
+
+impl FromStr for LlamaGrammar {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = parse_with_root(s, Self::ROOT)?;
+        Ok(Self { parse })
+    }
+}
+
+/// Parse `s`, checking that it defines `root`.
+fn parse_with_root(s: &str, root: &str) -> Result<ParseState, GrammarParseError> {
+    let parse = ParseState::from_str(s)?;
+    if !parse.symbol_ids.contains_key(root) {
+        return Err(GrammarParseError::at(
+            s,
+            s.len(),
+            GrammarParseErrorKind::MissingRoot(root.to_string()),
+        ));
+    }
+    Ok(parse)
+}
+
+/// Check that `s` is a valid grammar defining `root`, without keeping the parsed rules.
+pub(crate) fn validate(s: &str, root: &str) -> Result<(), GrammarParseError> {
+    parse_with_root(s, root).map(|_| ())
+}
+
+/// Writes the grammar back out as GBNF. Every rule (including the ones generated for groups and
+/// repetitions) is written on its own line, so parsing the output yields the same rules.
+impl Display for LlamaGrammar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut names = vec![""; self.parse.rules.len()];
+        for (name, &id) in &self.parse.symbol_ids {
+            if let Some(slot) = names.get_mut(id as usize) {
+                *slot = name;
+            }
+        }
+
+        for (id, rule) in self.parse.rules.iter().enumerate() {
+            if rule.is_empty() {
+                continue;
+            }
+            write!(f, "{} ::=", names[id])?;
+            let mut i = 0;
+            let mut alternative_start = 0;
+            while i < rule.len() {
+                let element = rule[i];
+                i += 1;
+                if is_end_of_sequence(element) && i - 1 == alternative_start {
+                    // an empty alternative must be written explicitly, as a trailing `|` would
+                    // continue the rule onto the next line.
+                    f.write_str(" \"\"")?;
+                }
+                match element.type_ {
+                    llama_cpp_sys_2::LLAMA_GRETYPE_END => break,
+                    llama_cpp_sys_2::LLAMA_GRETYPE_ALT => {
+                        f.write_str(" |")?;
+                        alternative_start = i;
+                    }
+                    llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF => {
+                        write!(f, " {}", names[element.value as usize])?;
+                    }
+                    llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ANY => f.write_str(" .")?,
+                    llama_cpp_sys_2::LLAMA_GRETYPE_CHAR if !is_class_part(rule.get(i)) => {
+                        f.write_str(" \"")?;
+                        write_escaped(f, element.value, false)?;
+                        while let Some(next) = rule.get(i) {
+                            if next.type_ != llama_cpp_sys_2::LLAMA_GRETYPE_CHAR
+                                || is_class_part(rule.get(i + 1))
+                            {
+                                break;
+                            }
+                            write_escaped(f, next.value, false)?;
+                            i += 1;
+                        }
+                        f.write_char('"')?;
+                    }
+                    start => {
+                        f.write_str(" [")?;
+                        if start == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_NOT {
+                            f.write_char('^')?;
+                        }
+                        write_escaped(f, element.value, true)?;
+                        while let Some(next) = rule.get(i).filter(|e| is_class_part(Some(e))) {
+                            if next.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER {
+                                f.write_char('-')?;
+                            }
+                            write_escaped(f, next.value, true)?;
+                            i += 1;
+                        }
+                        f.write_char(']')?;
+                    }
+                }
+            }
+            f.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+fn is_class_part(element: Option<&llama_grammar_element>) -> bool {
+    element.is_some_and(|e| {
+        e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ALT
+            || e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER
+    })
+}
+
+fn write_escaped(f: &mut impl Write, value: u32, in_class: bool) -> std::fmt::Result {
+    match char::from_u32(value) {
+        Some('\t') => f.write_str("\\t"),
+        Some('\n') => f.write_str("\\n"),
+        Some('\r') => f.write_str("\\r"),
+        Some(c @ ('\\' | '"' | '[' | ']')) => write!(f, "\\{c}"),
+        Some(c @ ('-' | '^')) if in_class => write!(f, "\\x{:02X}", u32::from(c)),
+        Some(c) if !c.is_control() => f.write_char(c),
+        _ if value <= 0xFF => write!(f, "\\x{value:02X}"),
+        _ if value <= 0xFFFF => write!(f, "\\u{value:04X}"),
+        _ => write!(f, "\\U{value:08X}"),
+    }
+}
+
+/// The raw output of the parser: the symbol table and the rules indexed by symbol id.
+#[derive(Debug, Clone, PartialEq)]
+struct ParseState {
+    symbol_ids: BTreeMap<String, u32>,
+    rules: Vec<Vec<llama_grammar_element>>,
+}
+
+impl FromStr for ParseState {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            src: s,
+            state: ParseState {
+                symbol_ids: BTreeMap::new(),
+                rules: Vec::new(),
+            },
+            first_reference: BTreeMap::new(),
+            definitions: BTreeMap::new(),
+        };
+        parser.parse()?;
+        Ok(parser.state)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    state: ParseState,
+    /// byte offset of the first reference to each symbol, used for error reporting.
+    first_reference: BTreeMap<u32, usize>,
+    /// byte offset of the definition of each named rule, used for error reporting.
+    definitions: BTreeMap<u32, usize>,
+}
+
+fn is_word_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+impl Parser<'_> {
+    fn error(&self, offset: usize, kind: GrammarParseErrorKind) -> GrammarParseError {
+        GrammarParseError::at(self.src, offset, kind)
+    }
+
+    fn byte(&self, pos: usize) -> Option<u8> {
+        self.src.as_bytes().get(pos).copied()
+    }
+
+    fn get_symbol_id(&mut self, name: &str) -> u32 {
+        let next_id = u32::try_from(self.state.symbol_ids.len()).expect("too many symbols");
+        *self
+            .state
+            .symbol_ids
+            .entry(name.to_string())
+            .or_insert(next_id)
+    }
+
+    fn generate_symbol_id(&mut self, base_name: &str) -> u32 {
+        let next_id = u32::try_from(self.state.symbol_ids.len()).expect("too many symbols");
+        self.state
+            .symbol_ids
+            .insert(format!("{base_name}_{next_id}"), next_id);
+        next_id
+    }
+
+    fn add_rule(&mut self, rule_id: u32, rule: Vec<llama_grammar_element>) {
+        let rule_id = rule_id as usize;
+        if self.state.rules.len() <= rule_id {
+            self.state.rules.resize(rule_id + 1, Vec::new());
+        }
+        self.state.rules[rule_id] = rule;
+    }
+
+    fn parse_space(&self, mut pos: usize, newline_ok: bool) -> usize {
+        while let Some(c) = self.byte(pos) {
+            match c {
+                b' ' | b'\t' => pos += 1,
+                b'\r' | b'\n' if newline_ok => pos += 1,
+                b'#' => {
+                    while self.byte(pos).is_some_and(|c| c != b'\r' && c != b'\n') {
+                        pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        pos
+    }
+
+    fn parse_name(&self, pos: usize) -> Result<usize, GrammarParseError> {
+        let mut end = pos;
+        while self.byte(end).is_some_and(is_word_char) {
+            end += 1;
+        }
+        if end == pos {
+            return Err(self.error(pos, GrammarParseErrorKind::ExpectedName));
+        }
+        Ok(end)
+    }
+
+    fn parse_int(&self, pos: usize, rule_name: &str) -> Result<(u32, usize), GrammarParseError> {
+        let mut end = pos;
+        while self.byte(end).is_some_and(|c| c.is_ascii_digit()) {
+            end += 1;
+        }
+        if end == pos {
+            return Err(self.error(
+                pos,
+                GrammarParseErrorKind::ExpectedInteger(rule_name.to_string()),
+            ));
+        }
+        let digits = &self.src[pos..end];
+        let value = digits.parse().map_err(|_| {
+            self.error(
+                pos,
+                GrammarParseErrorKind::RepetitionCountTooLarge(digits.to_string()),
+            )
+        })?;
+        Ok((value, end))
+    }
+
+    fn parse_hex(&self, pos: usize, size: usize) -> Result<(u32, usize), GrammarParseError> {
+        let digits: String = self.src[pos..]
+            .chars()
+            .take(size)
+            .take_while(char::is_ascii_hexdigit)
+            .collect();
+        if digits.len() != size {
+            return Err(self.error(
+                pos,
+                GrammarParseErrorKind::IncorrectHexSize {
+                    expected_size: size,
+                    actual: digits,
+                },
+            ));
+        }
+        let value = u32::from_str_radix(&digits, 16).expect("validated hex digits");
+        Ok((value, pos + size))
+    }
+
+    fn parse_char(&self, pos: usize) -> Result<(u32, usize), GrammarParseError> {
+        let mut chars = self.src[pos..].chars();
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('x') => self.parse_hex(pos + 2, 2),
+                Some('u') => self.parse_hex(pos + 2, 4),
+                Some('U') => self.parse_hex(pos + 2, 8),
+                Some('t') => Ok((u32::from('\t'), pos + 2)),
+                Some('r') => Ok((u32::from('\r'), pos + 2)),
+                Some('n') => Ok((u32::from('\n'), pos + 2)),
+                Some(c @ ('\\' | '"' | '[' | ']')) => Ok((u32::from(c), pos + 2)),
+                Some(c) => Err(self.error(pos, GrammarParseErrorKind::UnknownEscape(c))),
+                None => Err(self.error(pos + 1, GrammarParseErrorKind::UnexpectedEndOfInput)),
+            },
+            Some(c) => Ok((u32::from(c), pos + c.len_utf8())),
+            None => Err(self.error(pos, GrammarParseErrorKind::UnexpectedEndOfInput)),
+        }
+    }
+
+    fn parse(&mut self) -> Result<(), GrammarParseError> {
+        let mut pos = self.parse_space(0, true);
+        while pos < self.src.len() {
+            pos = self.parse_rule(pos)?;
+        }
+        self.validate()
+    }
+
+    fn parse_rule(&mut self, start: usize) -> Result<usize, GrammarParseError> {
+        let name_end = self.parse_name(start)?;
+        let name = &self.src[start..name_end];
+        let mut pos = self.parse_space(name_end, false);
+        let rule_id = self.get_symbol_id(name);
+        self.definitions.entry(rule_id).or_insert(start);
+
+        if !self.src[pos..].starts_with("::=") {
+            return Err(self.error(
+                pos,
+                GrammarParseErrorKind::ExpectedDefinition(name.to_string()),
+            ));
+        }
+        pos = self.parse_space(pos + 3, true);
+        pos = self.parse_alternates(pos, name, rule_id, false)?;
+
+        match self.byte(pos) {
+            Some(b'\r') if self.byte(pos + 1) == Some(b'\n') => pos += 2,
+            Some(b'\r' | b'\n') => pos += 1,
+            None => {}
+            Some(_) => {
+                return Err(self.error(
+                    pos,
+                    GrammarParseErrorKind::ExpectedNewline(name.to_string()),
+                ))
+            }
+        }
+        Ok(self.parse_space(pos, true))
+    }
+
+    fn parse_alternates(
+        &mut self,
+        pos: usize,
+        rule_name: &str,
+        rule_id: u32,
+        is_nested: bool,
+    ) -> Result<usize, GrammarParseError> {
+        let mut rule = Vec::new();
+        let mut pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        while self.byte(pos) == Some(b'|') {
+            rule.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_ALT, 0));
+            pos = self.parse_space(pos + 1, true);
+            pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        }
+        rule.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_END, 0));
+        self.add_rule(rule_id, rule);
+        Ok(pos)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn parse_sequence(
+        &mut self,
+        mut pos: usize,
+        rule_name: &str,
+        out: &mut Vec<llama_grammar_element>,
+        is_nested: bool,
+    ) -> Result<usize, GrammarParseError> {
+        let mut last_sym_start = out.len();
+
+        while let Some(c) = self.byte(pos) {
+            match c {
+                b'"' => {
+                    pos += 1;
+                    last_sym_start = out.len();
+                    while self.byte(pos) != Some(b'"') {
+                        let (value, next) = self.parse_char(pos)?;
+                        out.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_CHAR, value));
+                        pos = next;
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'[' => {
+                    pos += 1;
+                    let mut start_type = llama_cpp_sys_2::LLAMA_GRETYPE_CHAR;
+                    if self.byte(pos) == Some(b'^') {
+                        pos += 1;
+                        start_type = llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_NOT;
+                    }
+                    last_sym_start = out.len();
+                    while self.byte(pos) != Some(b']') {
+                        let (value, next) = self.parse_char(pos)?;
+                        pos = next;
+                        let type_ = if last_sym_start < out.len() {
+                            llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ALT
+                        } else {
+                            start_type
+                        };
+                        out.push(element(type_, value));
+                        if self.byte(pos) == Some(b'-') && self.byte(pos + 1) != Some(b']') {
+                            let (upper, next) = self.parse_char(pos + 1)?;
+                            out.push(element(
+                                llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER,
+                                upper,
+                            ));
+                            pos = next;
+                        }
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                c if is_word_char(c) => {
+                    let name_end = self.parse_name(pos)?;
+                    let ref_rule_id = self.get_symbol_id(&self.src[pos..name_end]);
+                    self.first_reference.entry(ref_rule_id).or_insert(pos);
+                    pos = self.parse_space(name_end, is_nested);
+                    last_sym_start = out.len();
+                    out.push(element(
+                        llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
+                        ref_rule_id,
+                    ));
+                }
+                b'(' => {
+                    let open = pos;
+                    pos = self.parse_space(pos + 1, true);
+                    let sub_rule_id = self.generate_symbol_id(rule_name);
+                    pos = self.parse_alternates(pos, rule_name, sub_rule_id, true)?;
+                    last_sym_start = out.len();
+                    out.push(element(
+                        llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
+                        sub_rule_id,
+                    ));
+                    if self.byte(pos) != Some(b')') {
+                        let offset = if pos < self.src.len() { pos } else { open };
+                        return Err(self.error(
+                            offset,
+                            GrammarParseErrorKind::ExpectedClosingParen(rule_name.to_string()),
+                        ));
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'.' => {
+                    last_sym_start = out.len();
+                    out.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ANY, 0));
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'*' | b'+' | b'?' => {
+                    let (min, max) = match c {
+                        b'*' => (0, None),
+                        b'+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    self.handle_repetitions(pos, rule_name, out, last_sym_start, min, max)?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'{' => {
+                    let open = pos;
+                    pos = self.parse_space(pos + 1, is_nested);
+                    let (min, int_end) = self.parse_int(pos, rule_name)?;
+                    pos = self.parse_space(int_end, is_nested);
+                    let max = match self.byte(pos) {
+                        Some(b'}') => Some(min),
+                        Some(b',') => {
+                            pos = self.parse_space(pos + 1, is_nested);
+                            let mut max = None;
+                            if self.byte(pos).is_some_and(|c| c.is_ascii_digit()) {
+                                let (value, int_end) = self.parse_int(pos, rule_name)?;
+                                max = Some(value);
+                                pos = self.parse_space(int_end, is_nested);
+                            }
+                            if self.byte(pos) != Some(b'}') {
+                                return Err(self.error(
+                                    pos,
+                                    GrammarParseErrorKind::MalformedRepetition(
+                                        rule_name.to_string(),
+                                    ),
+                                ));
+                            }
+                            max
+                        }
+                        _ => {
+                            return Err(self.error(
+                                pos,
+                                GrammarParseErrorKind::MalformedRepetition(rule_name.to_string()),
+                            ))
+                        }
+                    };
+                    if let Some(max) = max.filter(|&max| max < min) {
+                        return Err(self.error(
+                            open,
+                            GrammarParseErrorKind::InvalidRepetitionRange { min, max },
+                        ));
+                    }
+                    self.handle_repetitions(open, rule_name, out, last_sym_start, min, max)?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                _ => break,
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Rewrites the last symbol in `out` according to the repetition rules used by llama.cpp:
+    ///
+    /// ```text
+    /// S{m,n} --> S S S (m times) S'(n-m)
+    ///            S'(n-m) ::= S S'(n-m-1) |
+    ///            (... n-m definitions of these S' rules ...)
+    ///            S'(1) ::= S |
+    /// S{m,} -->  S S S (m times) S'
+    ///            S' ::= S S' |
+    /// ```
+    fn handle_repetitions(
+        &mut self,
+        pos: usize,
+        rule_name: &str,
+        out: &mut Vec<llama_grammar_element>,
+        last_sym_start: usize,
+        min_times: u32,
+        max_times: Option<u32>,
+    ) -> Result<(), GrammarParseError> {
+        if last_sym_start == out.len() {
+            return Err(self.error(
+                pos,
+                GrammarParseErrorKind::ExpectedItemBeforeRepetition(rule_name.to_string()),
+            ));
+        }
+
+        let prev_rule = out[last_sym_start..].to_vec();
+        if min_times == 0 {
+            out.truncate(last_sym_start);
+        } else {
+            for _ in 1..min_times {
+                out.extend_from_slice(&prev_rule);
+            }
+        }
+
+        let n_opt = max_times.map_or(1, |max_times| max_times - min_times);
+        let mut last_rec_rule_id = 0;
+        for i in 0..n_opt {
+            let mut rec_rule = prev_rule.clone();
+            let rec_rule_id = self.generate_symbol_id(rule_name);
+            if i > 0 || max_times.is_none() {
+                let target = if max_times.is_none() {
+                    rec_rule_id
+                } else {
+                    last_rec_rule_id
+                };
+                rec_rule.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF, target));
+            }
+            rec_rule.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_ALT, 0));
+            rec_rule.push(element(llama_cpp_sys_2::LLAMA_GRETYPE_END, 0));
+            self.add_rule(rec_rule_id, rec_rule);
+            last_rec_rule_id = rec_rule_id;
+        }
+        if n_opt > 0 {
+            out.push(element(
+                llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
+                last_rec_rule_id,
+            ));
+        }
+        Ok(())
+    }
+
+    fn name_of(&self, id: u32) -> String {
+        self.state
+            .symbol_ids
+            .iter()
+            .find(|(_, &v)| v == id)
+            .map(|(k, _)| k.clone())
+            .unwrap_or_default()
+    }
+
+    /// Ensures every referenced rule is defined and that no rule is left recursive, as llama.cpp
+    /// refuses to build a sampler from such grammars.
+    fn validate(&self) -> Result<(), GrammarParseError> {
+        for (&id, &offset) in &self.first_reference {
+            let defined = self
+                .state
+                .rules
+                .get(id as usize)
+                .is_some_and(|rule| !rule.is_empty());
+            if !defined {
+                return Err(self.error(
+                    offset,
+                    GrammarParseErrorKind::UndefinedRule(self.name_of(id)),
+                ));
+            }
+        }
+
+        let n_rules = self.state.rules.len();
+        let mut visited = vec![false; n_rules];
+        let mut in_progress = vec![false; n_rules];
+        let mut may_be_empty = vec![false; n_rules];
+        for id in 0..n_rules {
+            if visited[id] {
+                continue;
+            }
+            if let Some(recursive) =
+                self.detect_left_recursion(id, &mut visited, &mut in_progress, &mut may_be_empty)
+            {
+                let offset = self
+                    .definitions
+                    .get(&recursive)
+                    .or_else(|| self.first_reference.get(&recursive))
+                    .copied()
+                    .unwrap_or_default();
+                return Err(self.error(
+                    offset,
+                    GrammarParseErrorKind::LeftRecursion(self.name_of(recursive)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn detect_left_recursion(
+        &self,
+        rule_index: usize,
+        visited: &mut [bool],
+        in_progress: &mut [bool],
+        may_be_empty: &mut [bool],
+    ) -> Option<u32> {
+        if in_progress[rule_index] {
+            return Some(u32::try_from(rule_index).expect("rule index fits into a u32"));
+        }
+        in_progress[rule_index] = true;
+        let rule = &self.state.rules[rule_index];
+
+        let mut at_rule_start = true;
+        for e in rule {
+            if is_end_of_sequence(*e) {
+                if at_rule_start {
+                    may_be_empty[rule_index] = true;
+                    break;
+                }
+                at_rule_start = true;
+            } else {
+                at_rule_start = false;
+            }
+        }
+
+        let mut recurse_into_nonterminal = true;
+        for e in rule {
+            if e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF && recurse_into_nonterminal {
+                let referenced = e.value as usize;
+                if let Some(recursive) =
+                    self.detect_left_recursion(referenced, visited, in_progress, may_be_empty)
+                {
+                    return Some(recursive);
+                }
+                if !may_be_empty[referenced] {
+                    recurse_into_nonterminal = false;
+                }
+            } else {
+                recurse_into_nonterminal = is_end_of_sequence(*e);
+            }
+        }
+
+        in_progress[rule_index] = false;
+        visited[rule_index] = true;
+        None
+    }
+}
+
+fn is_end_of_sequence(e: llama_grammar_element) -> bool {
+    e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_END || e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_ALT
+}
+
+fn element(type_: llama_gretype, value: u32) -> llama_grammar_element {
+    llama_grammar_element { type_, value }
+}
+
+#[cfg(test)]
+mod tests;
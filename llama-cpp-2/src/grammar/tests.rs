@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
@@ -88,3 +89,150 @@ fn check_parse_char_range() {
         parse_state
     );
 }
+
+#[test]
+fn check_display_round_trips() {
+    for entry in std::fs::read_dir("src/grammar").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().unwrap_or_default() != "gbnf" {
+            continue;
+        }
+        let grammar = LlamaGrammar::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let rendered = grammar.to_string();
+        let reparsed = LlamaGrammar::from_str(&rendered)
+            .unwrap_or_else(|e| panic!("{} did not re-parse: {e}\n{rendered}", path.display()));
+
+        // symbol ids are renumbered, but every rule keeps its name and definition
+        let mut before = rendered.lines().collect::<Vec<_>>();
+        let after = reparsed.to_string();
+        let mut after = after.lines().collect::<Vec<_>>();
+        before.sort_unstable();
+        after.sort_unstable();
+        assert_eq!(before, after, "{}", path.display());
+    }
+}
+
+#[test]
+fn check_repetition() {
+    let grammar = LlamaGrammar::from_str(r#"root ::= "a"{2,3}"#).unwrap();
+    assert_eq!(
+        grammar.to_string(),
+        "root ::= \"aa\" root_1\nroot_1 ::= \"a\" | \"\"\n"
+    );
+}
+
+#[test]
+fn check_error_location() {
+    let err = LlamaGrammar::from_str("root ::= item\nitem ::= \"a\" other\n").unwrap_err();
+    assert_eq!(
+        err,
+        GrammarParseError {
+            kind: GrammarParseErrorKind::UndefinedRule("other".to_string()),
+            line: 2,
+            column: 14,
+        }
+    );
+
+    let err = LlamaGrammar::from_str("root ::= [a-z\n").unwrap_err();
+    assert_eq!(err.kind, GrammarParseErrorKind::UnexpectedEndOfInput);
+
+    let err = LlamaGrammar::from_str("root ::= \"\\q\"").unwrap_err();
+    assert_eq!(
+        (err.kind, err.line, err.column),
+        (GrammarParseErrorKind::UnknownEscape('q'), 1, 11)
+    );
+}
+
+#[test]
+fn check_left_recursion() {
+    let err =
+        LlamaGrammar::from_str("root ::= expr\nexpr ::= expr \"+\" expr | \"1\"").unwrap_err();
+    assert_eq!(
+        err.kind,
+        GrammarParseErrorKind::LeftRecursion("expr".to_string())
+    );
+    assert_eq!(err.line, 2);
+}
+
+#[test]
+fn check_missing_root() {
+    let err = LlamaGrammar::from_str(r#"item ::= "a""#).unwrap_err();
+    assert_eq!(
+        err.kind,
+        GrammarParseErrorKind::MissingRoot("root".to_string())
+    );
+}
+
+/// Whether `grammar` matches all of `input`. Used to check generated grammars.
+pub(super) fn accepts(grammar: &LlamaGrammar, input: &str) -> bool {
+    let input = input.chars().collect::<Vec<_>>();
+    let mut memo = std::collections::HashMap::new();
+    match_rule(grammar.rules(), grammar.root_id(), 0, &input, &mut memo).contains(&input.len())
+}
+
+/// Every position a match of `rule` starting at `start` can end at.
+fn match_rule(
+    rules: &[Vec<llama_grammar_element>],
+    rule: u32,
+    start: usize,
+    input: &[char],
+    memo: &mut std::collections::HashMap<(u32, usize), BTreeSet<usize>>,
+) -> BTreeSet<usize> {
+    if let Some(ends) = memo.get(&(rule, start)) {
+        return ends.clone();
+    }
+    let elements = &rules[rule as usize];
+    let mut ends = BTreeSet::new();
+    let mut positions = BTreeSet::from([start]);
+    let mut i = 0;
+    while i < elements.len() {
+        let e = elements[i];
+        match e.type_ {
+            llama_cpp_sys_2::LLAMA_GRETYPE_END | llama_cpp_sys_2::LLAMA_GRETYPE_ALT => {
+                ends.append(&mut positions);
+                positions.insert(start);
+                i += 1;
+            }
+            llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF => {
+                positions = positions
+                    .into_iter()
+                    .flat_map(|p| match_rule(rules, e.value, p, input, memo))
+                    .collect();
+                i += 1;
+            }
+            llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ANY => {
+                positions = positions
+                    .into_iter()
+                    .filter(|&p| p < input.len())
+                    .map(|p| p + 1)
+                    .collect();
+                i += 1;
+            }
+            _ => {
+                let negated = e.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_NOT;
+                let mut ranges = vec![(e.value, e.value)];
+                i += 1;
+                while let Some(part) = elements.get(i).filter(|p| is_class_part(Some(p))) {
+                    if part.type_ == llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER {
+                        ranges.last_mut().unwrap().1 = part.value;
+                    } else {
+                        ranges.push((part.value, part.value));
+                    }
+                    i += 1;
+                }
+                positions = positions
+                    .into_iter()
+                    .filter(|&p| {
+                        input.get(p).is_some_and(|&c| {
+                            let c = u32::from(c);
+                            ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated
+                        })
+                    })
+                    .map(|p| p + 1)
+                    .collect();
+            }
+        }
+    }
+    memo.insert((rule, start), ends.clone());
+    ends
+}
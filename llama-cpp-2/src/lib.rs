@@ -17,16 +17,24 @@ use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
 
+use crate::grammar::GrammarParseError;
 use crate::llama_batch::BatchAddError;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod beam_search;
+pub mod completion;
 pub mod context;
+pub mod gguf;
+pub mod grammar;
 pub mod llama_backend;
 pub mod llama_batch;
+pub mod log;
 pub mod model;
 pub mod sampling;
+pub mod scheduler;
+pub mod speculative;
 pub mod timing;
 pub mod token;
 pub mod token_type;
@@ -62,7 +70,9 @@ pub enum LLamaCppError {
     /// see [`EmbeddingsError`]
     #[error(transparent)]
     EmbeddingError(#[from] EmbeddingsError),
-    // See [`LlamaSamplerError`]
+    /// See [`LlamaSamplerError`]
+    #[error(transparent)]
+    LlamaSamplerError(#[from] LlamaSamplerError),
 }
 
 /// There was an error while getting the chat template from a model.
@@ -96,6 +106,9 @@ pub enum DecodeError {
     /// The number of tokens in the batch was 0.
     #[error("Decode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The computation was aborted by the abort callback of the context.
+    #[error("Decode Error 2: aborted")]
+    Aborted,
     /// An unknown error occurred.
     #[error("Decode Error {0}: unknown")]
     Unknown(c_int),
@@ -129,12 +142,35 @@ pub enum EmbeddingsError {
     NonePoolType,
 }
 
+/// Failed to create a sampler.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum LlamaSamplerError {
+    /// There was a null byte in a provided string and thus it could not be converted to a C string.
+    #[error("null byte in string {0}")]
+    NullError(#[from] NulError),
+    /// The grammar passed to a grammar sampler was invalid.
+    #[error("invalid grammar: {0}")]
+    GrammarParseError(#[from] GrammarParseError),
+    /// A parameter was outside the range the sampler supports.
+    #[error("invalid value for {name}: {reason}")]
+    InvalidParameter {
+        /// the name of the parameter
+        name: &'static str,
+        /// why the value was rejected
+        reason: &'static str,
+    },
+    /// llama.cpp returned a null sampler.
+    #[error("null reference from llama.cpp")]
+    NullReturn,
+}
+
 /// Decode a error from llama.cpp into a [`DecodeError`].
 impl From<NonZeroI32> for DecodeError {
     fn from(value: NonZeroI32) -> Self {
         match value.get() {
             1 => DecodeError::NoKvCacheSlot,
             -1 => DecodeError::NTokensZero,
+            2 => DecodeError::Aborted,
             i => DecodeError::Unknown(i),
         }
     }
@@ -160,6 +196,9 @@ pub enum LlamaModelLoadError {
     /// llama.cpp returned a nullptr - this could be many different causes.
     #[error("null result from llama cpp")]
     NullResult,
+    /// The progress callback cancelled loading the model.
+    #[error("loading the model was cancelled by the progress callback")]
+    Cancelled,
     /// Failed to convert the path to a rust str. This means the path was not valid unicode
     #[error("failed to convert path {0} to str")]
     PathToStrError(PathBuf),
@@ -195,6 +234,28 @@ pub enum LlamaLoraAdapterRemoveError {
     ErrorResult(i32),
 }
 
+/// Failed to read the metadata of a model.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum MetaValError {
+    /// There was a null byte in the key.
+    #[error("null byte in string {0}")]
+    NullError(#[from] NulError),
+    /// The value was not valid utf8.
+    #[error("FromUtf8Error {0}")]
+    FromUtf8Error(#[from] FromUtf8Error),
+    /// llama.cpp returned a negative value, most likely because the key or index does not exist.
+    #[error("negative return value {0}, the key or index is likely missing")]
+    NegativeReturn(i32),
+    /// The value could not be parsed into the requested type.
+    #[error("failed to parse the value {value:?} of {key}")]
+    ParseError {
+        /// the key of the value
+        key: String,
+        /// the value as a string
+        value: String,
+    },
+}
+
 /// get the time (in microseconds) according to llama.cpp
 /// ```
 /// # use llama_cpp_2::llama_time_us;
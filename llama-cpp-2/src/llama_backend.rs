@@ -1,5 +1,6 @@
 //! Representation of an initialized llama backend
 
+use crate::log::LogOptions;
 use crate::LLamaCppError;
 use llama_cpp_sys_2::ggml_log_level;
 use std::sync::atomic::AtomicBool;
@@ -83,6 +84,23 @@ impl LlamaBackend {
             llama_cpp_sys_2::llama_log_set(Some(void_log), std::ptr::null_mut());
         }
     }
+
+    /// Send the logs of llama.cpp and ggml to [`tracing`] instead of `stderr`. Messages are
+    /// logged with the targets `llama.cpp` and `ggml`, one event per line.
+    ///
+    /// ```
+    ///# use llama_cpp_2::llama_backend::LlamaBackend;
+    ///# use llama_cpp_2::log::{LogModule, LogOptions};
+    ///# use std::error::Error;
+    ///# fn main() -> Result<(), Box<dyn Error>> {
+    /// let mut backend = LlamaBackend::init()?;
+    /// backend.send_logs_to_tracing(LogOptions::default().with_module(LogModule::Ggml, false));
+    ///# Ok(())
+    ///# }
+    /// ```
+    pub fn send_logs_to_tracing(&mut self, options: LogOptions) {
+        crate::log::send_logs_to_tracing(options);
+    }
 }
 
 /// A rusty wrapper around `numa_strategy`.
@@ -0,0 +1,227 @@
+//! Forward the logs of llama.cpp and ggml to [`tracing`].
+//!
+//! See [`crate::llama_backend::LlamaBackend::send_logs_to_tracing`].
+
+use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use llama_cpp_sys_2::ggml_log_level;
+use tracing::Level;
+
+/// The library a log message comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::module_name_repetitions)]
+pub enum LogModule {
+    /// llama.cpp itself, logged with the target `llama.cpp`.
+    Llama,
+    /// the ggml tensor library, logged with the target `ggml`.
+    Ggml,
+}
+
+/// Options for [`crate::llama_backend::LlamaBackend::send_logs_to_tracing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LogOptions {
+    llama: bool,
+    ggml: bool,
+}
+
+impl Default for LogOptions {
+    /// Forward the logs of all modules.
+    fn default() -> Self {
+        Self {
+            llama: true,
+            ggml: true,
+        }
+    }
+}
+
+impl LogOptions {
+    /// Enable or disable the logs of all modules. Disabled logs are dropped rather than written
+    /// to `stderr`.
+    #[must_use]
+    pub fn with_logs_enabled(self, enabled: bool) -> Self {
+        Self {
+            llama: enabled,
+            ggml: enabled,
+        }
+    }
+
+    /// Enable or disable the logs of `module`.
+    ///
+    /// ```
+    /// # use llama_cpp_2::log::{LogModule, LogOptions};
+    /// let options = LogOptions::default().with_module(LogModule::Ggml, false);
+    /// assert!(options.is_enabled(LogModule::Llama));
+    /// assert!(!options.is_enabled(LogModule::Ggml));
+    /// ```
+    #[must_use]
+    pub fn with_module(mut self, module: LogModule, enabled: bool) -> Self {
+        match module {
+            LogModule::Llama => self.llama = enabled,
+            LogModule::Ggml => self.ggml = enabled,
+        }
+        self
+    }
+
+    /// Whether the logs of `module` are forwarded.
+    #[must_use]
+    pub fn is_enabled(&self, module: LogModule) -> bool {
+        match module {
+            LogModule::Llama => self.llama,
+            LogModule::Ggml => self.ggml,
+        }
+    }
+}
+
+/// The state of the log callback of one module. llama.cpp may log from several threads, so the
+/// partial line is behind a mutex.
+struct LogState {
+    module: LogModule,
+    enabled: AtomicBool,
+    line: Mutex<LineBuffer>,
+}
+
+impl LogState {
+    const fn new(module: LogModule) -> Self {
+        Self {
+            module,
+            enabled: AtomicBool::new(true),
+            line: Mutex::new(LineBuffer::new()),
+        }
+    }
+}
+
+static LLAMA_STATE: LogState = LogState::new(LogModule::Llama);
+static GGML_STATE: LogState = LogState::new(LogModule::Ggml);
+
+/// Install the log callbacks of llama.cpp and ggml.
+pub(crate) fn send_logs_to_tracing(options: LogOptions) {
+    for state in [&LLAMA_STATE, &GGML_STATE] {
+        state
+            .enabled
+            .store(options.is_enabled(state.module), Ordering::Relaxed);
+    }
+    let user_data = |state: &'static LogState| std::ptr::from_ref(state).cast_mut().cast();
+    unsafe {
+        llama_cpp_sys_2::llama_log_set(Some(log_callback), user_data(&LLAMA_STATE));
+        llama_cpp_sys_2::ggml_log_set(Some(log_callback), user_data(&GGML_STATE));
+    }
+}
+
+unsafe extern "C" fn log_callback(level: ggml_log_level, text: *const c_char, data: *mut c_void) {
+    let state = unsafe { &*data.cast::<LogState>() };
+    if text.is_null() || !state.enabled.load(Ordering::Relaxed) {
+        return;
+    }
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    // a poisoned lock only means a previous log call panicked, the buffer is still usable
+    let lines = state
+        .line
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .push(level, &text);
+    for (level, line) in lines {
+        emit(state.module, level, &line);
+    }
+}
+
+fn emit(module: LogModule, level: Level, line: &str) {
+    macro_rules! emit {
+        ($target:literal) => {
+            match level {
+                Level::ERROR => tracing::error!(target: $target, "{line}"),
+                Level::WARN => tracing::warn!(target: $target, "{line}"),
+                Level::INFO => tracing::info!(target: $target, "{line}"),
+                Level::DEBUG => tracing::debug!(target: $target, "{line}"),
+                Level::TRACE => tracing::trace!(target: $target, "{line}"),
+            }
+        };
+    }
+    match module {
+        LogModule::Llama => emit!("llama.cpp"),
+        LogModule::Ggml => emit!("ggml"),
+    }
+}
+
+/// Joins the fragments llama.cpp logs into whole lines.
+#[derive(Debug)]
+struct LineBuffer {
+    text: String,
+    /// the level of the message that started `text`, also used for continuations
+    level: Level,
+}
+
+impl LineBuffer {
+    const fn new() -> Self {
+        Self {
+            text: String::new(),
+            level: Level::INFO,
+        }
+    }
+
+    /// Add a fragment, returning the lines it completes. A fragment with a new level completes
+    /// the pending partial line.
+    fn push(&mut self, level: ggml_log_level, fragment: &str) -> Vec<(Level, String)> {
+        let mut lines = Vec::new();
+        if let Some(level) = to_tracing_level(level) {
+            if !self.text.is_empty() {
+                lines.push((self.level, std::mem::take(&mut self.text)));
+            }
+            self.level = level;
+        }
+        self.text.push_str(fragment);
+        while let Some(end) = self.text.find('\n') {
+            let line = self.text[..end].to_owned();
+            self.text.drain(..=end);
+            lines.push((self.level, line));
+        }
+        lines
+    }
+}
+
+/// The tracing level of a ggml log level, or `None` for a continuation of the previous message.
+fn to_tracing_level(level: ggml_log_level) -> Option<Level> {
+    match level {
+        llama_cpp_sys_2::GGML_LOG_LEVEL_CONT => None,
+        llama_cpp_sys_2::GGML_LOG_LEVEL_ERROR => Some(Level::ERROR),
+        llama_cpp_sys_2::GGML_LOG_LEVEL_WARN => Some(Level::WARN),
+        llama_cpp_sys_2::GGML_LOG_LEVEL_DEBUG => Some(Level::DEBUG),
+        _ => Some(Level::INFO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llama_cpp_sys_2::{GGML_LOG_LEVEL_CONT, GGML_LOG_LEVEL_INFO, GGML_LOG_LEVEL_WARN};
+
+    #[test]
+    fn fragments_are_joined_into_lines() {
+        let mut buffer = LineBuffer::new();
+        assert!(buffer.push(GGML_LOG_LEVEL_WARN, "loading").is_empty());
+        assert!(buffer.push(GGML_LOG_LEVEL_CONT, ".").is_empty());
+        assert_eq!(
+            buffer.push(GGML_LOG_LEVEL_CONT, ".\nsecond\nthi"),
+            [
+                (Level::WARN, "loading..".to_owned()),
+                (Level::WARN, "second".to_owned())
+            ]
+        );
+        assert_eq!(buffer.text, "thi");
+    }
+
+    #[test]
+    fn a_new_message_completes_the_partial_line() {
+        let mut buffer = LineBuffer::new();
+        assert!(buffer.push(GGML_LOG_LEVEL_WARN, "partial").is_empty());
+        assert_eq!(
+            buffer.push(GGML_LOG_LEVEL_INFO, "next\n"),
+            [
+                (Level::WARN, "partial".to_owned()),
+                (Level::INFO, "next".to_owned())
+            ]
+        );
+    }
+}
@@ -1,9 +1,10 @@
 //! A safe wrapper around `llama_model`.
-use std::ffi::CString;
+use std::ffi::{c_char, CString};
 use std::num::NonZeroU16;
 use std::os::raw::c_int;
 use std::path::Path;
 use std::ptr::NonNull;
+use std::str::FromStr;
 
 use crate::context::params::LlamaContextParams;
 use crate::context::LlamaContext;
@@ -13,7 +14,8 @@ use crate::token::LlamaToken;
 use crate::token_type::{LlamaTokenAttr, LlamaTokenAttrs};
 use crate::{
     ApplyChatTemplateError, ChatTemplateError, LlamaContextLoadError, LlamaLoraAdapterInitError,
-    LlamaModelLoadError, NewLlamaChatMessageError, StringToTokenError, TokenToStringError,
+    LlamaModelLoadError, MetaValError, NewLlamaChatMessageError, StringToTokenError,
+    TokenToStringError,
 };
 
 pub mod params;
@@ -395,6 +397,187 @@ impl LlamaModel {
         unsafe { llama_cpp_sys_2::llama_n_embd(self.model.as_ptr()) }
     }
 
+    /// The number of layers of the model.
+    ///
+    /// # Panics
+    ///
+    /// If llama.cpp returns a negative number of layers.
+    #[must_use]
+    pub fn n_layer(&self) -> u32 {
+        let n_layer = unsafe { llama_cpp_sys_2::llama_n_layer(self.model.as_ptr()) };
+        u32::try_from(n_layer).expect("n_layer fits into an u32")
+    }
+
+    /// The number of attention heads of the model.
+    ///
+    /// # Panics
+    ///
+    /// If llama.cpp returns a negative number of heads.
+    #[must_use]
+    pub fn n_head(&self) -> u32 {
+        let n_head = unsafe { llama_cpp_sys_2::llama_n_head(self.model.as_ptr()) };
+        u32::try_from(n_head).expect("n_head fits into an u32")
+    }
+
+    /// The kind of rotary position embedding the model uses, or `None` if it uses none.
+    #[must_use]
+    pub fn rope_type(&self) -> Option<RopeType> {
+        let rope_type = unsafe { llama_cpp_sys_2::llama_rope_type(self.model.as_ptr()) };
+        match rope_type {
+            llama_cpp_sys_2::LLAMA_ROPE_TYPE_NONE => None,
+            llama_cpp_sys_2::LLAMA_ROPE_TYPE_NORM => Some(RopeType::Norm),
+            llama_cpp_sys_2::LLAMA_ROPE_TYPE_NEOX => Some(RopeType::NeoX),
+            llama_cpp_sys_2::LLAMA_ROPE_TYPE_MROPE => Some(RopeType::MRope),
+            llama_cpp_sys_2::LLAMA_ROPE_TYPE_VISION => Some(RopeType::Vision),
+            unknown => Some(RopeType::Unknown(unknown)),
+        }
+    }
+
+    /// The total size of all tensors of the model in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_size(self.model.as_ptr()) }
+    }
+
+    /// The total number of parameters of the model.
+    #[must_use]
+    pub fn n_params(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_n_params(self.model.as_ptr()) }
+    }
+
+    /// Whether the model has an encoder, which must be run with [`LlamaContext::encode`].
+    #[must_use]
+    pub fn has_encoder(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_model_has_encoder(self.model.as_ptr()) }
+    }
+
+    /// Whether the model has a decoder. Encoder-only models (e.g. BERT) have none.
+    #[must_use]
+    pub fn has_decoder(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_model_has_decoder(self.model.as_ptr()) }
+    }
+
+    /// A short description of the model's architecture, size and quantization, e.g.
+    /// `llama 7B Q4_K - Medium`.
+    ///
+    /// # Errors
+    ///
+    /// If the description is not valid utf8.
+    pub fn desc(&self) -> Result<String, MetaValError> {
+        read_string(|buf, buf_size| unsafe {
+            llama_cpp_sys_2::llama_model_desc(self.model.as_ptr(), buf, buf_size)
+        })
+    }
+
+    /// The number of metadata key-value pairs of the model.
+    #[must_use]
+    pub fn meta_count(&self) -> i32 {
+        unsafe { llama_cpp_sys_2::llama_model_meta_count(self.model.as_ptr()) }
+    }
+
+    /// The metadata key at `index`.
+    ///
+    /// # Errors
+    ///
+    /// - [`MetaValError::NegativeReturn`] if `index` is out of range.
+    /// - [`MetaValError::FromUtf8Error`] if the key is not valid utf8.
+    pub fn meta_key_by_index(&self, index: i32) -> Result<String, MetaValError> {
+        read_string(|buf, buf_size| unsafe {
+            llama_cpp_sys_2::llama_model_meta_key_by_index(
+                self.model.as_ptr(),
+                index,
+                buf,
+                buf_size,
+            )
+        })
+    }
+
+    /// The metadata value at `index`, formatted as a string by llama.cpp.
+    ///
+    /// # Errors
+    ///
+    /// - [`MetaValError::NegativeReturn`] if `index` is out of range.
+    /// - [`MetaValError::FromUtf8Error`] if the value is not valid utf8.
+    pub fn meta_val_str_by_index(&self, index: i32) -> Result<String, MetaValError> {
+        read_string(|buf, buf_size| unsafe {
+            llama_cpp_sys_2::llama_model_meta_val_str_by_index(
+                self.model.as_ptr(),
+                index,
+                buf,
+                buf_size,
+            )
+        })
+    }
+
+    /// All metadata key-value pairs of the model, with values formatted as strings.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// for entry in model.meta() {
+    ///     let (key, value) = entry?;
+    ///     println!("{key} = {value}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn meta(&self) -> impl Iterator<Item = Result<(String, String), MetaValError>> + '_ {
+        (0..self.meta_count()).map(|index| {
+            Ok((
+                self.meta_key_by_index(index)?,
+                self.meta_val_str_by_index(index)?,
+            ))
+        })
+    }
+
+    /// The metadata value of `key`, formatted as a string by llama.cpp.
+    ///
+    /// # Errors
+    ///
+    /// - [`MetaValError::NegativeReturn`] if the model has no value for `key`.
+    /// - [`MetaValError::NullError`] if `key` contains a null byte.
+    /// - [`MetaValError::FromUtf8Error`] if the value is not valid utf8.
+    pub fn meta_val_str(&self, key: &str) -> Result<String, MetaValError> {
+        let key = CString::new(key)?;
+        read_string(|buf, buf_size| unsafe {
+            llama_cpp_sys_2::llama_model_meta_val_str(
+                self.model.as_ptr(),
+                key.as_ptr(),
+                buf,
+                buf_size,
+            )
+        })
+    }
+
+    /// The metadata value of `key`, parsed into `T`.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let architecture = model.meta_val_str("general.architecture")?;
+    /// let context_length: u32 = model.meta_val(&format!("{architecture}.context_length"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - [`MetaValError::ParseError`] if the value cannot be parsed into `T`.
+    /// - see [`LlamaModel::meta_val_str`] for the other errors.
+    pub fn meta_val<T: FromStr>(&self, key: &str) -> Result<T, MetaValError> {
+        let value = self.meta_val_str(key)?;
+        value.parse().map_err(|_| MetaValError::ParseError {
+            key: key.to_owned(),
+            value,
+        })
+    }
+
     /// Get chat template from model.
     ///
     /// # Errors
@@ -453,7 +636,12 @@ impl LlamaModel {
         let llama_model =
             unsafe { llama_cpp_sys_2::llama_load_model_from_file(cstr.as_ptr(), params.params) };
 
-        let model = NonNull::new(llama_model).ok_or(LlamaModelLoadError::NullResult)?;
+        let cancelled = params.take_cancelled();
+        let model = NonNull::new(llama_model).ok_or(if cancelled {
+            LlamaModelLoadError::Cancelled
+        } else {
+            LlamaModelLoadError::NullResult
+        })?;
 
         tracing::debug!(?path, "Loaded model");
         Ok(LlamaModel { model })
@@ -579,6 +767,21 @@ impl LlamaModel {
     }
 }
 
+/// Read a string from a llama.cpp function that writes into a buffer like `snprintf`, retrying
+/// with a larger buffer if the string was truncated.
+fn read_string(mut read: impl FnMut(*mut c_char, usize) -> i32) -> Result<String, MetaValError> {
+    let mut buf = vec![0u8; 256];
+    loop {
+        let len = read(buf.as_mut_ptr().cast(), buf.len());
+        let len = usize::try_from(len).map_err(|_| MetaValError::NegativeReturn(len))?;
+        if len < buf.len() {
+            buf.truncate(len);
+            return Ok(String::from_utf8(buf)?);
+        }
+        buf.resize(len + 1, 0);
+    }
+}
+
 impl Drop for LlamaModel {
     fn drop(&mut self) {
         unsafe { llama_cpp_sys_2::llama_free_model(self.model.as_ptr()) }
@@ -595,6 +798,21 @@ pub enum VocabType {
     SPM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_SPM as _,
 }
 
+/// A rusty equivalent of `llama_rope_type`.
+#[derive(Debug, Eq, Copy, Clone, PartialEq)]
+pub enum RopeType {
+    /// Rotary embeddings of adjacent pairs of dimensions (e.g. llama)
+    Norm,
+    /// Rotary embeddings of the two halves of the dimensions (e.g. falcon, qwen2)
+    NeoX,
+    /// Multimodal rotary embeddings (e.g. qwen2-vl)
+    MRope,
+    /// Rotary embeddings of vision models
+    Vision,
+    /// A rope type added to llama.cpp after this library was written.
+    Unknown(i32),
+}
+
 /// There was an error converting a `llama_vocab_type` to a `VocabType`.
 #[derive(thiserror::Error, Debug, Eq, PartialEq)]
 pub enum LlamaTokenTypeFromIntError {
@@ -614,3 +832,39 @@ impl TryFrom<llama_cpp_sys_2::llama_vocab_type> for VocabType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Behaves like `snprintf(buf, buf_size, "%s", value)`.
+    fn snprintf(value: &str, buf: *mut c_char, buf_size: usize) -> i32 {
+        let n = value.len().min(buf_size.saturating_sub(1));
+        unsafe {
+            std::ptr::copy_nonoverlapping(value.as_ptr(), buf.cast(), n);
+            *buf.add(n) = 0;
+        }
+        i32::try_from(value.len()).unwrap()
+    }
+
+    #[test]
+    fn read_string_grows_the_buffer() {
+        let long = "x".repeat(1000);
+        let mut calls = 0;
+        let read = read_string(|buf, buf_size| {
+            calls += 1;
+            snprintf(&long, buf, buf_size)
+        });
+        assert_eq!(read, Ok(long));
+        assert_eq!(calls, 2);
+
+        assert_eq!(
+            read_string(|buf, size| snprintf("", buf, size)),
+            Ok(String::new())
+        );
+        assert_eq!(
+            read_string(|_, _| -1),
+            Err(MetaValError::NegativeReturn(-1))
+        );
+    }
+}
@@ -1,10 +1,11 @@
 //! A safe wrapper around `llama_model_params`.
 
 use crate::model::params::kv_overrides::KvOverrides;
-use std::ffi::{c_char, CStr};
+use std::ffi::{c_char, c_void, CStr};
 use std::fmt::{Debug, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
-use std::ptr::null;
+use std::ptr::{null, NonNull};
 
 pub mod kv_overrides;
 
@@ -13,6 +14,22 @@ pub mod kv_overrides;
 pub struct LlamaModelParams {
     pub(crate) params: llama_cpp_sys_2::llama_model_params,
     kv_overrides: Vec<llama_cpp_sys_2::llama_model_kv_override>,
+    /// owned, freed on drop. A raw pointer as llama.cpp mutates it through `params` while the
+    /// params are only borrowed.
+    progress_callback: Option<NonNull<ProgressCallback>>,
+}
+
+/// A progress callback along with whether it cancelled the last load.
+struct ProgressCallback {
+    callback: Box<dyn FnMut(f32) -> bool>,
+    cancelled: bool,
+}
+
+unsafe extern "C" fn call_progress_callback(progress: f32, data: *mut c_void) -> bool {
+    let state = unsafe { &mut *data.cast::<ProgressCallback>() };
+    let proceed = catch_unwind(AssertUnwindSafe(|| (state.callback)(progress))).unwrap_or(false);
+    state.cancelled = !proceed;
+    proceed
 }
 
 impl Debug for LlamaModelParams {
@@ -24,6 +41,7 @@ impl Debug for LlamaModelParams {
             .field("use_mmap", &self.params.use_mmap)
             .field("use_mlock", &self.params.use_mlock)
             .field("kv_overrides", &"vec of kv_overrides")
+            .field("progress_callback", &self.progress_callback.is_some())
             .finish()
     }
 }
@@ -174,6 +192,57 @@ impl LlamaModelParams {
         self.params.use_mlock = use_mlock;
         self
     }
+
+    /// Set a callback that is called with the loading progress, from `0.0` to `1.0`. Returning
+    /// `false` cancels the load, and [`crate::model::LlamaModel::load_from_file`] returns
+    /// [`crate::LlamaModelLoadError::Cancelled`]. A panic in the callback also cancels the load.
+    ///
+    /// ```
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// let shutting_down = Arc::new(AtomicBool::new(false));
+    /// let flag = Arc::clone(&shutting_down);
+    /// let params = LlamaModelParams::default().with_progress_callback(move |progress| {
+    ///     println!("loading: {:.0}%", progress * 100.0);
+    ///     !flag.load(Ordering::Relaxed)
+    /// });
+    /// ```
+    #[must_use]
+    pub fn with_progress_callback(mut self, callback: impl FnMut(f32) -> bool + 'static) -> Self {
+        self.free_progress_callback();
+        let state = Box::new(ProgressCallback {
+            callback: Box::new(callback),
+            cancelled: false,
+        });
+        let state = NonNull::from(Box::leak(state));
+        self.params.progress_callback = Some(call_progress_callback);
+        self.params.progress_callback_user_data = state.as_ptr().cast();
+        self.progress_callback = Some(state);
+        self
+    }
+
+    /// Whether the progress callback cancelled the last load, resetting the flag.
+    pub(crate) fn take_cancelled(&self) -> bool {
+        self.progress_callback.is_some_and(|state| {
+            // no reference to the state exists outside of the progress callback
+            unsafe { std::mem::replace(&mut (*state.as_ptr()).cancelled, false) }
+        })
+    }
+
+    fn free_progress_callback(&mut self) {
+        if let Some(state) = self.progress_callback.take() {
+            self.params.progress_callback = None;
+            self.params.progress_callback_user_data = std::ptr::null_mut();
+            drop(unsafe { Box::from_raw(state.as_ptr()) });
+        }
+    }
+}
+
+impl Drop for LlamaModelParams {
+    fn drop(&mut self) {
+        self.free_progress_callback();
+    }
 }
 
 /// Default parameters for `LlamaModel`. (as defined in llama.cpp by `llama_model_default_params`)
@@ -202,6 +271,7 @@ impl Default for LlamaModelParams {
                     val_i64: 0,
                 },
             }],
+            progress_callback: None,
         }
     }
 }
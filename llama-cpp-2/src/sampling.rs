@@ -1,13 +1,22 @@
 //! Safe wrapper around `llama_sampler`.
 
 use std::borrow::Borrow;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::OnceLock;
 
 use crate::context::LlamaContext;
-use crate::model::LlamaModel;
+use crate::grammar::{self, LlamaGrammar};
+use crate::model::{AddBos, LlamaModel};
 use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::logprobs::TokenLogprobs;
 use crate::token::LlamaToken;
+use crate::{LlamaSamplerError, StringToTokenError};
+
+pub mod custom;
 
 /// A safe wrapper around `llama_sampler`.
 pub struct LlamaSampler {
@@ -30,6 +39,24 @@ type CChar = i8;
 
 
 impl LlamaSampler {
+    /// Wraps a sampler returned by llama.cpp, failing if it is null.
+    fn from_raw(sampler: *mut llama_cpp_sys_2::llama_sampler) -> Result<Self, LlamaSamplerError> {
+        if sampler.is_null() {
+            Err(LlamaSamplerError::NullReturn)
+        } else {
+            Ok(Self { sampler })
+        }
+    }
+
+    /// Creates an independent copy of the sampler, including its current state.
+    ///
+    /// # Errors
+    /// [`LlamaSamplerError::NullReturn`] if llama.cpp cannot clone the sampler (it has state but
+    /// no `clone` callback).
+    pub fn try_clone(&self) -> Result<Self, LlamaSamplerError> {
+        Self::from_raw(unsafe { llama_cpp_sys_2::llama_sampler_clone(self.sampler) })
+    }
+
     /// Sample and accept a token from the idx-th output of the last evaluation
     #[must_use]
     pub fn sample(&mut self, ctx: &LlamaContext, idx: i32) -> LlamaToken {
@@ -40,6 +67,24 @@ impl LlamaSampler {
         LlamaToken(token)
     }
 
+    /// Same as [`Self::sample`], but also returns the log-probability of the sampled token and of
+    /// the ``n_top`` most likely tokens, computed from the model's raw logits.
+    ///
+    /// # Panics
+    ///
+    /// - the logits for `idx` are not initialized (see [`LlamaContext::get_logits_ith`]).
+    #[must_use]
+    pub fn sample_with_logprobs(
+        &mut self,
+        ctx: &LlamaContext,
+        idx: i32,
+        n_top: usize,
+    ) -> (LlamaToken, TokenLogprobs) {
+        let token = self.sample(ctx, idx);
+        let logprobs = TokenLogprobs::from_logits(ctx.get_logits_ith(idx), token, n_top);
+        (token, logprobs)
+    }
+
     /// Applies this sampler to a [`LlamaTokenDataArray`].
     pub fn apply(&self, data_array: &mut LlamaTokenDataArray) {
         data_array.apply_sampler(self);
@@ -70,6 +115,29 @@ impl LlamaSampler {
         self
     }
 
+    /// Resets the internal state of the sampler (e.g. the tokens seen by repetition penalties or
+    /// the position in a grammar), as if it had just been created.
+    pub fn reset(&mut self) {
+        unsafe { llama_cpp_sys_2::llama_sampler_reset(self.sampler) }
+    }
+
+    /// The name llama.cpp gives this sampler, e.g. `"top-k"` or `"chain"`.
+    #[must_use]
+    pub fn name(&self) -> String {
+        let name = unsafe { CStr::from_ptr(llama_cpp_sys_2::llama_sampler_name(self.sampler)) };
+        name.to_string_lossy().into_owned()
+    }
+
+    /// The seed used by this sampler. If it was created with [`llama_cpp_sys_2::LLAMA_DEFAULT_SEED`] this is the
+    /// random seed that was picked instead. For a chain this is the seed of the last sampler that
+    /// has one, as llama.cpp searches the chain from the end.
+    ///
+    /// Returns [`llama_cpp_sys_2::LLAMA_DEFAULT_SEED`] if the sampler does not use a seed.
+    #[must_use]
+    pub fn get_seed(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_sampler_get_seed(self.sampler) }
+    }
+
     /// Combines a list of samplers into a single sampler that applies each component sampler one
     /// after another.
     ///
@@ -129,6 +197,91 @@ impl LlamaSampler {
         Self::chain(samplers, false)
     }
 
+    /// Whether this sampler is a chain created by llama.cpp. The name cannot be trusted, as a
+    /// [`custom::CustomSampler`] may pick any name, so the interface is compared with the one of
+    /// an empty chain instead.
+    fn is_chain(&self) -> bool {
+        static CHAIN_IFACE: OnceLock<usize> = OnceLock::new();
+        let chain_iface = *CHAIN_IFACE.get_or_init(|| {
+            let chain = Self::chain_simple([]);
+            unsafe { (*chain.sampler).iface.addr() }
+        });
+        unsafe { (*self.sampler).iface.addr() == chain_iface }
+    }
+
+    fn assert_chain(&self) {
+        assert!(self.is_chain(), "sampler is not a chain");
+    }
+
+    /// The number of samplers in this chain.
+    ///
+    /// # Example
+    /// ```rust
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    ///
+    /// let mut chain = LlamaSampler::chain_simple([
+    ///     LlamaSampler::top_k(10),
+    ///     LlamaSampler::temp(0.5),
+    /// ]);
+    /// chain.chain_add(LlamaSampler::greedy());
+    /// assert_eq!(chain.chain_n(), 3);
+    ///
+    /// assert_eq!(chain.chain_get(1).unwrap().name(), "temp");
+    /// assert!(chain.chain_get(3).is_none());
+    ///
+    /// let top_k = chain.chain_remove(0).unwrap();
+    /// assert_eq!(top_k.name(), "top-k");
+    /// assert_eq!(chain.chain_n(), 2);
+    /// ```
+    ///
+    /// # Panics
+    /// If this sampler is not a chain.
+    #[must_use]
+    pub fn chain_n(&self) -> usize {
+        self.assert_chain();
+        let n = unsafe { llama_cpp_sys_2::llama_sampler_chain_n(self.sampler) };
+        usize::try_from(n).expect("chain length is never negative")
+    }
+
+    /// The `i`-th sampler in this chain, or `None` if `i` is out of range.
+    ///
+    /// # Panics
+    /// If this sampler is not a chain.
+    #[must_use]
+    pub fn chain_get(&self, i: usize) -> Option<ChainedSampler<'_>> {
+        self.assert_chain();
+        let sampler =
+            unsafe { llama_cpp_sys_2::llama_sampler_chain_get(self.sampler, i.try_into().ok()?) };
+        (!sampler.is_null()).then(|| ChainedSampler {
+            sampler: ManuallyDrop::new(Self { sampler }),
+            chain: PhantomData,
+        })
+    }
+
+    /// Adds a sampler to the end of this chain.
+    ///
+    /// # Panics
+    /// If this sampler is not a chain.
+    pub fn chain_add(&mut self, sampler: Self) {
+        self.assert_chain();
+        unsafe { llama_cpp_sys_2::llama_sampler_chain_add(self.sampler, sampler.sampler) };
+        // the chain now owns the sampler
+        std::mem::forget(sampler);
+    }
+
+    /// Removes the `i`-th sampler from this chain and returns it, or `None` if `i` is out of
+    /// range.
+    ///
+    /// # Panics
+    /// If this sampler is not a chain.
+    pub fn chain_remove(&mut self, i: usize) -> Option<Self> {
+        self.assert_chain();
+        let sampler = unsafe {
+            llama_cpp_sys_2::llama_sampler_chain_remove(self.sampler, i.try_into().ok()?)
+        };
+        (!sampler.is_null()).then_some(Self { sampler })
+    }
+
     /// Updates the logits l_i' = l_i/t. When t <= 0.0f, the maximum logit is kept at it's original
     /// value, the rest are set to -inf
     ///
@@ -229,12 +382,45 @@ impl LlamaSampler {
 
     /// Grammar sampler
     ///
-    /// # Panics
-    /// If either of ``grammar_str`` or ``grammar_root`` contain null bytes.
-    #[must_use]
-    pub fn grammar(model: &LlamaModel, grammar_str: &str, grammar_root: &str) -> Self {
-        let grammar_str = CString::new(grammar_str).unwrap();
-        let grammar_root = CString::new(grammar_root).unwrap();
+    /// The grammar is parsed before it is handed to llama.cpp, so mistakes are reported with their
+    /// location rather than producing a sampler that ignores the grammar.
+    ///
+    /// # Errors
+    /// - [`LlamaSamplerError::NullError`] if either of ``grammar_str`` or ``grammar_root``
+    ///   contain null bytes.
+    /// - [`LlamaSamplerError::GrammarParseError`] if the grammar is invalid or does not define
+    ///   ``grammar_root``.
+    /// - [`LlamaSamplerError::NullReturn`] if llama.cpp fails to create the sampler.
+    pub fn grammar(
+        model: &LlamaModel,
+        grammar_str: &str,
+        grammar_root: &str,
+    ) -> Result<Self, LlamaSamplerError> {
+        let c_grammar_str = CString::new(grammar_str)?;
+        let c_grammar_root = CString::new(grammar_root)?;
+        grammar::validate(grammar_str, grammar_root)?;
+
+        let sampler = unsafe {
+            llama_cpp_sys_2::llama_sampler_init_grammar(
+                model.model.as_ptr(),
+                c_grammar_str.as_ptr(),
+                c_grammar_root.as_ptr(),
+            )
+        };
+        Self::from_raw(sampler)
+    }
+
+    /// Grammar sampler from an already parsed (and therefore validated) [`LlamaGrammar`].
+    ///
+    /// # Errors
+    /// [`LlamaSamplerError::NullReturn`] if llama.cpp fails to create the sampler. The printed
+    /// grammar escapes control characters, so [`LlamaSamplerError::NullError`] is not returned.
+    pub fn from_grammar(
+        model: &LlamaModel,
+        grammar: &LlamaGrammar,
+    ) -> Result<Self, LlamaSamplerError> {
+        let grammar_str = CString::new(grammar.to_string())?;
+        let grammar_root = CString::new(LlamaGrammar::ROOT)?;
 
         let sampler = unsafe {
             llama_cpp_sys_2::llama_sampler_init_grammar(
@@ -243,17 +429,17 @@ impl LlamaSampler {
                 grammar_root.as_ptr(),
             )
         };
-        Self { sampler }
+        Self::from_raw(sampler)
     }
 
     /// DRY sampler, designed by p-e-w, as described in:
     /// <https://github.com/oobabooga/text-generation-webui/pull/5677>, porting Koboldcpp
     /// implementation authored by pi6am: <https://github.com/LostRuins/koboldcpp/pull/982>
     ///
-    /// # Panics
-    /// If any string in ``seq_breakers`` contains null bytes.
+    /// # Errors
+    /// - [`LlamaSamplerError::NullError`] if any string in ``seq_breakers`` contains null bytes.
+    /// - [`LlamaSamplerError::NullReturn`] if llama.cpp fails to create the sampler.
     #[allow(missing_docs)]
-    #[must_use]
     pub fn dry(
         model: &LlamaModel,
         multiplier: f32,
@@ -261,11 +447,11 @@ impl LlamaSampler {
         allowed_length: i32,
         penalty_last_n: i32,
         seq_breakers: impl IntoIterator<Item = impl AsRef<[u8]>>,
-    ) -> Self {
-        let seq_breakers: Vec<CString> = seq_breakers
+    ) -> Result<Self, LlamaSamplerError> {
+        let seq_breakers = seq_breakers
             .into_iter()
-            .map(|s| CString::new(s.as_ref()).unwrap())
-            .collect();
+            .map(|s| CString::new(s.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
         let mut seq_breaker_pointers: Vec<*const CChar> =
             seq_breakers.iter().map(|s| s.as_ptr()).collect();
 
@@ -280,9 +466,90 @@ impl LlamaSampler {
                 seq_breaker_pointers.len(),
             )
         };
+        Self::from_raw(sampler)
+    }
+
+    /// Adds a fixed bias to the logits of specific tokens. A bias of [`f32::NEG_INFINITY`] bans a
+    /// token outright, see [`Self::ban_tokens`].
+    ///
+    /// Parameters:
+    /// - ``n_vocab``: [`LlamaModel::n_vocab`]
+    /// - ``biases``: tokens and the value to add to their logits
+    ///
+    /// # Example:
+    /// ```rust
+    /// use llama_cpp_2::token::{
+    ///    LlamaToken,
+    ///    data::LlamaTokenData,
+    ///    data_array::LlamaTokenDataArray
+    /// };
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    ///
+    /// let mut data_array = LlamaTokenDataArray::new(vec![
+    ///     LlamaTokenData::new(LlamaToken(0), 0., 0.),
+    ///     LlamaTokenData::new(LlamaToken(1), 1., 0.),
+    ///     LlamaTokenData::new(LlamaToken(2), 2., 0.),
+    /// ], false);
+    ///
+    /// data_array.apply_sampler(&LlamaSampler::logit_bias(3, &[
+    ///     (LlamaToken(0), 5.),
+    ///     (LlamaToken(2), f32::NEG_INFINITY),
+    /// ]));
+    ///
+    /// assert_eq!(data_array.data[0].logit(), 5.);
+    /// assert_eq!(data_array.data[1].logit(), 1.);
+    /// assert_eq!(data_array.data[2].logit(), f32::NEG_INFINITY);
+    /// ```
+    ///
+    /// # Panics
+    /// If there are more than [`i32::MAX`] biases.
+    #[must_use]
+    pub fn logit_bias(n_vocab: i32, biases: &[(LlamaToken, f32)]) -> Self {
+        let biases = biases
+            .iter()
+            .map(|&(LlamaToken(token), bias)| llama_cpp_sys_2::llama_logit_bias { token, bias })
+            .collect::<Vec<_>>();
+        let n_logit_bias = i32::try_from(biases.len()).expect("too many logit biases");
+
+        let sampler = unsafe {
+            llama_cpp_sys_2::llama_sampler_init_logit_bias(n_vocab, n_logit_bias, biases.as_ptr())
+        };
         Self { sampler }
     }
 
+    /// Same as [`Self::logit_bias`], but biases every token of each string as tokenized by
+    /// [`LlamaModel::str_to_token`] (without a BOS token). Strings are tokenized exactly as
+    /// given, so a word in the middle of a sentence usually needs its leading space, e.g. `" Sure"`.
+    ///
+    /// # Errors
+    /// If any of the strings fail to tokenize. See [`StringToTokenError`].
+    pub fn logit_bias_from_strings<'a>(
+        model: &LlamaModel,
+        biases: impl IntoIterator<Item = (&'a str, f32)>,
+    ) -> Result<Self, StringToTokenError> {
+        let mut token_biases = Vec::new();
+        for (string, bias) in biases {
+            let tokens = model.str_to_token(string, AddBos::Never)?;
+            token_biases.extend(tokens.into_iter().map(|token| (token, bias)));
+        }
+        Ok(Self::logit_bias(model.n_vocab(), &token_biases))
+    }
+
+    /// Prevents any of ``tokens`` from being sampled by biasing them with
+    /// [`f32::NEG_INFINITY`].
+    ///
+    /// Parameters:
+    /// - ``n_vocab``: [`LlamaModel::n_vocab`]
+    /// - ``tokens``: the tokens to ban
+    #[must_use]
+    pub fn ban_tokens(n_vocab: i32, tokens: impl IntoIterator<Item = LlamaToken>) -> Self {
+        let biases = tokens
+            .into_iter()
+            .map(|token| (token, f32::NEG_INFINITY))
+            .collect::<Vec<_>>();
+        Self::logit_bias(n_vocab, &biases)
+    }
+
     /// Penalizes tokens for being present in the context.
     ///
     /// Parameters:  
@@ -369,11 +636,33 @@ impl LlamaSampler {
     ///     value that is used to calculate `s_hat`, which in turn helps to calculate the value of `k`.
     ///     In the paper, they use `m = 100`, but you can experiment with different values to see how
     ///     it affects the performance of the algorithm.
-    #[must_use]
-    pub fn mirostat(n_vocab: i32, seed: u32, tau: f32, eta: f32, m: i32) -> Self {
+    ///
+    /// # Errors
+    /// [`LlamaSamplerError::InvalidParameter`] if ``n_vocab`` or ``m`` are not positive, or
+    /// ``tau`` or ``eta`` are negative or not finite.
+    pub fn mirostat(
+        n_vocab: i32,
+        seed: u32,
+        tau: f32,
+        eta: f32,
+        m: i32,
+    ) -> Result<Self, LlamaSamplerError> {
+        if n_vocab <= 0 {
+            return Err(LlamaSamplerError::InvalidParameter {
+                name: "n_vocab",
+                reason: "must be positive",
+            });
+        }
+        if m <= 0 {
+            return Err(LlamaSamplerError::InvalidParameter {
+                name: "m",
+                reason: "must be positive",
+            });
+        }
+        check_mirostat_rates(tau, eta)?;
         let sampler =
             unsafe { llama_cpp_sys_2::llama_sampler_init_mirostat(n_vocab, seed, tau, eta, m) };
-        Self { sampler }
+        Self::from_raw(sampler)
     }
 
     /// Mirostat 2.0 algorithm described in the paper <https://arxiv.org/abs/2007.14966>. Uses tokens instead of words.
@@ -386,10 +675,24 @@ impl LlamaSampler {
     /// - ``eta``: The learning rate used to update `mu` based on the error between the target and
     ///     observed surprisal of the sampled word. A larger learning rate will cause `mu` to be
     ///     updated more quickly, while a smaller learning rate will result in slower updates.
-    #[must_use]
-    pub fn mirostat_v2(seed: u32, tau: f32, eta: f32) -> Self {
+    ///
+    /// # Example:
+    /// ```rust
+    /// use llama_cpp_2::sampling::LlamaSampler;
+    /// use llama_cpp_2::LlamaSamplerError;
+    ///
+    /// assert!(matches!(
+    ///     LlamaSampler::mirostat_v2(1234, f32::NAN, 0.1),
+    ///     Err(LlamaSamplerError::InvalidParameter { name: "tau", .. })
+    /// ));
+    /// ```
+    ///
+    /// # Errors
+    /// [`LlamaSamplerError::InvalidParameter`] if ``tau`` or ``eta`` are negative or not finite.
+    pub fn mirostat_v2(seed: u32, tau: f32, eta: f32) -> Result<Self, LlamaSamplerError> {
+        check_mirostat_rates(tau, eta)?;
         let sampler = unsafe { llama_cpp_sys_2::llama_sampler_init_mirostat_v2(seed, tau, eta) };
-        Self { sampler }
+        Self::from_raw(sampler)
     }
 
     /// Selects a token at random based on each token's probabilities
@@ -427,6 +730,48 @@ impl LlamaSampler {
     }
 }
 
+/// `tau` and `eta` feed straight into mirostat's running estimate of `mu`, so anything that is not
+/// a finite, non-negative number poisons every later sample.
+fn check_mirostat_rates(tau: f32, eta: f32) -> Result<(), LlamaSamplerError> {
+    for (name, value) in [("tau", tau), ("eta", eta)] {
+        if !value.is_finite() || value < 0.0 {
+            return Err(LlamaSamplerError::InvalidParameter {
+                name,
+                reason: "must be a finite, non-negative number",
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Creates an independent copy of the sampler, including its current state.
+///
+/// # Panics
+///
+/// If llama.cpp cannot clone the sampler, see [`LlamaSampler::try_clone`].
+impl Clone for LlamaSampler {
+    fn clone(&self) -> Self {
+        self.try_clone()
+            .expect("llama.cpp does not support cloning this sampler")
+    }
+}
+
+/// A sampler borrowed from a chain, see [`LlamaSampler::chain_get`]. It is still owned (and will
+/// be freed) by the chain; use [`LlamaSampler::clone`] to get a copy that outlives it.
+#[derive(Debug)]
+pub struct ChainedSampler<'a> {
+    sampler: ManuallyDrop<LlamaSampler>,
+    chain: PhantomData<&'a LlamaSampler>,
+}
+
+impl Deref for ChainedSampler<'_> {
+    type Target = LlamaSampler;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sampler
+    }
+}
+
 impl Drop for LlamaSampler {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,221 @@
+//! Samplers implemented in rust.
+//!
+//! Implement [`CustomSampler`] and wrap it with [`LlamaSampler::custom`] to use it anywhere a
+//! built-in sampler can be used, including inside a [`LlamaSampler::chain`].
+
+use std::ffi::{c_char, CStr};
+use std::ptr;
+
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// A sampler implemented in rust.
+///
+/// The methods mirror the callbacks of `llama_sampler_i`. They are called from inside llama.cpp,
+/// so a panic in any of them aborts the process.
+///
+/// # Example
+/// ```rust
+/// use std::ffi::CStr;
+/// use llama_cpp_2::sampling::custom::CustomSampler;
+/// use llama_cpp_2::sampling::LlamaSampler;
+/// use llama_cpp_2::token::{
+///    LlamaToken,
+///    data::LlamaTokenData,
+///    data_array::LlamaTokenDataArray
+/// };
+///
+/// /// Never picks a token that was already accepted.
+/// #[derive(Clone, Default)]
+/// struct NoRepeat(Vec<LlamaToken>);
+///
+/// impl CustomSampler for NoRepeat {
+///     fn name(&self) -> &CStr {
+///         c"no-repeat"
+///     }
+///
+///     fn apply(&mut self, data_array: &mut LlamaTokenDataArray) {
+///         data_array.data.retain(|data| !self.0.contains(&data.id()));
+///     }
+///
+///     fn accept(&mut self, token: LlamaToken) {
+///         self.0.push(token);
+///     }
+///
+///     fn reset(&mut self) {
+///         self.0.clear();
+///     }
+///
+///     fn clone_sampler(&self) -> Box<dyn CustomSampler> {
+///         Box::new(self.clone())
+///     }
+/// }
+///
+/// let mut sampler = LlamaSampler::chain_simple([
+///     LlamaSampler::custom(NoRepeat::default()),
+///     LlamaSampler::greedy(),
+/// ]);
+/// sampler.accept(LlamaToken(1));
+///
+/// let mut data_array = LlamaTokenDataArray::new(vec![
+///     LlamaTokenData::new(LlamaToken(0), 0., 0.),
+///     LlamaTokenData::new(LlamaToken(1), 1., 0.),
+/// ], false);
+/// data_array.apply_sampler(&sampler);
+///
+/// assert_eq!(data_array.data.len(), 1);
+/// assert_eq!(data_array.selected_token(), Some(LlamaToken(0)));
+/// ```
+#[allow(clippy::module_name_repetitions)]
+pub trait CustomSampler {
+    /// The name llama.cpp reports for this sampler (e.g. in performance output).
+    fn name(&self) -> &CStr;
+
+    /// Modify the candidates. Candidates may be removed, reordered or have their logits changed,
+    /// but any added beyond the number of candidates passed in are dropped.
+    fn apply(&mut self, data_array: &mut LlamaTokenDataArray);
+
+    /// Update any internal state after `token` was chosen.
+    fn accept(&mut self, _token: LlamaToken) {}
+
+    /// Reset any internal state to how it was when the sampler was created.
+    fn reset(&mut self) {}
+
+    /// Create an independent copy of this sampler, used when llama.cpp clones it.
+    fn clone_sampler(&self) -> Box<dyn CustomSampler>;
+}
+
+impl LlamaSampler {
+    /// Wrap a [`CustomSampler`] so it can be used like any of the built-in samplers.
+    #[must_use]
+    pub fn custom(sampler: impl CustomSampler + 'static) -> Self {
+        Self::custom_boxed(Box::new(sampler))
+    }
+
+    fn custom_boxed(sampler: Box<dyn CustomSampler>) -> Self {
+        // the box is boxed again as `Box<dyn _>` is a fat pointer.
+        let ctx = Box::into_raw(Box::new(sampler));
+        let sampler = unsafe {
+            llama_cpp_sys_2::llama_sampler_init(ptr::addr_of!(CUSTOM_SAMPLER_I), ctx.cast())
+        };
+        Self { sampler }
+    }
+}
+
+static CUSTOM_SAMPLER_I: llama_cpp_sys_2::llama_sampler_i = llama_cpp_sys_2::llama_sampler_i {
+    name: Some(custom_name),
+    accept: Some(custom_accept),
+    apply: Some(custom_apply),
+    reset: Some(custom_reset),
+    clone: Some(custom_clone),
+    free: Some(custom_free),
+};
+
+/// SAFETY: `smpl` must have been created by [`LlamaSampler::custom_boxed`] and not yet freed.
+unsafe fn custom_sampler<'a>(
+    smpl: *const llama_cpp_sys_2::llama_sampler,
+) -> &'a mut Box<dyn CustomSampler> {
+    &mut *(*smpl).ctx.cast::<Box<dyn CustomSampler>>()
+}
+
+unsafe extern "C" fn custom_name(smpl: *const llama_cpp_sys_2::llama_sampler) -> *const c_char {
+    custom_sampler(smpl).name().as_ptr()
+}
+
+unsafe extern "C" fn custom_accept(
+    smpl: *mut llama_cpp_sys_2::llama_sampler,
+    token: llama_cpp_sys_2::llama_token,
+) {
+    custom_sampler(smpl).accept(LlamaToken(token));
+}
+
+unsafe extern "C" fn custom_apply(
+    smpl: *mut llama_cpp_sys_2::llama_sampler,
+    cur_p: *mut llama_cpp_sys_2::llama_token_data_array,
+) {
+    let cur_p = &mut *cur_p;
+    if cur_p.size == 0 || cur_p.data.is_null() {
+        return;
+    }
+    let data = std::slice::from_raw_parts(cur_p.data.cast::<LlamaTokenData>(), cur_p.size);
+    let mut data_array = LlamaTokenDataArray {
+        data: data.to_vec(),
+        selected: usize::try_from(cur_p.selected).ok(),
+        sorted: cur_p.sorted,
+    };
+
+    custom_sampler(smpl).apply(&mut data_array);
+
+    // llama.cpp owns the buffer, so it cannot grow.
+    data_array.data.truncate(cur_p.size);
+    ptr::copy_nonoverlapping(
+        data_array
+            .data
+            .as_ptr()
+            .cast::<llama_cpp_sys_2::llama_token_data>(),
+        cur_p.data,
+        data_array.data.len(),
+    );
+    cur_p.size = data_array.data.len();
+    cur_p.selected = data_array
+        .selected
+        .filter(|&selected| selected < cur_p.size)
+        .and_then(|selected| selected.try_into().ok())
+        .unwrap_or(-1);
+    cur_p.sorted = data_array.sorted;
+}
+
+unsafe extern "C" fn custom_reset(smpl: *mut llama_cpp_sys_2::llama_sampler) {
+    custom_sampler(smpl).reset();
+}
+
+unsafe extern "C" fn custom_clone(
+    smpl: *const llama_cpp_sys_2::llama_sampler,
+) -> *mut llama_cpp_sys_2::llama_sampler {
+    let clone = LlamaSampler::custom_boxed(custom_sampler(smpl).clone_sampler());
+    // ownership passes to llama.cpp
+    let sampler = clone.sampler;
+    std::mem::forget(clone);
+    sampler
+}
+
+unsafe extern "C" fn custom_free(smpl: *mut llama_cpp_sys_2::llama_sampler) {
+    drop(Box::from_raw((*smpl).ctx.cast::<Box<dyn CustomSampler>>()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretends to be a chain by its name.
+    #[derive(Clone)]
+    struct FakeChain;
+
+    impl CustomSampler for FakeChain {
+        fn name(&self) -> &CStr {
+            c"chain"
+        }
+
+        fn apply(&mut self, _data_array: &mut LlamaTokenDataArray) {}
+
+        fn clone_sampler(&self) -> Box<dyn CustomSampler> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "sampler is not a chain")]
+    fn a_chain_name_does_not_make_a_chain() {
+        let fake = LlamaSampler::custom(FakeChain);
+        assert_eq!(fake.name(), "chain");
+        let _ = fake.chain_n();
+    }
+
+    #[test]
+    fn real_chains_are_chains() {
+        let chain = LlamaSampler::chain_simple([LlamaSampler::custom(FakeChain)]);
+        assert_eq!(chain.chain_n(), 1);
+    }
+}
@@ -0,0 +1,523 @@
+//! Decode many independent requests together (continuous batching).
+//!
+//! A [`Scheduler`] owns a [`LlamaContext`] whose sequences it hands out to requests. Each call to
+//! [`Scheduler::step`] builds one batch holding the next token of every generating request and as
+//! many prompt tokens of newly added requests as fit, decodes it and samples every request whose
+//! logits are ready with its own [`LlamaSampler`]. Requests can be added at any time and start as
+//! soon as a sequence is free.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # use llama_cpp_2::scheduler::{Request, Scheduler, SchedulerEvent};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let ctx = model.new_context(&backend, params)?;
+//! let mut scheduler = Scheduler::new(ctx, 4);
+//!
+//! for prompt in ["Once upon a time", "The capital of France is"] {
+//!     let prompt = model.str_to_token(prompt, AddBos::Always)?;
+//!     let request = Request::new(prompt, LlamaSampler::greedy()).with_max_tokens(32);
+//!     scheduler.add(request)?;
+//! }
+//!
+//! while !scheduler.is_idle() {
+//!     for event in scheduler.step()? {
+//!         match event {
+//!             SchedulerEvent::Token { id, token } => {
+//!                 println!("{id:?}: {}", model.token_to_str(token, Special::Tokenize)?);
+//!             }
+//!             SchedulerEvent::Finished { id, reason } => println!("{id:?} finished: {reason:?}"),
+//!         }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::completion::FinishReason;
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// An error that can occur while scheduling requests.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum SchedulerError {
+    /// The prompt was empty, so there are no logits to sample from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the part of the context available to one sequence.
+    #[error("the prompt has {len} tokens but each sequence only holds {n_ctx_seq} tokens")]
+    PromptTooLong {
+        /// the length of the prompt
+        len: usize,
+        /// the number of tokens each sequence can hold
+        n_ctx_seq: usize,
+    },
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// A sequence id did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// Identifies a request added to a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub u64);
+
+/// Something that happened to a request during [`Scheduler::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum SchedulerEvent {
+    /// A token was generated for the request.
+    Token {
+        /// the request
+        id: RequestId,
+        /// the generated token
+        token: LlamaToken,
+    },
+    /// The request finished and its sequence was freed. End-of-generation tokens are not
+    /// reported as [`SchedulerEvent::Token`].
+    Finished {
+        /// the request
+        id: RequestId,
+        /// why it finished
+        reason: FinishReason,
+    },
+}
+
+/// A prompt to generate a completion for.
+#[derive(Debug)]
+pub struct Request {
+    prompt: Vec<LlamaToken>,
+    sampler: LlamaSampler,
+    max_tokens: Option<usize>,
+}
+
+impl Request {
+    /// A request to complete `prompt`, sampling with `sampler`.
+    #[must_use]
+    pub fn new(prompt: Vec<LlamaToken>, sampler: LlamaSampler) -> Self {
+        Self {
+            prompt,
+            sampler,
+            max_tokens: None,
+        }
+    }
+
+    /// Stop after generating `max_tokens` tokens.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+}
+
+/// A request that has been assigned a sequence.
+#[derive(Debug)]
+struct Active {
+    id: RequestId,
+    seq_id: i32,
+    request: Request,
+    /// the number of prompt tokens in the kv cache
+    n_prompt_decoded: usize,
+    /// the sampled token that has not been decoded yet
+    next_token: Option<LlamaToken>,
+    n_past: i32,
+    n_generated: usize,
+}
+
+/// Decodes many requests together, each in its own sequence of a shared [`LlamaContext`].
+#[derive(Debug)]
+pub struct Scheduler<'model> {
+    ctx: LlamaContext<'model>,
+    batch: LlamaBatch,
+    n_batch: usize,
+    /// the number of tokens each sequence can hold
+    n_ctx_seq: usize,
+    free_seq_ids: Vec<i32>,
+    waiting: VecDeque<(RequestId, Request)>,
+    active: Vec<Active>,
+    next_id: u64,
+}
+
+impl<'model> Scheduler<'model> {
+    /// Create a scheduler running up to `n_seq` requests at once in sequences `0..n_seq` of
+    /// `ctx`, which must be empty and allow that many sequences (see
+    /// [`crate::context::params::LlamaContextParams::with_n_seq_max`]). Each sequence can hold an
+    /// equal share of the context.
+    ///
+    /// # Panics
+    ///
+    /// - `n_seq` is `0` or does not fit into an [`i32`]
+    /// - `n_seq` is larger than the number of sequences of the context
+    /// - `n_seq` is larger than the batch size of the context, so the next tokens of all
+    ///   requests would not fit into one batch
+    /// - the batch size of the context does not fit into a [`usize`]
+    #[must_use]
+    pub fn new(ctx: LlamaContext<'model>, n_seq: u32) -> Self {
+        assert!(n_seq > 0, "a scheduler needs at least one sequence");
+        assert!(
+            n_seq <= ctx.n_seq_max(),
+            "n_seq must not be larger than the n_seq_max of the context"
+        );
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+        assert!(
+            usize::try_from(n_seq).is_ok_and(|n_seq| n_seq <= n_batch),
+            "n_seq must not be larger than the batch size"
+        );
+        let n_seq = i32::try_from(n_seq).expect("n_seq does not fit into an i32");
+        let n_ctx = usize::try_from(ctx.n_ctx()).unwrap_or(usize::MAX);
+        Self {
+            ctx,
+            batch: LlamaBatch::new(n_batch, 1),
+            n_batch,
+            n_ctx_seq: n_ctx / n_seq.unsigned_abs() as usize,
+            // reversed so sequences are handed out in order
+            free_seq_ids: (0..n_seq).rev().collect(),
+            waiting: VecDeque::new(),
+            active: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Add a request. It starts at the next [`Scheduler::step`] with a free sequence.
+    ///
+    /// # Errors
+    ///
+    /// - [`SchedulerError::EmptyPrompt`] if the prompt is empty.
+    /// - [`SchedulerError::PromptTooLong`] if the prompt does not fit into one sequence.
+    pub fn add(&mut self, request: Request) -> Result<RequestId, SchedulerError> {
+        if request.prompt.is_empty() {
+            return Err(SchedulerError::EmptyPrompt);
+        }
+        if request.prompt.len() > self.n_ctx_seq {
+            return Err(SchedulerError::PromptTooLong {
+                len: request.prompt.len(),
+                n_ctx_seq: self.n_ctx_seq,
+            });
+        }
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.waiting.push_back((id, request));
+        Ok(id)
+    }
+
+    /// Cancel a request, freeing its sequence. Returns `false` if it is not known (e.g. because
+    /// it already finished).
+    ///
+    /// # Errors
+    ///
+    /// - [`SchedulerError::KvCacheConversionError`] if the sequence id does not fit.
+    pub fn cancel(&mut self, id: RequestId) -> Result<bool, SchedulerError> {
+        if let Some(i) = self.waiting.iter().position(|(waiting, _)| *waiting == id) {
+            self.waiting.remove(i);
+            return Ok(true);
+        }
+        match self.active.iter().position(|active| active.id == id) {
+            Some(i) => {
+                let active = self.active.remove(i);
+                self.free(active.seq_id)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether there are no requests left to run.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.active.is_empty()
+    }
+
+    /// The number of requests that have a sequence.
+    #[must_use]
+    pub fn n_active(&self) -> usize {
+        self.active.len()
+    }
+
+    /// The number of requests waiting for a free sequence.
+    #[must_use]
+    pub fn n_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// The context requests are decoded in.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'model> {
+        &self.ctx
+    }
+
+    /// Stop scheduling and return the context. The kv cache still holds any active requests.
+    #[must_use]
+    pub fn into_context(self) -> LlamaContext<'model> {
+        self.ctx
+    }
+
+    /// Decode one batch and sample every request that has finished its prompt.
+    ///
+    /// The batch holds the last sampled token of every generating request, then prompt tokens of
+    /// starting requests (oldest first) up to the batch size. Returns nothing if there is nothing
+    /// to do.
+    ///
+    /// # Errors
+    ///
+    /// - [`SchedulerError::DecodeError`] if decoding the batch failed.
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an [`i32`]
+    pub fn step(&mut self) -> Result<Vec<SchedulerEvent>, SchedulerError> {
+        admit(&mut self.waiting, &mut self.free_seq_ids, &mut self.active);
+        let scheduled = fill_batch(&self.active, &mut self.batch, self.n_batch)?;
+        if scheduled.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.ctx.decode(&mut self.batch)?;
+
+        let mut events = Vec::new();
+        let mut finished = Vec::new();
+        for (i, logits_index, n_prompt) in scheduled {
+            let active = &mut self.active[i];
+            if n_prompt > 0 {
+                active.n_prompt_decoded += n_prompt;
+                active.n_past += i32::try_from(n_prompt).expect("position fits into an i32");
+            } else {
+                active.n_past += 1;
+            }
+            let Some(logits_index) = logits_index else {
+                continue;
+            };
+
+            // only reachable with a limit of 0, otherwise the request finished after its last
+            // token
+            let reason = if active.reached_max_tokens() {
+                Some(FinishReason::MaxTokens)
+            } else {
+                let token = active.request.sampler.sample(&self.ctx, logits_index);
+                if self.ctx.model.is_eog_token(token) {
+                    Some(FinishReason::EndOfGeneration)
+                } else {
+                    active.n_generated += 1;
+                    active.next_token = Some(token);
+                    events.push(SchedulerEvent::Token {
+                        id: active.id,
+                        token,
+                    });
+                    active.finish_reason(self.n_ctx_seq)
+                }
+            };
+            if let Some(reason) = reason {
+                events.push(SchedulerEvent::Finished {
+                    id: active.id,
+                    reason,
+                });
+                finished.push(i);
+            }
+        }
+
+        // remove from the back so the indices stay valid
+        for i in finished.into_iter().rev() {
+            let active = self.active.remove(i);
+            self.free(active.seq_id)?;
+        }
+        Ok(events)
+    }
+
+    /// Remove a sequence from the kv cache and make it available to new requests.
+    fn free(&mut self, seq_id: i32) -> Result<(), SchedulerError> {
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        self.ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+        self.free_seq_ids.push(seq_id);
+        Ok(())
+    }
+}
+
+impl Active {
+    /// Whether the request generated as many tokens as it may.
+    fn reached_max_tokens(&self) -> bool {
+        self.request
+            .max_tokens
+            .is_some_and(|max| self.n_generated >= max)
+    }
+
+    /// Why the request finishes after generating a token, if it does.
+    fn finish_reason(&self, n_ctx_seq: usize) -> Option<FinishReason> {
+        if self.reached_max_tokens() {
+            Some(FinishReason::MaxTokens)
+        } else if self.n_past.unsigned_abs() as usize >= n_ctx_seq {
+            Some(FinishReason::ContextFull)
+        } else {
+            None
+        }
+    }
+}
+
+/// Move waiting requests into free sequences, oldest first.
+fn admit(
+    waiting: &mut VecDeque<(RequestId, Request)>,
+    free_seq_ids: &mut Vec<i32>,
+    active: &mut Vec<Active>,
+) {
+    while !waiting.is_empty() {
+        let Some(seq_id) = free_seq_ids.pop() else {
+            break;
+        };
+        let (id, request) = waiting.pop_front().expect("checked it is not empty");
+        active.push(Active {
+            id,
+            seq_id,
+            request,
+            n_prompt_decoded: 0,
+            next_token: None,
+            n_past: 0,
+            n_generated: 0,
+        });
+    }
+}
+
+/// Fill `batch` with the next token of every generating request, then with prompt tokens of
+/// starting requests up to `n_batch` tokens. Returns (index into `active`, index of the logits in
+/// the batch, prompt tokens added) for every request with tokens in the batch.
+fn fill_batch(
+    active: &[Active],
+    batch: &mut LlamaBatch,
+    n_batch: usize,
+) -> Result<Vec<(usize, Option<i32>, usize)>, BatchAddError> {
+    let mut scheduled = Vec::new();
+    batch.clear();
+    for (i, active) in active.iter().enumerate() {
+        if let Some(token) = active.next_token {
+            batch.add(token, active.n_past, &[active.seq_id], true)?;
+            scheduled.push((i, Some(batch.n_tokens() - 1), 0));
+        }
+    }
+    for (i, active) in active.iter().enumerate() {
+        let prompt = &active.request.prompt[active.n_prompt_decoded..];
+        let space = n_batch - batch.n_tokens().unsigned_abs() as usize;
+        if prompt.is_empty() || active.next_token.is_some() || space == 0 {
+            continue;
+        }
+        let chunk = &prompt[..prompt.len().min(space)];
+        let is_last_chunk = chunk.len() == prompt.len();
+        for (j, &token) in chunk.iter().enumerate() {
+            let pos = active.n_past + i32::try_from(j).expect("position fits into an i32");
+            let logits = is_last_chunk && j + 1 == chunk.len();
+            batch.add(token, pos, &[active.seq_id], logits)?;
+        }
+        let logits_index = is_last_chunk.then(|| batch.n_tokens() - 1);
+        scheduled.push((i, logits_index, chunk.len()));
+    }
+    Ok(scheduled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt_len: i32) -> Request {
+        Request::new(
+            (0..prompt_len).map(LlamaToken).collect(),
+            LlamaSampler::greedy(),
+        )
+    }
+
+    fn active(seq_id: i32, request: Request) -> Active {
+        Active {
+            id: RequestId(seq_id.unsigned_abs().into()),
+            seq_id,
+            request,
+            n_prompt_decoded: 0,
+            next_token: None,
+            n_past: 0,
+            n_generated: 0,
+        }
+    }
+
+    #[test]
+    fn requests_are_admitted_in_order_while_sequences_are_free() {
+        let mut waiting = (0..3).map(|i| (RequestId(i), request(2))).collect();
+        let mut free_seq_ids = vec![1, 0];
+        let mut active = Vec::new();
+        admit(&mut waiting, &mut free_seq_ids, &mut active);
+
+        let admitted = active
+            .iter()
+            .map(|active| (active.id, active.seq_id))
+            .collect::<Vec<_>>();
+        assert_eq!(admitted, [(RequestId(0), 0), (RequestId(1), 1)]);
+        assert!(free_seq_ids.is_empty());
+        assert_eq!(waiting.len(), 1);
+    }
+
+    #[test]
+    fn generating_requests_go_first_and_prompts_are_split_to_fit() {
+        let mut generating = active(0, request(2));
+        generating.n_prompt_decoded = 2;
+        generating.n_past = 2;
+        generating.next_token = Some(LlamaToken(7));
+        let mut starting = active(1, request(6));
+        let mut batch = LlamaBatch::new(4, 1);
+
+        let scheduled = fill_batch(&[generating, starting], &mut batch, 4).unwrap();
+        assert_eq!(scheduled, [(0, Some(0), 0), (1, None, 3)]);
+        assert_eq!(batch.n_tokens(), 4);
+
+        starting = active(1, request(6));
+        starting.n_prompt_decoded = 3;
+        starting.n_past = 3;
+        let scheduled = fill_batch(&[starting], &mut batch, 4).unwrap();
+        assert_eq!(scheduled, [(0, Some(2), 3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "n_seq must not be larger than the n_seq_max of the context")]
+    fn n_seq_must_fit_the_context() {
+        use crate::context::params::LlamaContextParams;
+        use crate::gguf::tiny::test_model;
+        use std::num::NonZeroU32;
+
+        let (backend, model) = test_model();
+        let params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(64))
+            .with_n_seq_max(2);
+        let ctx = model.new_context(backend, params).unwrap();
+        let _ = Scheduler::new(ctx, 3);
+    }
+
+    #[test]
+    fn finish_reasons() {
+        let mut limited = active(0, request(2).with_max_tokens(0));
+        assert!(limited.reached_max_tokens());
+
+        limited.request.max_tokens = Some(2);
+        limited.n_past = 3;
+        limited.n_generated = 1;
+        assert!(!limited.reached_max_tokens());
+        assert_eq!(limited.finish_reason(8), None);
+        limited.n_generated = 2;
+        assert_eq!(limited.finish_reason(8), Some(FinishReason::MaxTokens));
+
+        let mut unlimited = active(1, request(2));
+        unlimited.n_past = 8;
+        unlimited.n_generated = 100;
+        assert_eq!(unlimited.finish_reason(8), Some(FinishReason::ContextFull));
+        unlimited.n_past = 7;
+        assert_eq!(unlimited.finish_reason(8), None);
+    }
+}
@@ -0,0 +1,512 @@
+//! Speculative decoding: a cheap draft model proposes tokens that the target model checks all at
+//! once, so several tokens can be generated per target [`LlamaContext::decode`].
+//!
+//! Drafts are made greedily. The target samples every drafted position with its own sampler and
+//! keeps drafted tokens for as long as they agree with what it sampled, so the output is the same
+//! as sampling the target alone with that sampler.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+//! # use llama_cpp_2::sampling::LlamaSampler;
+//! # use llama_cpp_2::speculative::SpeculativeDecoder;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = LlamaBackend::init()?;
+//! let target = LlamaModel::load_from_file(&backend, "path/to/70b", &Default::default())?;
+//! let draft = LlamaModel::load_from_file(&backend, "path/to/1b", &Default::default())?;
+//! let mut target_ctx = target.new_context(&backend, LlamaContextParams::default())?;
+//! let mut draft_ctx = draft.new_context(&backend, LlamaContextParams::default())?;
+//! let mut sampler = LlamaSampler::greedy();
+//!
+//! let prompt = target.str_to_token("Hello! how are you?", AddBos::Always)?;
+//! let mut decoder =
+//!     SpeculativeDecoder::new(&mut target_ctx, &mut draft_ctx, &mut sampler, &prompt, 8)?;
+//! 'generate: for _ in 0..32 {
+//!     for token in decoder.step()? {
+//!         if target.is_eog_token(token) {
+//!             break 'generate;
+//!         }
+//!         print!("{}", target.token_to_str(token, Special::Tokenize)?);
+//!     }
+//! }
+//! println!("\nacceptance rate: {:.2}", decoder.stats().acceptance_rate());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! See [`lookup`] for speculative decoding without a draft model.
+
+use std::cmp::Ordering;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{LlamaModel, Special, VocabType};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+pub mod lookup;
+
+/// The sequence id speculative decoding runs in.
+pub(crate) const SEQ_ID: i32 = 0;
+
+/// Vocabularies may differ in size by this many tokens (e.g. padding) and still be compatible.
+const MAX_VOCAB_SIZE_DIFFERENCE: i32 = 128;
+
+/// Token ids below this are often control tokens that differ between models of a family.
+const CHECK_START_TOKEN_ID: i32 = 5;
+
+/// Why a draft model cannot be used with a target model.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum VocabMismatch {
+    /// The models use different kinds of tokenizer.
+    #[error("the target vocab type is {target:?} but the draft vocab type is {draft:?}")]
+    VocabType {
+        /// the vocab type of the target model
+        target: VocabType,
+        /// the vocab type of the draft model
+        draft: VocabType,
+    },
+    /// The vocab sizes differ by more than a few padding tokens.
+    #[error("the target has {target} tokens but the draft has {draft} tokens")]
+    VocabSize {
+        /// the vocab size of the target model
+        target: i32,
+        /// the vocab size of the draft model
+        draft: i32,
+    },
+    /// A special token has a different id.
+    #[error("the {name} token is {target} in the target but {draft} in the draft")]
+    SpecialToken {
+        /// the name of the special token
+        name: &'static str,
+        /// the token in the target model
+        target: LlamaToken,
+        /// the token in the draft model
+        draft: LlamaToken,
+    },
+    /// A token has different text.
+    #[error("token {token} is {target:?} in the target but {draft:?} in the draft")]
+    Token {
+        /// the token
+        token: LlamaToken,
+        /// the text of the token in the target model
+        target: String,
+        /// the text of the token in the draft model
+        draft: String,
+    },
+}
+
+/// An error that can occur during speculative decoding.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum SpeculativeError {
+    /// The draft model cannot be used with the target model.
+    #[error(transparent)]
+    VocabMismatch(#[from] VocabMismatch),
+    /// The prompt was empty.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// There is no room left in one of the contexts.
+    #[error("the context is full")]
+    ContextFull,
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    /// Adding a token to a batch failed.
+    #[error(transparent)]
+    BatchAddError(#[from] BatchAddError),
+    /// A position did not fit into the kv cache api.
+    #[error(transparent)]
+    KvCacheConversionError(#[from] KvCacheConversionError),
+}
+
+/// How well drafts were accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct SpeculativeStats {
+    /// the number of target decodes
+    pub n_steps: u64,
+    /// the number of drafted tokens
+    pub n_drafted: u64,
+    /// the number of drafted tokens the target accepted
+    pub n_accepted: u64,
+    /// the number of tokens generated, including those sampled by the target itself
+    pub n_generated: u64,
+}
+
+impl SpeculativeStats {
+    /// The fraction of drafted tokens that were accepted, or `0` if nothing was drafted.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.n_drafted == 0 {
+            0.0
+        } else {
+            self.n_accepted as f64 / self.n_drafted as f64
+        }
+    }
+
+    /// The average number of tokens generated per target decode, or `0` before the first step.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn tokens_per_step(&self) -> f64 {
+        if self.n_steps == 0 {
+            0.0
+        } else {
+            self.n_generated as f64 / self.n_steps as f64
+        }
+    }
+
+    /// Count a step that drafted `n_drafted` tokens and accepted `n_accepted` of them, generating
+    /// the accepted tokens and the one the target sampled after them.
+    pub(crate) fn record_step(&mut self, n_drafted: usize, n_accepted: usize) {
+        self.n_steps += 1;
+        self.n_drafted += n_drafted as u64;
+        self.n_accepted += n_accepted as u64;
+        self.n_generated += n_accepted as u64 + 1;
+    }
+}
+
+/// Check that tokens of `draft` mean the same as tokens of `target`, as llama.cpp's speculative
+/// example does: the tokenizers must be of the same type, agree on the special tokens, have
+/// nearly the same size and render every shared token the same way.
+///
+/// # Errors
+///
+/// Returns the first difference found.
+pub fn check_vocab_compatibility(
+    target: &LlamaModel,
+    draft: &LlamaModel,
+) -> Result<(), VocabMismatch> {
+    let (target_type, draft_type) = (target.vocab_type(), draft.vocab_type());
+    if target_type != draft_type {
+        return Err(VocabMismatch::VocabType {
+            target: target_type,
+            draft: draft_type,
+        });
+    }
+    for (name, target_token, draft_token) in [
+        ("bos", target.token_bos(), draft.token_bos()),
+        ("eos", target.token_eos(), draft.token_eos()),
+    ] {
+        if target_token != draft_token {
+            return Err(VocabMismatch::SpecialToken {
+                name,
+                target: target_token,
+                draft: draft_token,
+            });
+        }
+    }
+    let (n_target, n_draft) = (target.n_vocab(), draft.n_vocab());
+    if (n_target - n_draft).abs() > MAX_VOCAB_SIZE_DIFFERENCE {
+        return Err(VocabMismatch::VocabSize {
+            target: n_target,
+            draft: n_draft,
+        });
+    }
+    for token in (CHECK_START_TOKEN_ID..n_target.min(n_draft)).map(LlamaToken) {
+        let target_text = target.token_to_bytes(token, Special::Tokenize).ok();
+        let draft_text = draft.token_to_bytes(token, Special::Tokenize).ok();
+        if target_text != draft_text {
+            let lossy = |text: Option<Vec<u8>>| {
+                String::from_utf8_lossy(&text.unwrap_or_default()).into_owned()
+            };
+            return Err(VocabMismatch::Token {
+                token,
+                target: lossy(target_text),
+                draft: lossy(draft_text),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Generates tokens with a target model, using a draft model to propose several at a time.
+///
+/// Both contexts hold the same tokens in sequence `0`, which is appended to after any tokens
+/// already in the target's kv cache. The draft's kv cache must hold the same tokens as the
+/// target's when the decoder is created.
+#[derive(Debug)]
+pub struct SpeculativeDecoder<'a, 'target, 'draft> {
+    target: &'a mut LlamaContext<'target>,
+    draft: &'a mut LlamaContext<'draft>,
+    sampler: &'a mut LlamaSampler,
+    n_draft: usize,
+    target_batch: LlamaBatch,
+    draft_batch: LlamaBatch,
+    /// the number of tokens in the target's kv cache
+    n_past: i32,
+    /// the last token, which is not in either kv cache yet
+    last: LlamaToken,
+    /// accepted tokens the draft has not decoded yet
+    draft_pending: Vec<LlamaToken>,
+    stats: SpeculativeStats,
+}
+
+impl<'a, 'target, 'draft> SpeculativeDecoder<'a, 'target, 'draft> {
+    /// Check the vocabularies are compatible and decode `prompt` in both contexts, drafting up to
+    /// `n_draft` tokens per step.
+    ///
+    /// # Errors
+    ///
+    /// - [`SpeculativeError::VocabMismatch`] if the models' vocabularies are not compatible.
+    /// - [`SpeculativeError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`SpeculativeError::ContextFull`] if the prompt does not fit into both contexts.
+    /// - [`SpeculativeError::DecodeError`] if decoding the prompt failed.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of a context does not fit into a [`usize`]
+    pub fn new(
+        target: &'a mut LlamaContext<'target>,
+        draft: &'a mut LlamaContext<'draft>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+        n_draft: usize,
+    ) -> Result<Self, SpeculativeError> {
+        check_vocab_compatibility(target.model, draft.model)?;
+        let (&last, prompt) = prompt.split_last().ok_or(SpeculativeError::EmptyPrompt)?;
+
+        let n_past = target.kv_cache_seq_pos_max(SEQ_ID) + 1;
+        let mut decoder = Self {
+            target_batch: LlamaBatch::new(n_draft + 1, 1),
+            // at most one pending token and `last`
+            draft_batch: LlamaBatch::new(2, 1),
+            target,
+            draft,
+            sampler,
+            n_draft,
+            n_past,
+            last,
+            draft_pending: Vec::new(),
+            stats: SpeculativeStats::default(),
+        };
+        // the rest of the prompt is decoded now and `last` by the first step
+        if decoder.n_room() < prompt.len() + 1 {
+            return Err(SpeculativeError::ContextFull);
+        }
+        decode_all(decoder.target, prompt, n_past)?;
+        decode_all(decoder.draft, prompt, n_past)?;
+        decoder.n_past += i32::try_from(prompt.len()).map_err(|_| SpeculativeError::ContextFull)?;
+        Ok(decoder)
+    }
+
+    /// Draft up to `n_draft` tokens, verify them with a single target decode and return the
+    /// accepted tokens followed by the token the target sampled after them. At least one token
+    /// is always returned.
+    ///
+    /// End-of-generation tokens are returned like any other; callers should stop once they see
+    /// one.
+    ///
+    /// # Errors
+    ///
+    /// - [`SpeculativeError::ContextFull`] if there is no room for another token.
+    /// - [`SpeculativeError::DecodeError`] if decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - the number of drafted tokens does not fit into an [`i32`]
+    pub fn step(&mut self) -> Result<Vec<LlamaToken>, SpeculativeError> {
+        let n_room = self.n_room();
+        if n_room == 0 {
+            return Err(SpeculativeError::ContextFull);
+        }
+        let drafted = self.draft_tokens(self.n_draft.min(n_room - 1))?;
+        let generated = verify(
+            self.target,
+            &mut self.target_batch,
+            self.sampler,
+            self.n_past,
+            self.last,
+            &drafted,
+        )?;
+        let n_accepted = generated.len() - 1;
+
+        // the draft decoded `last` and all but the final drafted token
+        let draft_kept = self.n_past + 1 + i32::try_from(n_accepted).expect("fits in the context");
+        remove_from(self.draft, draft_kept)?;
+        if drafted.is_empty() {
+            // there was only room for the target, so the draft never saw `last`
+            self.draft_pending.push(self.last);
+        } else if n_accepted == drafted.len() {
+            self.draft_pending.push(drafted[n_accepted - 1]);
+        }
+
+        self.n_past = draft_kept;
+        self.last = *generated.last().expect("at least one token is generated");
+
+        self.stats.record_step(drafted.len(), n_accepted);
+        Ok(generated)
+    }
+
+    /// The acceptance statistics so far.
+    #[must_use]
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    /// The number of tokens in the target's kv cache.
+    #[must_use]
+    pub fn n_past(&self) -> i32 {
+        self.n_past
+    }
+
+    /// The number of tokens that can still be decoded in both contexts.
+    fn n_room(&self) -> usize {
+        let n_ctx = self.target.n_ctx().min(self.draft.n_ctx());
+        usize::try_from(i64::from(n_ctx) - i64::from(self.n_past)).unwrap_or(0)
+    }
+
+    /// Decode the pending tokens and `last` in the draft and greedily draft `n` tokens.
+    fn draft_tokens(&mut self, n: usize) -> Result<Vec<LlamaToken>, SpeculativeError> {
+        let mut drafted = Vec::with_capacity(n);
+        if n == 0 {
+            return Ok(drafted);
+        }
+        let mut pos =
+            self.n_past - i32::try_from(self.draft_pending.len()).expect("fits in the context");
+        let mut input = std::mem::take(&mut self.draft_pending);
+        input.push(self.last);
+        if input.len() > 2 {
+            self.draft_batch = LlamaBatch::new(input.len(), 1);
+        }
+        loop {
+            self.draft_batch.clear();
+            for (i, &token) in input.iter().enumerate() {
+                self.draft_batch
+                    .add(token, pos, &[SEQ_ID], i + 1 == input.len())?;
+                pos += 1;
+            }
+            self.draft.decode(&mut self.draft_batch)?;
+            let token = argmax(self.draft.get_logits_ith(self.draft_batch.n_tokens() - 1));
+            drafted.push(token);
+            if drafted.len() == n {
+                return Ok(drafted);
+            }
+            input.clear();
+            input.push(token);
+        }
+    }
+}
+
+/// Decode `draft` after `last` in the target, sampling every position, and keep the drafted
+/// tokens for as long as they match what was sampled. Rejected tokens are removed from the kv
+/// cache. Returns the accepted tokens followed by the token sampled after them.
+pub(crate) fn verify(
+    target: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    sampler: &mut LlamaSampler,
+    n_past: i32,
+    last: LlamaToken,
+    draft: &[LlamaToken],
+) -> Result<Vec<LlamaToken>, SpeculativeError> {
+    batch.clear();
+    for (pos, &token) in (n_past..).zip(std::iter::once(&last).chain(draft)) {
+        batch.add(token, pos, &[SEQ_ID], true)?;
+    }
+    target.decode(batch)?;
+
+    let generated = accept(draft, |i| {
+        sampler.sample(target, i32::try_from(i).expect("fits in the batch"))
+    });
+    // `last` and the accepted tokens stay, the final sampled token is not decoded yet.
+    let kept = n_past + i32::try_from(generated.len()).expect("fits in the batch");
+    remove_from(target, kept)?;
+    Ok(generated)
+}
+
+/// Sample every drafted position with `sample` and keep the drafted tokens for as long as they
+/// match what was sampled. Returns the accepted tokens followed by the token sampled after them.
+fn accept(draft: &[LlamaToken], mut sample: impl FnMut(usize) -> LlamaToken) -> Vec<LlamaToken> {
+    let mut generated = Vec::with_capacity(draft.len() + 1);
+    for i in 0..=draft.len() {
+        let token = sample(i);
+        generated.push(token);
+        if draft.get(i) != Some(&token) {
+            break;
+        }
+    }
+    generated
+}
+
+/// Remove everything at or after `pos` from sequence `0`.
+fn remove_from(ctx: &mut LlamaContext, pos: i32) -> Result<(), SpeculativeError> {
+    let p0 = u32::try_from(pos).map_err(KvCacheConversionError::P0TooLarge)?;
+    ctx.clear_kv_cache_seq(Some(SEQ_ID.unsigned_abs()), Some(p0), None)?;
+    Ok(())
+}
+
+/// Decode `tokens` into sequence `0` from position `n_past` in batches, without logits.
+pub(crate) fn decode_all(
+    ctx: &mut LlamaContext,
+    tokens: &[LlamaToken],
+    n_past: i32,
+) -> Result<(), SpeculativeError> {
+    let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch does not fit into a usize");
+    let mut batch = LlamaBatch::new(n_batch, 1);
+    let mut pos = n_past;
+    for chunk in tokens.chunks(n_batch) {
+        batch.clear();
+        for &token in chunk {
+            batch.add(token, pos, &[SEQ_ID], false)?;
+            pos += 1;
+        }
+        ctx.decode(&mut batch)?;
+    }
+    Ok(())
+}
+
+/// The token with the highest logit.
+fn argmax(logits: &[f32]) -> LlamaToken {
+    let (token, _) = logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+        .expect("there is at least one logit");
+    LlamaToken(i32::try_from(token).expect("vocab does not fit into an i32"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens<const N: usize>(ids: [i32; N]) -> [LlamaToken; N] {
+        ids.map(LlamaToken)
+    }
+
+    #[test]
+    fn drafted_tokens_are_kept_until_the_first_mismatch() {
+        let draft = tokens([1, 2, 3]);
+        let sampled = |ids: [i32; 4]| move |i: usize| LlamaToken(ids[i]);
+
+        assert_eq!(accept(&draft, sampled([1, 2, 9, 0])), tokens([1, 2, 9]));
+        assert_eq!(accept(&draft, sampled([1, 2, 3, 4])), tokens([1, 2, 3, 4]));
+        assert_eq!(accept(&draft, sampled([7, 0, 0, 0])), tokens([7]));
+        assert_eq!(accept(&[], sampled([5, 0, 0, 0])), tokens([5]));
+    }
+
+    #[test]
+    fn steps_are_counted() {
+        let mut stats = SpeculativeStats::default();
+        assert!(stats.acceptance_rate().abs() < 1e-12);
+        assert!(stats.tokens_per_step().abs() < 1e-12);
+
+        stats.record_step(4, 4);
+        stats.record_step(4, 0);
+        stats.record_step(0, 0);
+        assert_eq!(
+            stats,
+            SpeculativeStats {
+                n_steps: 3,
+                n_drafted: 8,
+                n_accepted: 4,
+                n_generated: 7,
+            }
+        );
+        assert!((stats.acceptance_rate() - 0.5).abs() < 1e-12);
+        assert!((stats.tokens_per_step() - 7.0 / 3.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,234 @@
+//! Prompt-lookup decoding: speculative decoding where drafts are copied from earlier in the
+//! token stream instead of being generated by a draft model.
+//!
+//! When the last few tokens (an n-gram) appeared before, the tokens that followed them then are a
+//! good guess for what follows now. This works well when the output copies spans of the prompt,
+//! e.g. for code editing or summarization.
+//!
+//! ```
+//! # use llama_cpp_2::speculative::lookup::PromptLookup;
+//! # use llama_cpp_2::token::LlamaToken;
+//! let history = [1, 2, 3, 4, 5, 9, 2, 3].map(LlamaToken);
+//! let lookup = PromptLookup::default().with_n_draft(2);
+//! assert_eq!(lookup.draft(&history), [LlamaToken(4), LlamaToken(5)]);
+//! ```
+
+use crate::context::LlamaContext;
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::LlamaSampler;
+use crate::speculative::{decode_all, verify, SpeculativeError, SpeculativeStats, SEQ_ID};
+use crate::token::LlamaToken;
+
+/// How drafts are looked up in the token history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct PromptLookup {
+    ngram_min: usize,
+    ngram_max: usize,
+    n_draft: usize,
+}
+
+impl Default for PromptLookup {
+    /// Match n-grams of 1 to 3 tokens and draft up to 10 tokens.
+    fn default() -> Self {
+        Self {
+            ngram_min: 1,
+            ngram_max: 3,
+            n_draft: 10,
+        }
+    }
+}
+
+impl PromptLookup {
+    /// Match n-grams of `ngram_min` to `ngram_max` tokens, preferring longer ones.
+    ///
+    /// # Panics
+    ///
+    /// - `ngram_min` is `0` or greater than `ngram_max`
+    #[must_use]
+    pub fn with_ngram_size(mut self, ngram_min: usize, ngram_max: usize) -> Self {
+        assert!(
+            0 < ngram_min && ngram_min <= ngram_max,
+            "n-gram sizes must satisfy 0 < ngram_min <= ngram_max"
+        );
+        self.ngram_min = ngram_min;
+        self.ngram_max = ngram_max;
+        self
+    }
+
+    /// Draft at most `n_draft` tokens per step.
+    #[must_use]
+    pub fn with_n_draft(mut self, n_draft: usize) -> Self {
+        self.n_draft = n_draft;
+        self
+    }
+
+    /// The tokens that followed the most recent earlier occurrence of the longest n-gram that
+    /// ends `history`, or nothing if no n-gram occurred before.
+    #[must_use]
+    pub fn draft(&self, history: &[LlamaToken]) -> Vec<LlamaToken> {
+        if self.n_draft == 0 {
+            return Vec::new();
+        }
+        for n in (self.ngram_min..=self.ngram_max.min(history.len())).rev() {
+            let ngram = &history[history.len() - n..];
+            // any match must be followed by at least one token before the n-gram itself
+            let found = history[..history.len() - 1]
+                .windows(n)
+                .rposition(|window| window == ngram);
+            if let Some(start) = found {
+                let draft_start = start + n;
+                let draft_end = (draft_start + self.n_draft).min(history.len());
+                return history[draft_start..draft_end].to_vec();
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Generates tokens with a single model, drafting with [`PromptLookup`].
+///
+/// Generation continues sequence `0` after any tokens already in the kv cache. Only the prompt
+/// and the generated tokens are searched for drafts.
+#[derive(Debug)]
+pub struct PromptLookupDecoder<'a, 'model> {
+    ctx: &'a mut LlamaContext<'model>,
+    sampler: &'a mut LlamaSampler,
+    lookup: PromptLookup,
+    batch: LlamaBatch,
+    /// the prompt and generated tokens, ending with `last`
+    history: Vec<LlamaToken>,
+    /// the number of tokens in the kv cache
+    n_past: i32,
+    stats: SpeculativeStats,
+}
+
+impl<'a, 'model> PromptLookupDecoder<'a, 'model> {
+    /// Decode `prompt` and prepare to generate with drafts from `lookup`.
+    ///
+    /// # Errors
+    ///
+    /// - [`SpeculativeError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`SpeculativeError::ContextFull`] if the prompt does not fit into the context.
+    /// - [`SpeculativeError::DecodeError`] if decoding the prompt failed.
+    ///
+    /// # Panics
+    ///
+    /// - the batch size of the context does not fit into a [`usize`]
+    pub fn new(
+        ctx: &'a mut LlamaContext<'model>,
+        sampler: &'a mut LlamaSampler,
+        prompt: &[LlamaToken],
+        lookup: PromptLookup,
+    ) -> Result<Self, SpeculativeError> {
+        let (_, before_last) = prompt.split_last().ok_or(SpeculativeError::EmptyPrompt)?;
+        let n_past = ctx.kv_cache_seq_pos_max(SEQ_ID) + 1;
+        let mut decoder = Self {
+            ctx,
+            sampler,
+            lookup,
+            batch: LlamaBatch::new(lookup.n_draft + 1, 1),
+            history: prompt.to_vec(),
+            n_past,
+            stats: SpeculativeStats::default(),
+        };
+        // all but the last prompt token are decoded now and the last one by the first step
+        if decoder.n_room() < prompt.len() {
+            return Err(SpeculativeError::ContextFull);
+        }
+        decode_all(decoder.ctx, before_last, n_past)?;
+        decoder.n_past +=
+            i32::try_from(before_last.len()).map_err(|_| SpeculativeError::ContextFull)?;
+        Ok(decoder)
+    }
+
+    /// Look up a draft, verify it with a single decode and return the accepted tokens followed
+    /// by the token sampled after them. At least one token is always returned.
+    ///
+    /// End-of-generation tokens are returned like any other; callers should stop once they see
+    /// one.
+    ///
+    /// # Errors
+    ///
+    /// - [`SpeculativeError::ContextFull`] if there is no room for another token.
+    /// - [`SpeculativeError::DecodeError`] if decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - the number of drafted tokens does not fit into an [`i32`]
+    pub fn step(&mut self) -> Result<Vec<LlamaToken>, SpeculativeError> {
+        let n_room = self.n_room();
+        if n_room == 0 {
+            return Err(SpeculativeError::ContextFull);
+        }
+        let mut drafted = self.lookup.draft(&self.history);
+        drafted.truncate(n_room - 1);
+        let last = *self.history.last().expect("the history is never empty");
+        let generated = verify(
+            self.ctx,
+            &mut self.batch,
+            self.sampler,
+            self.n_past,
+            last,
+            &drafted,
+        )?;
+        let n_accepted = generated.len() - 1;
+
+        self.n_past += i32::try_from(generated.len()).expect("fits in the context");
+        self.history.extend_from_slice(&generated);
+
+        self.stats.record_step(drafted.len(), n_accepted);
+        Ok(generated)
+    }
+
+    /// The acceptance statistics so far.
+    #[must_use]
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    /// The prompt followed by all generated tokens.
+    #[must_use]
+    pub fn history(&self) -> &[LlamaToken] {
+        &self.history
+    }
+
+    /// The number of tokens that can still be decoded.
+    fn n_room(&self) -> usize {
+        usize::try_from(i64::from(self.ctx.n_ctx()) - i64::from(self.n_past)).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PromptLookup;
+    use crate::token::LlamaToken;
+
+    fn tokens<const N: usize>(ids: [i32; N]) -> [LlamaToken; N] {
+        ids.map(LlamaToken)
+    }
+
+    #[test]
+    fn longest_ngram_wins() {
+        // "2 3" last occurred followed by 9, but "1 2 3" occurred followed by 4
+        let history = tokens([1, 2, 3, 4, 2, 3, 9, 1, 2, 3]);
+        let lookup = PromptLookup::default().with_n_draft(1);
+        assert_eq!(lookup.draft(&history), tokens([4]));
+
+        let lookup = lookup.with_ngram_size(1, 2);
+        assert_eq!(lookup.draft(&history), tokens([9]));
+    }
+
+    #[test]
+    fn draft_is_cut_at_the_end_of_the_history() {
+        let history = tokens([5, 6, 7, 5]);
+        assert_eq!(PromptLookup::default().draft(&history), tokens([6, 7, 5]));
+    }
+
+    #[test]
+    fn no_match_drafts_nothing() {
+        let history = tokens([1, 2, 3]);
+        assert!(PromptLookup::default().draft(&history).is_empty());
+        assert!(PromptLookup::default().draft(&[]).is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Log-probabilities of sampled tokens, in the style of the `logprobs` returned by the `OpenAI` API.
+use std::cmp::Ordering;
+
+use crate::token::LlamaToken;
+
+/// A token and its log-probability.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct TokenLogprob {
+    /// the token
+    pub token: LlamaToken,
+    /// the natural log of the probability of the token
+    pub logprob: f32,
+}
+
+/// The log-probability of a sampled token along with the most likely alternatives.
+///
+/// Log-probabilities are computed from the raw logits of the model over the full vocabulary,
+/// before any sampler (temperature, top-k, ...) has modified them.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct TokenLogprobs {
+    /// the token that was sampled
+    pub chosen: TokenLogprob,
+    /// the most likely tokens, most likely first. This may include the chosen token.
+    pub top: Vec<TokenLogprob>,
+}
+
+impl TokenLogprobs {
+    /// Compute the log-probabilities of `chosen` and the `n_top` most likely tokens from the
+    /// logits of every token in the vocabulary.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # use llama_cpp_2::token::logprobs::TokenLogprobs;
+    /// let logprobs = TokenLogprobs::from_logits(&[1.0, 3.0, 2.0], LlamaToken(2), 2);
+    ///
+    /// assert_eq!(logprobs.chosen.token, LlamaToken(2));
+    /// assert!((logprobs.chosen.logprob - -1.4076).abs() < 1e-4);
+    ///
+    /// let top = logprobs.top.iter().map(|t| t.token).collect::<Vec<_>>();
+    /// assert_eq!(top, [LlamaToken(1), LlamaToken(2)]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// - `chosen` is not a valid index into `logits`.
+    #[must_use]
+    pub fn from_logits(logits: &[f32], chosen: LlamaToken, n_top: usize) -> Self {
+        let log_sum_exp = log_sum_exp(logits);
+        let logprob = |token: usize| logits[token] - log_sum_exp;
+
+        let chosen_index = usize::try_from(chosen.0).expect("token is negative");
+        let chosen = TokenLogprob {
+            token: chosen,
+            logprob: logprob(chosen_index),
+        };
+        if n_top == 0 {
+            // skip sorting the vocabulary, which needs a vocab sized buffer
+            return Self {
+                chosen,
+                top: Vec::new(),
+            };
+        }
+
+        // higher logits first, ties broken by token id so the result is deterministic.
+        let by_logit = |a: &usize, b: &usize| {
+            logits[*b]
+                .partial_cmp(&logits[*a])
+                .unwrap_or(Ordering::Equal)
+                .then(a.cmp(b))
+        };
+        let mut indices = (0..logits.len()).collect::<Vec<_>>();
+        let n_top = n_top.min(indices.len());
+        if n_top > 0 && n_top < indices.len() {
+            indices.select_nth_unstable_by(n_top - 1, by_logit);
+        }
+        indices.truncate(n_top);
+        indices.sort_unstable_by(by_logit);
+
+        let top = indices
+            .into_iter()
+            .map(|token| TokenLogprob {
+                token: LlamaToken(i32::try_from(token).expect("vocab does not fit into an i32")),
+                logprob: logprob(token),
+            })
+            .collect();
+
+        Self { chosen, top }
+    }
+}
+
+/// `ln(sum(exp(logits)))`, computed without overflowing by factoring out the largest logit.
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return max;
+    }
+    let sum = logits
+        .iter()
+        .map(|&logit| f64::from(logit - max).exp())
+        .sum::<f64>();
+    #[allow(clippy::cast_possible_truncation)]
+    let log_sum = sum.ln() as f32;
+    max + log_sum
+}
+
+/// The log-softmax of `logits`: the log-probability of each token.
+///
+/// ```
+/// # use llama_cpp_2::token::logprobs::log_softmax;
+/// let logprobs = log_softmax(&[1000.0, 1000.0]);
+/// assert!((logprobs[0] - -std::f32::consts::LN_2).abs() < 1e-4);
+/// assert_eq!(logprobs[0], logprobs[1]);
+/// ```
+#[must_use]
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let log_sum_exp = log_sum_exp(logits);
+    logits.iter().map(|&logit| logit - log_sum_exp).collect()
+}